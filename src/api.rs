@@ -0,0 +1,310 @@
+use std::collections::HashMap;
+use std::io::Read;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use chrono::Utc;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use crate::{Bytes, Context, Miner};
+use crate::blockchain::types::MineResult;
+use crate::commons::{DOMAIN_EXPIRY_WARNING, DOMAIN_GRACE_PERIOD};
+use crate::event::Event;
+use crate::miner::{MineRequest, mine_request, transfer_domain};
+
+/// Max size of request body that we accept
+const MAX_BODY_SIZE: usize = 64 * 1024;
+
+/// Request to transfer domain to a new owner
+#[derive(Deserialize)]
+struct TransferRequest {
+    name: String,
+    owner: Bytes
+}
+
+/// Mining speed and found difficulty of current mining job
+#[derive(Default)]
+struct MinerStats {
+    speeds: HashMap<usize, u64>,
+    max_diff: u32
+}
+
+/// Starts local HTTP server with JSON API to control running node
+pub fn start_api_server(context: Arc<Mutex<Context>>, miner: Arc<Mutex<Miner>>) -> Result<(), String> {
+    let (listen, token) = {
+        let settings = &context.lock().unwrap().settings;
+        (settings.api.listen.clone(), settings.api.token.clone())
+    };
+    if token.is_empty() {
+        warn!("API token is not set, any local program can use the API");
+    }
+    let server = Server::http(&listen).map_err(|e| format!("Error starting API server on {}: {}", &listen, e))?;
+    info!("Started API server on {}", &listen);
+
+    let stats = Arc::new(Mutex::new(MinerStats::default()));
+    {
+        let stats = Arc::clone(&stats);
+        context.lock().unwrap().bus.register(move |_uuid, e| {
+            match e {
+                Event::MinerStarted | Event::MinerStopped { .. } => {
+                    let mut stats = stats.lock().unwrap();
+                    stats.speeds.clear();
+                    stats.max_diff = 0;
+                }
+                Event::MinerStats { thread, speed, max_diff } => {
+                    let mut stats = stats.lock().unwrap();
+                    stats.speeds.insert(thread, speed);
+                    if max_diff > stats.max_diff {
+                        stats.max_diff = max_diff;
+                    }
+                }
+                _ => {}
+            }
+            true
+        });
+    }
+
+    thread::spawn(move || {
+        for mut request in server.incoming_requests() {
+            if let Err((code, error)) = check_access(request.method(), request.headers(), &token) {
+                warn!("Refused API request {} {}: {}", request.method(), request.url(), error);
+                respond(request, code, json!({ "error": error }));
+                continue;
+            }
+            let body = match read_body(&mut request) {
+                Ok(body) => body,
+                Err(e) => {
+                    respond(request, 400, json!({ "error": e }));
+                    continue;
+                }
+            };
+            debug!("API request {} {}", request.method(), request.url());
+            let (code, value) = handle_request(&context, &miner, &stats, request.method(), request.url(), &body);
+            respond(request, code, value);
+        }
+        info!("API server stopped");
+    });
+    Ok(())
+}
+
+/// Checks that request is not made by some web page in a browser, and that it has our token.
+/// Browsers can make cross-site POST requests without asking us, but they always add `Origin` header to them,
+/// and they can't set JSON content type or authorization without a preflight request, that we don't allow.
+fn check_access(method: &Method, headers: &[Header], token: &str) -> Result<(), (u16, &'static str)> {
+    fn get_header<'a>(headers: &'a [Header], name: &'static str) -> Option<&'a str> {
+        headers.iter().find(|header| header.field.equiv(name)).map(|header| header.value.as_str())
+    }
+
+    if get_header(headers, "Origin").is_some() {
+        return Err((403, "Cross-origin requests are not allowed"));
+    }
+    if !token.is_empty() {
+        let authorized = match get_header(headers, "Authorization") {
+            Some(value) => value.strip_prefix("Bearer ").map(|value| value.trim() == token).unwrap_or(false),
+            None => false
+        };
+        if !authorized {
+            return Err((401, "Wrong or missing API token"));
+        }
+    }
+    if *method == Method::Post {
+        let content_type = get_header(headers, "Content-Type").unwrap_or_default();
+        if content_type.split(';').next().unwrap_or_default().trim() != "application/json" {
+            return Err((415, "Content-Type must be application/json"));
+        }
+    }
+    Ok(())
+}
+
+fn read_body(request: &mut Request) -> Result<String, String> {
+    if request.body_length().unwrap_or(0) > MAX_BODY_SIZE {
+        return Err(String::from("Request is too big"));
+    }
+    let mut body = String::new();
+    request.as_reader().take(MAX_BODY_SIZE as u64).read_to_string(&mut body).map_err(|e| e.to_string())?;
+    Ok(body)
+}
+
+fn respond(request: Request, code: u16, value: Value) {
+    let header = Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).unwrap();
+    let response = Response::from_string(value.to_string())
+        .with_status_code(code)
+        .with_header(header);
+    if let Err(e) = request.respond(response) {
+        debug!("Error sending API response: {}", e);
+    }
+}
+
+fn handle_request(context: &Arc<Mutex<Context>>, miner: &Arc<Mutex<Miner>>, stats: &Arc<Mutex<MinerStats>>, method: &Method, url: &str, body: &str) -> (u16, Value) {
+    let path = url.split('?').next().unwrap_or_default().trim_matches('/');
+    let path: Vec<&str> = path.split('/').collect();
+    match (method, path.as_slice()) {
+        (Method::Get, ["api", "height"]) => {
+            let context = context.lock().unwrap();
+            (200, json!({ "height": context.chain.height(), "max_height": context.chain.max_height(), "hash": context.chain.last_hash() }))
+        }
+        (Method::Get, ["api", "blocks", index]) => {
+            let index = match index.parse::<u64>() {
+                Ok(index) => index,
+                Err(_) => return (400, json!({ "error": "Wrong block index" }))
+            };
+            match context.lock().unwrap().chain.get_block(index) {
+                Some(block) => (200, json!(block)),
+                None => (404, json!({ "error": "Block not found" }))
+            }
+        }
+        (Method::Get, ["api", "domains"]) => {
+            let context = context.lock().unwrap();
+            let keystore = match context.get_keystore() {
+                None => return (409, json!({ "error": "No key loaded" })),
+                Some(keystore) => keystore
+            };
+            let now = Utc::now().timestamp();
+            let domains: Vec<Value> = context.chain.get_owned_domains(&keystore)
+                .into_iter()
+                .map(|domain| {
+                    let expiring = domain.expires - now <= DOMAIN_EXPIRY_WARNING;
+                    json!({ "name": domain.name, "identity": domain.identity, "expires": domain.expires, "expiring": expiring })
+                })
+                .collect();
+            (200, json!(domains))
+        }
+        (Method::Get, ["api", "domains", name]) => {
+            let name = name.to_lowercase();
+            let context = context.lock().unwrap();
+            match context.chain.get_domain_transaction(&name) {
+                Some(transaction) => {
+                    let data = serde_json::from_str::<Value>(&transaction.data).unwrap_or(Value::Null);
+                    let expires = context.chain.get_domain_expiry(&name);
+                    (200, json!({ "name": name, "class": transaction.class, "owner": transaction.pub_key, "expires": expires, "data": data }))
+                }
+                None => (404, json!({ "error": "Domain not found" }))
+            }
+        }
+        (Method::Get, ["api", "domains", name, "expiry"]) => {
+            let name = name.to_lowercase();
+            match context.lock().unwrap().chain.get_domain_expiry(&name) {
+                Some(expires) => {
+                    let now = Utc::now().timestamp();
+                    let grace = expires + DOMAIN_GRACE_PERIOD;
+                    (200, json!({ "name": name, "expires": expires, "expired": expires < now, "grace_until": grace, "free": grace < now }))
+                }
+                None => (404, json!({ "error": "Domain not found" }))
+            }
+        }
+        (Method::Get, ["api", "zones"]) => {
+            (200, json!(context.lock().unwrap().chain.get_zones()))
+        }
+        (Method::Get, ["api", "peers"]) => {
+            (200, json!(context.lock().unwrap().peers))
+        }
+        (Method::Get, ["api", "miner"]) => {
+            let (running, busy, jobs) = {
+                let miner = miner.lock().unwrap();
+                (miner.is_mining(), miner.is_busy(), miner.jobs_count())
+            };
+            let stats = stats.lock().unwrap();
+            let speed: u64 = stats.speeds.values().sum();
+            (200, json!({ "running": running, "mining": busy, "jobs": jobs, "speed": speed, "max_diff": stats.max_diff }))
+        }
+        (Method::Post, ["api", "mine"]) => {
+            let request = match serde_json::from_str::<MineRequest>(body) {
+                Ok(request) => request,
+                Err(e) => return (400, json!({ "error": e.to_string() }))
+            };
+            match mine_request(context, miner, request) {
+                MineResult::Fine => (200, json!({ "result": format!("{:?}", MineResult::Fine) })),
+                result => (409, json!({ "result": format!("{:?}", result) }))
+            }
+        }
+        (Method::Post, ["api", "transfer"]) => {
+            let request = match serde_json::from_str::<TransferRequest>(body) {
+                Ok(request) => request,
+                Err(e) => return (400, json!({ "error": e.to_string() }))
+            };
+            match transfer_domain(context, miner, &request.name, &request.owner) {
+                MineResult::Fine => (200, json!({ "result": format!("{:?}", MineResult::Fine) })),
+                result => (409, json!({ "result": format!("{:?}", result) }))
+            }
+        }
+        (_, ["api", ..]) => (404, json!({ "error": "Unknown method" })),
+        _ => (404, json!({ "error": "Not found" }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use serde_json::Value;
+    use tiny_http::{Header, Method};
+
+    use crate::{Context, Miner, Settings};
+    use crate::api::{check_access, handle_request, MinerStats};
+    use crate::blockchain::chain::tests::memory_chain;
+
+    fn header(name: &str, value: &str) -> Header {
+        Header::from_bytes(name.as_bytes(), value.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn check_api_access() {
+        let json = header("Content-Type", "application/json; charset=utf-8");
+        assert!(check_access(&Method::Get, &[], "").is_ok());
+        assert!(check_access(&Method::Post, &[json.clone()], "").is_ok());
+        assert_eq!(check_access(&Method::Post, &[header("Content-Type", "text/plain")], "").unwrap_err().0, 415);
+        assert_eq!(check_access(&Method::Post, &[], "").unwrap_err().0, 415);
+        assert_eq!(check_access(&Method::Post, &[json.clone(), header("Origin", "https://example.com")], "").unwrap_err().0, 403);
+        assert_eq!(check_access(&Method::Get, &[header("Origin", "null")], "").unwrap_err().0, 403);
+
+        let auth = header("Authorization", "Bearer secret");
+        assert_eq!(check_access(&Method::Get, &[], "secret").unwrap_err().0, 401);
+        assert_eq!(check_access(&Method::Get, &[header("Authorization", "Bearer wrong")], "secret").unwrap_err().0, 401);
+        assert!(check_access(&Method::Get, &[auth.clone()], "secret").is_ok());
+        assert!(check_access(&Method::Post, &[auth, json], "secret").is_ok());
+    }
+
+    #[test]
+    fn handle_api_requests() {
+        let context = Context::new(String::from("test"), Settings::default(), None, memory_chain());
+        let context = Arc::new(Mutex::new(context));
+        let miner = Arc::new(Mutex::new(Miner::new(Arc::clone(&context))));
+        let stats = Arc::new(Mutex::new(MinerStats::default()));
+        let request = |method: Method, url: &str, body: &str| handle_request(&context, &miner, &stats, &method, url, body);
+
+        let (code, value) = request(Method::Get, "/api/height", "");
+        assert_eq!(code, 200);
+        assert_eq!(value["height"], 0);
+        assert_eq!(value["max_height"], 0);
+        assert_eq!(request(Method::Get, "/api/blocks/first", "").0, 400);
+        let (code, value) = request(Method::Get, "/api/blocks/5", "");
+        assert_eq!(code, 404);
+        assert_eq!(value["error"], "Block not found");
+        assert_eq!(request(Method::Get, "/api/domains/test.ygg", "").0, 404);
+        assert_eq!(request(Method::Get, "/api/domains/test.ygg/expiry", "").0, 404);
+        assert_eq!(request(Method::Get, "/api/domains", "").0, 409);
+        assert_eq!(request(Method::Get, "/api/zones?format=json", ""), (200, Value::Array(Vec::new())));
+        let (code, value) = request(Method::Get, "/api/miner", "");
+        assert_eq!(code, 200);
+        assert_eq!(value["running"], false);
+        assert_eq!(value["jobs"], 0);
+
+        assert_eq!(request(Method::Post, "/api/mine", "{").0, 400);
+        assert_eq!(request(Method::Post, "/api/mine", r#"{ "class": "domain" }"#).0, 400);
+        let (code, value) = request(Method::Post, "/api/mine", r#"{ "name": "test.ygg" }"#);
+        assert_eq!(code, 409);
+        assert_eq!(value["result"], "WrongKey");
+        assert_eq!(request(Method::Post, "/api/transfer", r#"{ "name": "test.ygg" }"#).0, 400);
+
+        let (code, value) = request(Method::Get, "/api/mine", "");
+        assert_eq!(code, 404);
+        assert_eq!(value["error"], "Unknown method");
+        let (code, value) = request(Method::Get, "/index.html", "");
+        assert_eq!(code, 404);
+        assert_eq!(value["error"], "Not found");
+    }
+}
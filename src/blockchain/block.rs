@@ -5,7 +5,7 @@ use std::fmt::Debug;
 use serde::{Serialize, Deserialize};
 use crate::bytes::Bytes;
 use crate::Transaction;
-use crate::blockchain::hash_utils::hash_difficulty;
+use std::cmp::min;
 
 #[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
 pub struct Block {
@@ -68,15 +68,8 @@ impl Block {
         Vec::from(serde_json::to_string(&self).unwrap().as_bytes())
     }
 
-    pub fn is_better_than(&self, other: &Block) -> bool {
-        if self.transaction.is_none() && other.transaction.is_some() {
-            return false;
-        }
-        if hash_difficulty(self.hash.as_slice()) < hash_difficulty(other.hash.as_slice()) {
-            return false;
-        }
-        // TODO add more checks
-
-        true
+    /// Amount of work needed to mine this block, used to compare branches of forks
+    pub fn work(&self) -> u128 {
+        1u128 << min(self.difficulty, 127)
     }
 }
\ No newline at end of file
@@ -1,7 +1,7 @@
 use std::cell::RefCell;
-use std::collections::{HashSet, HashMap};
+use std::collections::{BTreeMap, HashSet, HashMap};
 use std::fs;
-use std::path::Path;
+use std::path::PathBuf;
 
 use chrono::Utc;
 #[allow(unused_imports)]
@@ -10,9 +10,11 @@ use sqlite::{Connection, State, Statement};
 
 use crate::{Block, Bytes, Keystore, Transaction, check_domain, get_domain_zone};
 use crate::commons::constants::*;
-use crate::blockchain::types::{BlockQuality, MineResult, Options};
+use crate::commons::ConsensusParams;
+use crate::blockchain::types::{BlockQuality, MineResult, Options, OwnedDomain, Ownership};
 use crate::blockchain::types::BlockQuality::*;
 use crate::blockchain::hash_utils::*;
+use crate::blockchain::migrations;
 use crate::settings::Settings;
 use crate::keys::check_public_key_strength;
 use std::cmp::{min, max};
@@ -20,27 +22,36 @@ use crate::blockchain::transaction::{ZoneData, DomainData};
 use std::ops::Deref;
 use crate::blockchain::types::MineResult::*;
 
-const DB_NAME: &str = "blockchain.db";
+pub const DB_NAME: &str = "blockchain.db";
 const TEMP_DB_NAME: &str = "temp.db";
+/// How long we wait for DB to be unlocked by other connection, in milliseconds
+pub const DB_BUSY_TIMEOUT: usize = 5000;
 const SQL_CREATE_TABLES: &str = include_str!("sql/create_db.sql");
 const SQL_ADD_BLOCK: &str = "INSERT INTO blocks (id, timestamp, version, difficulty, random, nonce, 'transaction',\
                           prev_block_hash, hash, pub_key, signature) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?);";
-const SQL_REPLACE_BLOCK: &str = "UPDATE blocks SET timestamp = ?, version = ?, difficulty = ?, random = ?, nonce = ?, 'transaction' = ?,\
-                          prev_block_hash = ?, hash = ?, pub_key = ?, signature = ? WHERE id = ?;";
 const SQL_GET_LAST_BLOCK: &str = "SELECT * FROM blocks ORDER BY id DESC LIMIT 1;";
 const SQL_ADD_DOMAIN: &str = "INSERT INTO domains (id, timestamp, identity, confirmation, data, pub_key) VALUES (?, ?, ?, ?, ?, ?)";
 const SQL_ADD_ZONE: &str = "INSERT INTO zones (id, timestamp, identity, confirmation, data, pub_key) VALUES (?, ?, ?, ?, ?, ?)";
-const SQL_DELETE_DOMAIN: &str = "DELETE FROM domains WHERE id = ?";
-const SQL_DELETE_ZONE: &str = "DELETE FROM zones WHERE id = ?";
+const SQL_TRUNCATE_BLOCKS: &str = "DELETE FROM blocks WHERE id > ?";
+const SQL_TRUNCATE_DOMAINS: &str = "DELETE FROM domains WHERE id > ?";
+const SQL_TRUNCATE_ZONES: &str = "DELETE FROM zones WHERE id > ?";
 const SQL_GET_BLOCK_BY_ID: &str = "SELECT * FROM blocks WHERE id=? LIMIT 1;";
 const SQL_GET_LAST_FULL_BLOCK: &str = "SELECT * FROM blocks WHERE `transaction`<>'' ORDER BY id DESC LIMIT 1;";
 const SQL_GET_LAST_FULL_BLOCK_FOR_KEY: &str = "SELECT * FROM blocks WHERE `transaction`<>'' AND pub_key = ? ORDER BY id DESC LIMIT 1;";
-const SQL_GET_DOMAIN_PUBLIC_KEY_BY_ID: &str = "SELECT pub_key FROM domains WHERE identity = ? ORDER BY id DESC LIMIT 1;";
-const SQL_GET_ZONE_PUBLIC_KEY_BY_ID: &str = "SELECT pub_key FROM zones WHERE identity = ? ORDER BY id DESC LIMIT 1;";
+const SQL_GET_DOMAIN_OWNER_BY_ID: &str = "SELECT pub_key, timestamp, data FROM domains WHERE identity = ? ORDER BY id DESC LIMIT 1;";
+const SQL_GET_ZONE_OWNER_BY_ID: &str = "SELECT pub_key, timestamp, data FROM zones WHERE identity = ? ORDER BY id DESC LIMIT 1;";
 const SQL_GET_DOMAIN_BY_ID: &str = "SELECT * FROM domains WHERE identity = ? ORDER BY id DESC LIMIT 1;";
+const SQL_GET_DOMAINS_BY_KEY: &str = "SELECT id, timestamp, identity, data, pub_key FROM domains WHERE identity IN \
+                          (SELECT DISTINCT identity FROM domains WHERE pub_key = ?) ORDER BY id ASC;";
+const SQL_GET_BLOCKS_WITHOUT_TRANSACTIONS: &str = "SELECT * FROM blocks WHERE `transaction`<>'' AND \
+                          id NOT IN (SELECT id FROM domains) AND id NOT IN (SELECT id FROM zones) ORDER BY id ASC;";
+const SQL_DELETE_ORPHAN_DOMAINS: &str = "DELETE FROM domains WHERE id NOT IN (SELECT id FROM blocks);";
+const SQL_DELETE_ORPHAN_ZONES: &str = "DELETE FROM zones WHERE id NOT IN (SELECT id FROM blocks);";
 const SQL_GET_ZONES: &str = "SELECT data FROM zones;";
 
 const SQL_GET_OPTIONS: &str = "SELECT * FROM options;";
+const SQL_DELETE_OPTION: &str = "DELETE FROM options WHERE name = ?;";
+const SQL_ADD_OPTION: &str = "INSERT INTO options (name, value) VALUES (?, ?);";
 
 pub struct Chain {
     origin: Bytes,
@@ -48,84 +59,139 @@ pub struct Chain {
     last_full_block: Option<Block>,
     max_height: u64,
     db: Connection,
+    db_path: PathBuf,
     zones: RefCell<HashSet<String>>,
+    /// Blocks that we know are good, nothing can replace them
+    checkpoints: BTreeMap<u64, Bytes>,
+    consensus: ConsensusParams,
 }
 
 impl Chain {
-    pub fn new(settings: &Settings) -> Self {
+    pub fn new(settings: &Settings) -> Result<Self, String> {
+        if settings.regtest && settings.origin.eq_ignore_ascii_case(MAIN_ORIGIN) {
+            return Err(String::from("Regtest can not work with main blockchain, set another origin or make it empty"));
+        }
         let origin = settings.get_origin();
+        let db_path = settings.get_path(DB_NAME);
+        let mut db = sqlite::open(&db_path).map_err(|e| format!("Unable to open blockchain DB {}: {}", db_path.display(), e))?;
+        // Known peers are written to the same DB from network thread
+        db.set_busy_timeout(DB_BUSY_TIMEOUT).map_err(|e| e.to_string())?;
+        let checkpoints = settings.get_checkpoints()?;
+        let mut chain = Self::open(origin, db, db_path)?;
+        chain.consensus = settings.get_consensus();
+        chain.set_checkpoints(checkpoints)?;
+        Ok(chain)
+    }
 
-        let db = sqlite::open(DB_NAME).expect("Unable to open blockchain DB");
+    fn open(origin: Bytes, db: Connection, db_path: PathBuf) -> Result<Self, String> {
         let zones = RefCell::new(HashSet::new());
-        let mut chain = Chain { origin, last_block: None, last_full_block: None, max_height: 0, db, zones };
-        chain.init_db();
-        chain
+        let mut chain = Chain { origin, last_block: None, last_full_block: None, max_height: 0, db, db_path, zones, checkpoints: BTreeMap::new(), consensus: ConsensusParams::main() };
+        chain.init_db()?;
+        Ok(chain)
+    }
+
+    /// Sets checkpoints and removes our blocks that conflict with them
+    fn set_checkpoints(&mut self, checkpoints: BTreeMap<u64, Bytes>) -> Result<(), String> {
+        self.checkpoints = checkpoints;
+        let conflict = self.checkpoints
+            .iter()
+            .filter(|(height, _)| **height <= self.height())
+            .find(|(height, hash)| self.get_block(**height).map(|block| block.hash != **hash).unwrap_or(false))
+            .map(|(height, _)| *height);
+        if let Some(height) = conflict {
+            warn!("Our block {} conflicts with checkpoint, removing blocks from it", height);
+            self.atomic(|chain| chain.truncate(height - 1).map_err(|e| e.to_string()))?;
+            self.reload_cache();
+        }
+        Ok(())
+    }
+
+    /// Height of the last checkpoint, blocks below it don't need some expensive checks
+    fn last_checkpoint(&self) -> u64 {
+        self.checkpoints.keys().next_back().cloned().unwrap_or(0)
     }
 
-    /// Reads options from DB or initializes and writes them to DB if not found
-    fn init_db(&mut self) {
+    /// Reads options from DB, migrates DB to current version and writes options back
+    fn init_db(&mut self) -> Result<(), String> {
         let options = self.get_options();
         if !self.origin.is_zero() && !options.origin.is_empty() && self.origin.to_string() != options.origin {
-            self.clear_db();
+            self.clear_db()?;
+        }
+        let mut options = self.get_options();
+        if options.version > DB_VERSION {
+            return Err(format!("Blockchain DB has version {}, but we support only version {}. Please, update ALFIS.", options.version, DB_VERSION));
+        }
+        if self.db.prepare(SQL_GET_LAST_BLOCK).is_err() {
+            info!("No blockchain database found. Creating new.");
+            self.db.execute(SQL_CREATE_TABLES).map_err(|e| format!("Error creating DB tables: {}", e))?;
+            options.version = 0;
         }
         if options.version < DB_VERSION {
-            self.migrate_db(options.version, DB_VERSION);
+            let from = options.version;
+            self.atomic(|chain| chain.migrate_db(from, DB_VERSION))?;
         }
 
-        // Trying to get last block from DB to check its version
-        let block: Option<Block> = match self.db.prepare(SQL_GET_LAST_BLOCK) {
-            Ok(mut statement) => {
-                let mut result = None;
-                while statement.next().unwrap() == State::Row {
-                    match Self::get_block_from_statement(&mut statement) {
-                        None => {
-                            error!("Something wrong with block in DB!");
-                            panic!();
-                        }
-                        Some(block) => {
-                            debug!("Loaded last block: {:?}", &block);
-                            result = Some(block);
-                            break;
-                        }
-                    }
-                }
-                result
+        self.reload_cache();
+        if let Some(block) = &self.last_block {
+            debug!("Loaded last block: {:?}", block);
+            if let Err(e) = self.atomic(|chain| chain.repair_db()) {
+                error!("Error repairing blockchain DB: {}", e);
             }
-            Err(e) => {
-                info!("No blockchain database found. Creating new. {}", e);
-                self.db.execute(SQL_CREATE_TABLES).expect("Error creating DB tables");
-                None
+            // Repair could have removed some blocks
+            self.reload_cache();
+        }
+        if options.origin.is_empty() {
+            if let Some(block) = self.get_block(1) {
+                self.set_option("origin", &block.hash.to_string()).map_err(|e| e.to_string())?;
             }
-        };
-        // If some block loaded we check its version and determine if we need some migration
-        if let Some(block) = block {
-            // Cache some info
-            self.last_block = Some(block.clone());
-            if block.transaction.is_some() {
-                self.last_full_block = Some(block);
-            } else {
-                self.last_full_block = self.get_last_full_block(None);
+        }
+        Ok(())
+    }
+
+    /// Finds blocks that were written without their transactions by older versions, and fixes them.
+    /// Transactions without blocks are deleted.
+    fn repair_db(&mut self) -> Result<(), String> {
+        for sql in &[SQL_DELETE_ORPHAN_DOMAINS, SQL_DELETE_ORPHAN_ZONES] {
+            self.db.execute(*sql).map_err(|e| e.to_string())?;
+        }
+        let mut broken = Vec::new();
+        {
+            let mut statement = self.db.prepare(SQL_GET_BLOCKS_WITHOUT_TRANSACTIONS).map_err(|e| e.to_string())?;
+            while statement.next().map_err(|e| e.to_string())? == State::Row {
+                if let Some(block) = Self::get_block_from_statement(&mut statement) {
+                    broken.push(block);
+                }
+            }
+        }
+        for block in broken {
+            warn!("Block {} has no transaction in DB, repairing", block.index);
+            let transaction = block.transaction.unwrap();
+            if let Err(e) = self.add_transaction_to_table(block.index, block.timestamp, &transaction) {
+                // We can't fix this block, so we delete it and get it again from network
+                warn!("Unable to repair block {} ({}), removing blocks from it", block.index, e);
+                self.truncate(block.index - 1).map_err(|e| e.to_string())?;
+                break;
             }
         }
+        Ok(())
     }
 
-    fn migrate_db(&mut self, from: u32, to: u32) {
-        debug!("Migrating DB from {} to {}", from, to);
+    fn migrate_db(&mut self, from: u32, to: u32) -> Result<(), String> {
+        migrations::migrate(&self.db, from, to)?;
+        self.set_option("version", &to.to_string()).map_err(|e| e.to_string())
     }
 
-    fn clear_db(&mut self) {
+    fn clear_db(&mut self) -> Result<(), String> {
         warn!("Clearing DB");
         // We cannot close DB connection and recreate file,
         // therefore we switch our db to temporary file, delete main DB and switch back.
         // I know that this is a crutch, but this way I don't need to use Option<db> :)
-        self.db = sqlite::open(TEMP_DB_NAME).expect("Unable to open temporary blockchain DB");
-        let file = Path::new(DB_NAME);
-        if fs::remove_file(&file).is_err() {
-            panic!("Unable to remove database!");
-        }
-        self.db = sqlite::open(DB_NAME).expect("Unable to open blockchain DB");
-        let file = Path::new(TEMP_DB_NAME);
-        let _ = fs::remove_file(&file).is_err();
+        let temp_path = self.db_path.with_file_name(TEMP_DB_NAME);
+        self.db = sqlite::open(&temp_path).map_err(|e| format!("Unable to open temporary blockchain DB {}: {}", temp_path.display(), e))?;
+        fs::remove_file(&self.db_path).map_err(|e| format!("Unable to remove blockchain DB {}: {}", self.db_path.display(), e))?;
+        self.db = sqlite::open(&self.db_path).map_err(|e| format!("Unable to open blockchain DB {}: {}", self.db_path.display(), e))?;
+        let _ = fs::remove_file(&temp_path);
+        Ok(())
     }
 
     fn get_options(&self) -> Options {
@@ -144,48 +210,156 @@ impl Chain {
         options
     }
 
-    pub fn add_block(&mut self, block: Block) {
+    fn set_option(&self, name: &str, value: &str) -> sqlite::Result<()> {
+        let mut statement = self.db.prepare(SQL_DELETE_OPTION)?;
+        statement.bind(1, name)?;
+        statement.next()?;
+        let mut statement = self.db.prepare(SQL_ADD_OPTION)?;
+        statement.bind(1, name)?;
+        statement.bind(2, value)?;
+        statement.next()?;
+        Ok(())
+    }
+
+    /// Adds block with its transaction to DB in one DB transaction
+    pub fn add_block(&mut self, block: Block) -> Result<(), String> {
         debug!("Adding block:\n{:?}", &block);
+        self.atomic(|chain| chain.store_block(block))
+    }
+
+    /// Writes block and its transaction to tables and updates caches, must be called inside of DB transaction
+    fn store_block(&mut self, block: Block) -> Result<(), String> {
         let index = block.index;
         let timestamp = block.timestamp;
-        self.last_block = Some(block.clone());
-        if block.transaction.is_some() {
+        let transaction = block.transaction.clone();
+        self.add_block_to_table(block.clone()).map_err(|e| format!("Error adding block {}: {}", index, e))?;
+        if index == 1 {
+            self.set_option("origin", &block.hash.to_string()).map_err(|e| e.to_string())?;
+        }
+        if let Some(transaction) = transaction {
+            self.add_transaction_to_table(index, timestamp, &transaction).map_err(|e| format!("Error adding transaction of block {}: {}", index, e))?;
             self.last_full_block = Some(block.clone());
         }
-        let transaction = block.transaction.clone();
-        if self.add_block_to_table(block).is_ok() {
-            if let Some(transaction) = transaction {
-                self.add_transaction_to_table(index, timestamp, &transaction).expect("Error adding transaction");
+        self.last_block = Some(block);
+        Ok(())
+    }
+
+    /// Runs `func` inside of DB transaction, all changes are rolled back if it returns error
+    fn atomic<T, F: FnOnce(&mut Self) -> Result<T, String>>(&mut self, func: F) -> Result<T, String> {
+        self.db.execute("BEGIN TRANSACTION").map_err(|e| e.to_string())?;
+        let result = func(self).and_then(|result| {
+            self.db.execute("COMMIT").map_err(|e| e.to_string())?;
+            Ok(result)
+        });
+        if result.is_err() {
+            let _ = self.db.execute("ROLLBACK");
+            self.reload_cache();
+        }
+        result
+    }
+
+    /// Switches to other branch of blockchain if it has more accumulated work than ours.
+    /// Blocks must go one by one, and the first of them must continue our block at some height.
+    /// Returns Ok(true) if the fork was applied and Ok(false) if our branch is better.
+    pub fn apply_fork(&mut self, blocks: Vec<Block>) -> Result<bool, String> {
+        let first = match blocks.first() {
+            None => return Ok(false),
+            Some(block) => block
+        };
+        if first.index < 2 {
+            return Err(String::from("Fork has no common blocks with our blockchain"));
+        }
+        let root = match self.get_block(first.index - 1) {
+            None => return Err(format!("We don't have block {} to attach fork to", first.index - 1)),
+            Some(block) => block
+        };
+        let mut prev = &root;
+        for block in blocks.iter() {
+            if block.index != prev.index + 1 || block.prev_block_hash != prev.hash {
+                return Err(format!("Fork is broken at block {}", block.index));
             }
+            prev = block;
+        }
+
+        // We have checked that our blocks match checkpoints, fork can't replace them
+        if let Some((height, _)) = self.checkpoints.range(first.index..=self.height()).next() {
+            return Err(format!("Fork from block {} conflicts with checkpoint {}", first.index, height));
         }
+
+        // Checkpoints are stronger than any amount of work
+        let has_checkpoint = blocks.iter().any(|block| self.checkpoints.contains_key(&block.index));
+        let fork_work: u128 = blocks.iter().map(|block| block.work()).sum();
+        let our_work = self.get_work(first.index);
+        if fork_work <= our_work && !has_checkpoint {
+            debug!("Fork from block {} has less work than our blockchain, ignoring", first.index);
+            return Ok(false);
+        }
+
+        info!("Switching to fork from block {} to {}", first.index, prev.index);
+        self.atomic(|chain| chain.replace_blocks(root.index, blocks))?;
+        Ok(true)
     }
 
-    pub fn replace_block(&mut self, index: u64, block: Block) -> sqlite::Result<()> {
-        debug!("Replacing block {} with:\n{:?}", index, &block);
-        let old_block = self.get_block(index).unwrap();
-        if old_block.transaction.is_some() {
-            let mut statement = self.db.prepare(SQL_DELETE_DOMAIN)?;
-            statement.bind(1, index as i64)?;
-            statement.next()?;
+    /// Deletes all blocks above `index` and adds new ones, checking each of them
+    fn replace_blocks(&mut self, index: u64, blocks: Vec<Block>) -> Result<(), String> {
+        self.truncate(index).map_err(|e| e.to_string())?;
+        self.reload_cache();
+        for block in blocks {
+            match self.check_new_block(&block) {
+                Good => self.store_block(block)?,
+                quality => return Err(format!("Block {} of fork is {:?}", block.index, quality))
+            }
+        }
+        Ok(())
+    }
 
-            let mut statement = self.db.prepare(SQL_DELETE_ZONE)?;
+    /// Deletes all blocks and their transactions above `index`
+    fn truncate(&mut self, index: u64) -> sqlite::Result<()> {
+        for sql in &[SQL_TRUNCATE_BLOCKS, SQL_TRUNCATE_DOMAINS, SQL_TRUNCATE_ZONES] {
+            let mut statement = self.db.prepare(*sql)?;
             statement.bind(1, index as i64)?;
             statement.next()?;
         }
+        Ok(())
+    }
 
-        let index = block.index;
-        let timestamp = block.timestamp;
-        self.last_block = Some(block.clone());
-        if block.transaction.is_some() {
-            self.last_full_block = Some(block.clone());
-        }
-        let transaction = block.transaction.clone();
-        if self.replace_block_in_table(block).is_ok() {
-            if let Some(transaction) = transaction {
-                self.add_transaction_to_table(index, timestamp, &transaction).expect("Error adding transaction");
+    /// Re-reads cached blocks and zones from DB after removing some blocks
+    fn reload_cache(&mut self) {
+        self.last_block = None;
+        self.last_full_block = None;
+        self.zones.borrow_mut().clear();
+        if let Ok(mut statement) = self.db.prepare(SQL_GET_LAST_BLOCK) {
+            if let Ok(State::Row) = statement.next() {
+                self.last_block = Self::get_block_from_statement(&mut statement);
             }
         }
-        Ok(())
+        self.last_full_block = self.get_last_full_block(None);
+    }
+
+    /// Gets accumulated work of our blocks starting from `index`
+    fn get_work(&self, index: u64) -> u128 {
+        (index..=self.height())
+            .filter_map(|i| self.get_block(i))
+            .map(|block| block.work())
+            .sum()
+    }
+
+    /// Checks if this block is not from our branch of blockchain
+    pub fn is_fork(&self, block: &Block) -> bool {
+        let last_block = match &self.last_block {
+            None => return false,
+            Some(block) => block
+        };
+        if block.index == last_block.index + 1 {
+            return block.prev_block_hash != last_block.hash;
+        }
+        if block.index > last_block.index {
+            return false;
+        }
+        match self.get_block(block.index) {
+            None => false,
+            Some(my_block) => my_block.hash != block.hash
+        }
     }
 
     /// Adds block to blocks table
@@ -210,28 +384,6 @@ impl Chain {
         statement.next()
     }
 
-    /// Replaces block in blocks table on arrival of better block from some fork
-    fn replace_block_in_table(&mut self, block: Block) -> sqlite::Result<State> {
-        let mut statement = self.db.prepare(SQL_REPLACE_BLOCK)?;
-        statement.bind(1, block.timestamp as i64)?;
-        statement.bind(2, block.version as i64)?;
-        statement.bind(3, block.difficulty as i64)?;
-        statement.bind(4, block.random as i64)?;
-        statement.bind(5, block.nonce as i64)?;
-        match &block.transaction {
-            None => { statement.bind(6, "")?; }
-            Some(transaction) => {
-                statement.bind(6, transaction.to_string().as_str())?;
-            }
-        }
-        statement.bind(7, &**block.prev_block_hash)?;
-        statement.bind(8, &**block.hash)?;
-        statement.bind(9, &**block.pub_key)?;
-        statement.bind(10, &**block.signature)?;
-        statement.bind(11, block.index as i64)?;
-        statement.next()
-    }
-
     /// Adds transaction to transactions table
     fn add_transaction_to_table(&mut self, index: u64, timestamp: i64, t: &Transaction) -> sqlite::Result<State> {
         let sql = match t.class.as_ref() {
@@ -318,8 +470,9 @@ impl Chain {
         if domain.is_empty() {
             return false;
         }
+        let zone = !domain.contains('.');
         let identity_hash = hash_identity(domain, None);
-        if !self.is_id_available(&identity_hash, &keystore.get_public(), false) {
+        if !self.is_id_available(&identity_hash, &keystore.get_public(), zone, Utc::now().timestamp()) {
             return false;
         }
 
@@ -334,18 +487,88 @@ impl Chain {
         true
     }
 
-    /// Checks if this identity is free or is owned by the same pub_key
-    pub fn is_id_available(&self, identity: &Bytes, public_key: &Bytes, zone: bool) -> bool {
+    /// Checks if this identity is free or is owned (or co-owned) by this pub_key at the `time`.
+    /// Domains become free when they are expired for more than DOMAIN_GRACE_PERIOD,
+    /// until that only the previous owners can renew them.
+    pub fn is_id_available(&self, identity: &Bytes, public_key: &Bytes, zone: bool, time: i64) -> bool {
+        match self.get_id_ownership(identity, zone) {
+            None => true,
+            Some(ownership) => ownership.is_owner(public_key) || (!zone && ownership.is_free(time))
+        }
+    }
+
+    /// Gets current owners of domain or zone
+    pub fn get_ownership(&self, name: &str, zone: bool) -> Option<Ownership> {
+        if name.is_empty() {
+            return None;
+        }
+        self.get_id_ownership(&hash_identity(name, None), zone)
+    }
+
+    /// Gets the owners of identity and a timestamp of its last transaction
+    fn get_id_ownership(&self, identity: &Bytes, zone: bool) -> Option<Ownership> {
         let sql = match zone {
-            true => { SQL_GET_ZONE_PUBLIC_KEY_BY_ID }
-            false => { SQL_GET_DOMAIN_PUBLIC_KEY_BY_ID }
+            true => { SQL_GET_ZONE_OWNER_BY_ID }
+            false => { SQL_GET_DOMAIN_OWNER_BY_ID }
         };
 
         let mut statement = self.db.prepare(sql).unwrap();
         statement.bind(1, &***identity).expect("Error in bind");
-        while let State::Row = statement.next().unwrap() {
+        if let State::Row = statement.next().unwrap() {
             let pub_key = Bytes::from_bytes(&statement.read::<Vec<u8>>(0).unwrap());
-            if !pub_key.eq(public_key) {
+            let timestamp = statement.read::<i64>(1).unwrap();
+            let owners = get_owners_from_data(&statement.read::<String>(2).unwrap(), zone);
+            return Some(Ownership { pub_key, owners, timestamp });
+        }
+        None
+    }
+
+    /// Checks if the block with this transaction would take some identity to a new owner.
+    /// Updates by the owners, transfers and renewals are not new.
+    fn is_new_owner(&self, identity: &Bytes, public_key: &Bytes, zone: bool) -> bool {
+        match self.get_id_ownership(identity, zone) {
+            None => true,
+            Some(ownership) => !ownership.is_owner(public_key)
+        }
+    }
+
+    /// Checks if the signer of this block is allowed to make this transaction.
+    /// The main owner can change co-owners and transfer domains, co-owners can change only records.
+    fn check_transaction_owners(&self, block: &Block, transaction: &Transaction) -> bool {
+        let zone = transaction.class == CLASS_ZONE;
+        let ownership = match self.get_id_ownership(&transaction.identity, zone) {
+            Some(ownership) if ownership.is_owner(&block.pub_key) => ownership,
+            Some(ownership) if zone || !ownership.is_free(block.timestamp) => {
+                warn!("Block {:?} is trying to spoof an identity!", &block);
+                return false;
+            }
+            // New and freed identities belong to those who mine them
+            _ => {
+                if transaction.pub_key != block.pub_key {
+                    warn!("Block {:?} is trying to transfer unknown identity!", &block);
+                    return false;
+                }
+                return true;
+            }
+        };
+
+        if block.pub_key != ownership.pub_key {
+            if transaction.pub_key != ownership.pub_key {
+                warn!("Block {:?} is trying to transfer identity by co-owner!", &block);
+                return false;
+            }
+            if !same_owners(&get_owners_from_data(&transaction.data, zone), &ownership.owners) {
+                warn!("Block {:?} is trying to change owners by co-owner!", &block);
+                return false;
+            }
+        } else if transaction.pub_key != block.pub_key {
+            // If the owner signs transaction with another key it is a transfer of ownership
+            if zone {
+                warn!("Block {:?} is trying to transfer a zone!", &block);
+                return false;
+            }
+            if !check_public_key_strength(&transaction.pub_key, self.consensus.keystore_difficulty) {
+                warn!("Block {:?} is trying to transfer identity to a weak key!", &block);
                 return false;
             }
         }
@@ -390,18 +613,7 @@ impl Chain {
 
     /// Checks if some id exists in our blockchain
     pub fn is_id_in_blockchain(&self, id: &Bytes, zone: bool) -> bool {
-        let sql = match zone {
-            true => { SQL_GET_ZONE_PUBLIC_KEY_BY_ID }
-            false => { SQL_GET_DOMAIN_PUBLIC_KEY_BY_ID }
-        };
-        // Checking for existing zone in DB
-        let mut statement = self.db.prepare(sql).unwrap();
-        statement.bind(1, &***id).expect("Error in bind");
-        while let State::Row = statement.next().unwrap() {
-            // If there is such a zone
-            return true;
-        }
-        false
+        self.get_id_ownership(id, zone).is_some()
     }
 
     pub fn can_mine_domain(&self, domain: &str, pub_key: &Bytes) -> MineResult {
@@ -413,15 +625,13 @@ impl Chain {
         if !self.is_zone_in_blockchain(&zone) {
             return WrongZone;
         }
-        if let Some(transaction) = self.get_domain_transaction(&name) {
-            if transaction.pub_key.ne(pub_key) {
-                return NotOwned;
-            }
-        }
         let identity_hash = hash_identity(&name, None);
+        if !self.is_id_available(&identity_hash, pub_key, false, Utc::now().timestamp()) {
+            return NotOwned;
+        }
         if let Some(last) = self.get_last_full_block(Some(&pub_key)) {
-            let new_id = !self.is_id_in_blockchain(&identity_hash, false);
-            let time = last.timestamp + NEW_DOMAINS_INTERVAL - Utc::now().timestamp();
+            let new_id = self.is_new_owner(&identity_hash, pub_key, false);
+            let time = last.timestamp + self.consensus.new_domains_interval - Utc::now().timestamp();
             if new_id && time > 0 {
                 return Cooldown { time }
             }
@@ -447,10 +657,9 @@ impl Chain {
             }
             let identity = Bytes::from_bytes(&statement.read::<Vec<u8>>(2).unwrap());
             let confirmation = Bytes::from_bytes(&statement.read::<Vec<u8>>(3).unwrap());
-            let method = statement.read::<String>(4).unwrap();
-            let data = statement.read::<String>(5).unwrap();
-            let pub_key = Bytes::from_bytes(&statement.read::<Vec<u8>>(6).unwrap());
-            let transaction = Transaction { identity, confirmation, class: method, data, pub_key };
+            let data = statement.read::<String>(4).unwrap();
+            let pub_key = Bytes::from_bytes(&statement.read::<Vec<u8>>(5).unwrap());
+            let transaction = Transaction { identity, confirmation, class: String::from(CLASS_DOMAIN), data, pub_key };
             debug!("Found transaction for domain {}: {:?}", domain, &transaction);
             if transaction.check_identity(domain) {
                 return Some(transaction);
@@ -459,6 +668,55 @@ impl Chain {
         None
     }
 
+    /// Gets the time when this domain expires (or has expired), if it was ever mined.
+    /// Every domain transaction from the owner renews the domain for DOMAIN_LIFETIME.
+    pub fn get_domain_expiry(&self, domain: &str) -> Option<i64> {
+        if domain.is_empty() {
+            return None;
+        }
+        let identity_hash = hash_identity(domain, None);
+        self.get_id_ownership(&identity_hash, false).map(|ownership| ownership.timestamp + DOMAIN_LIFETIME)
+    }
+
+    /// Gets all domains that are owned by this keystore now, with their expiry times
+    pub fn get_owned_domains(&self, keystore: &Keystore) -> Vec<OwnedDomain> {
+        let pub_key = keystore.get_public();
+        let mut map = HashMap::new();
+        let mut statement = self.db.prepare(SQL_GET_DOMAINS_BY_KEY).unwrap();
+        statement.bind(1, &**pub_key).expect("Error in bind");
+        while let State::Row = statement.next().unwrap() {
+            let index = statement.read::<i64>(0).unwrap() as u64;
+            let timestamp = statement.read::<i64>(1).unwrap();
+            let identity = Bytes::from_bytes(&statement.read::<Vec<u8>>(2).unwrap());
+            let data = statement.read::<String>(3).unwrap();
+            let owner = Bytes::from_bytes(&statement.read::<Vec<u8>>(4).unwrap());
+            // Later transactions replace earlier ones, and the domain may be transferred in the end
+            map.insert(identity, (index, timestamp, data, owner));
+        }
+
+        let mut result = Vec::new();
+        for (identity, (index, timestamp, data, owner)) in map.into_iter() {
+            if owner != pub_key {
+                continue;
+            }
+            let name = self.decrypt_domain_name(index, &data, keystore).unwrap_or_default();
+            result.push(OwnedDomain { name, identity, expires: timestamp + DOMAIN_LIFETIME });
+        }
+        result.sort_by_key(|domain| domain.expires);
+        result
+    }
+
+    /// Domain names are encrypted with a hash of previous block, we try to get them back
+    fn decrypt_domain_name(&self, index: u64, data: &str, keystore: &Keystore) -> Option<String> {
+        let data = serde_json::from_str::<DomainData>(data).ok()?;
+        let block = self.get_block(index)?;
+        if data.domain.is_empty() || block.prev_block_hash.len() < 12 {
+            return None;
+        }
+        let name = keystore.try_decrypt(&data.domain, &block.prev_block_hash.as_slice()[..12])?;
+        String::from_utf8(name.to_vec()).ok()
+    }
+
     pub fn get_domain_info(&self, domain: &str) -> Option<String> {
         match self.get_domain_transaction(domain) {
             None => { None }
@@ -496,14 +754,18 @@ impl Chain {
         }
     }
 
+    pub fn get_consensus(&self) -> &ConsensusParams {
+        &self.consensus
+    }
+
     pub fn next_allowed_block(&self) -> u64 {
         match self.last_full_block {
             None => { self.height() + 1 }
             Some(ref block) => {
-                if block.index < LOCKER_BLOCK_START {
+                if block.index < self.consensus.locker_block_start {
                     self.height() + 1
                 } else {
-                    max(block.index, self.height()) + LOCKER_BLOCK_SIGNS
+                    max(block.index, self.height()) + self.consensus.locker_block_signs
                 }
             }
         }
@@ -526,16 +788,22 @@ impl Chain {
             warn!("Ignoring block from the future:\n{:?}", &block);
             return Bad;
         }
-        if !check_public_key_strength(&block.pub_key, KEYSTORE_DIFFICULTY) {
+        if let Some(hash) = self.checkpoints.get(&block.index) {
+            if *hash != block.hash {
+                warn!("Block {} conflicts with checkpoint, ignoring", block.index);
+                return Bad;
+            }
+        }
+        if !check_public_key_strength(&block.pub_key, self.consensus.keystore_difficulty) {
             warn!("Ignoring block with weak public key:\n{:?}", &block);
             return Bad;
         }
         let difficulty = match &block.transaction {
             None => {
                 if block.index == 1 {
-                    ZONE_DIFFICULTY
+                    self.consensus.zone_difficulty
                 } else {
-                    LOCKER_DIFFICULTY
+                    self.consensus.locker_difficulty
                 }
             }
             Some(t) => { self.get_difficulty_for_transaction(&t) }
@@ -552,19 +820,19 @@ impl Chain {
             warn!("Block {:?} has wrong hash! Ignoring!", &block);
             return Bad;
         }
-        if !check_block_signature(&block) {
+        // Hashes are always checked, they link blocks to the next checkpoint, but signatures under it can be skipped
+        if block.index >= self.last_checkpoint() && !check_block_signature(block) {
             warn!("Block {:?} has wrong signature! Ignoring!", &block);
             return Bad;
         }
         if let Some(transaction) = &block.transaction {
-            // TODO check for zone transaction
-            if !self.is_id_available(&transaction.identity, &block.pub_key, false) {
-                warn!("Block {:?} is trying to spoof an identity!", &block);
+            if !self.check_transaction_owners(block, transaction) {
                 return Bad;
             }
             if let Some(last) = self.get_last_full_block(Some(&block.pub_key)) {
-                let new_id = !self.is_id_in_blockchain(&transaction.identity, false);
-                if new_id && last.timestamp + NEW_DOMAINS_INTERVAL > block.timestamp {
+                let zone = transaction.class == CLASS_ZONE;
+                let new_id = self.is_new_owner(&transaction.identity, &block.pub_key, zone);
+                if new_id && last.timestamp + self.consensus.new_domains_interval > block.timestamp {
                     warn!("Block {:?} is mined too early!", &block);
                     return Bad;
                 }
@@ -590,11 +858,15 @@ impl Chain {
                     warn!("Block {} arrived too early.", block.index);
                     return Future;
                 }
-                if block.index >= LOCKER_BLOCK_START {
+                if last_block.index + 1 == block.index && last_block.hash != block.prev_block_hash {
+                    warn!("Got block {} that doesn't continue our last block", block.index);
+                    return Fork;
+                }
+                if block.index >= self.consensus.locker_block_start {
                     // If this block is locked part of blockchain
                     if let Some(full_block) = &self.last_full_block {
                         let locker_blocks = self.height() - full_block.index;
-                        if locker_blocks < LOCKER_BLOCK_SIGNS {
+                        if locker_blocks < self.consensus.locker_block_signs {
                             // Last full block is not locked enough
                             if block.transaction.is_some() {
                                 warn!("Not enough signing blocks over full {} block!", full_block.index);
@@ -604,10 +876,8 @@ impl Chain {
                                     return Bad;
                                 }
                             }
-                        } else if locker_blocks < LOCKER_BLOCK_LOCKERS && block.transaction.is_none() {
-                            if self.check_block_for_signing(&block, full_block) == Bad {
-                                return Bad;
-                            }
+                        } else if locker_blocks < self.consensus.locker_block_lockers && block.transaction.is_none() && self.check_block_for_signing(block, full_block) == Bad {
+                            return Bad;
                         }
                     }
                 }
@@ -649,7 +919,7 @@ impl Chain {
                     Err(_) => { u32::max_value() }
                 }
             }
-            "zone" => { ZONE_DIFFICULTY }
+            "zone" => { self.consensus.zone_difficulty }
             _ => { u32::max_value() }
         }
     }
@@ -676,16 +946,16 @@ impl Chain {
     /// block - last full block
     pub fn get_block_signers(&self, block: &Block) -> Vec<Bytes> {
         let mut result = Vec::new();
-        if block.index < LOCKER_BLOCK_START {
+        if block.index < self.consensus.locker_block_start {
             return result;
         }
         let mut set = HashSet::new();
         let tail = block.hash.get_tail_u64();
-        let interval = min(block.index, LOCKER_BLOCK_INTERVAL) - 1;
+        let interval = min(block.index, self.consensus.locker_block_interval) - 1;
         let start_index = block.index - interval;
         let mut count = 1;
-        while set.len() < LOCKER_BLOCK_LOCKERS as usize {
-            let index = start_index + ((tail * count) % LOCKER_BLOCK_INTERVAL);
+        while set.len() < self.consensus.locker_block_lockers as usize {
+            let index = start_index + ((tail * count) % self.consensus.locker_block_interval);
             if let Some(b) = self.get_block(index) {
                 if b.pub_key != block.pub_key && !set.contains(&b.pub_key) {
                     result.push(b.pub_key.clone());
@@ -712,4 +982,369 @@ impl Chain {
         let signature = Bytes::from_bytes(statement.read::<Vec<u8>>(10).unwrap().as_slice());
         Some(Block::from_all_params(index, timestamp, version, difficulty, random, nonce, prev_block_hash, hash, pub_key, signature, transaction))
     }
-}
\ No newline at end of file
+}
+
+/// Gets co-owners list from domain or zone data
+fn get_owners_from_data(data: &str, zone: bool) -> Vec<Bytes> {
+    let owners = match zone {
+        true => serde_json::from_str::<ZoneData>(data).map(|data| data.owners),
+        false => serde_json::from_str::<DomainData>(data).map(|data| data.owners)
+    };
+    owners.unwrap_or_default()
+}
+
+/// Compares owners lists regardless of their order
+fn same_owners(first: &[Bytes], second: &[Bytes]) -> bool {
+    let first: HashSet<&Bytes> = first.iter().collect();
+    let second: HashSet<&Bytes> = second.iter().collect();
+    first == second
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::cell::RefCell;
+    use std::collections::{BTreeMap, HashSet};
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicBool;
+
+    use chrono::Utc;
+
+    use crate::{Block, Bytes, Keystore, Settings, Transaction, get_domain_zone};
+    use crate::blockchain::chain::{Chain, SQL_CREATE_TABLES};
+    use crate::blockchain::hash_utils::{blakeout_data, hash_difficulty, hash_identity};
+    use crate::blockchain::transaction::{DomainData, ZoneData};
+    use crate::blockchain::types::BlockQuality;
+    use crate::blockchain::types::BlockQuality::*;
+    use crate::keys::generate_key;
+    use crate::commons::{CLASS_DOMAIN, CLASS_ZONE, ConsensusParams, DB_VERSION, DOMAIN_GRACE_PERIOD, DOMAIN_LIFETIME, MAIN_ORIGIN};
+
+    const FIXTURE_DB_V0: &str = include_str!("sql/fixtures/db_v0.sql");
+
+    pub fn memory_chain() -> Chain {
+        let db = sqlite::open(":memory:").unwrap();
+        db.execute(SQL_CREATE_TABLES).unwrap();
+        Chain { origin: Bytes::default(), last_block: None, last_full_block: None, max_height: 0, db, db_path: PathBuf::new(), zones: RefCell::new(HashSet::new()), checkpoints: BTreeMap::new(), consensus: ConsensusParams::main() }
+    }
+
+    /// Chain with the easiest consensus, without signing blocks and cooldowns between domains
+    pub fn test_chain() -> Chain {
+        let mut chain = memory_chain();
+        let easy = ConsensusParams { zone_difficulty: 1, zone_min_difficulty: 1, locker_difficulty: 1, locker_block_start: u64::MAX, new_domains_interval: 0, ..ConsensusParams::regtest() };
+        chain.consensus = easy;
+        chain
+    }
+
+    pub fn test_key() -> Keystore {
+        generate_key(ConsensusParams::regtest().keystore_difficulty, Arc::new(AtomicBool::new(true))).unwrap()
+    }
+
+    /// Mines a block over `prev`, or a genesis block without it
+    pub fn mine_block(prev: Option<&Block>, keystore: &Keystore, transaction: Option<Transaction>, difficulty: u32) -> Block {
+        let prev_hash = prev.map(|block| block.hash.clone()).unwrap_or_default();
+        let mut block = Block::new(transaction, keystore.get_public(), prev_hash, difficulty);
+        block.index = prev.map(|block| block.index + 1).unwrap_or(1);
+        block.timestamp = Utc::now().timestamp();
+        block.hash = loop {
+            let hash = blakeout_data(&block.as_bytes());
+            if hash_difficulty(&hash) >= block.difficulty {
+                break hash;
+            }
+            block.nonce += 1;
+        };
+        block.signature = Bytes::from_bytes(&keystore.sign(&block.as_bytes()));
+        block
+    }
+
+    /// Mines a block over the last block of the chain with needed difficulty
+    fn mine_next(chain: &Chain, keystore: &Keystore, transaction: Option<Transaction>) -> Block {
+        let difficulty = match &transaction {
+            None if chain.height() == 0 => chain.consensus.zone_difficulty,
+            None => chain.consensus.locker_difficulty,
+            Some(transaction) => chain.get_difficulty_for_transaction(transaction)
+        };
+        mine_block(chain.last_block.as_ref(), keystore, transaction, difficulty)
+    }
+
+    /// Mines the next block and adds it to the chain if it is good
+    fn add_next(chain: &mut Chain, keystore: &Keystore, transaction: Option<Transaction>) -> BlockQuality {
+        let block = mine_next(chain, keystore, transaction);
+        let quality = chain.check_new_block(&block);
+        if quality == Good {
+            chain.add_block(block).unwrap();
+        }
+        quality
+    }
+
+    fn zone_transaction(name: &str, pub_key: &Bytes, owners: Vec<Bytes>) -> Transaction {
+        let data = ZoneData { name: name.to_owned(), difficulty: 1, yggdrasil: false, owners };
+        Transaction::from_str(name.to_owned(), CLASS_ZONE.to_owned(), serde_json::to_string(&data).unwrap(), pub_key.clone())
+    }
+
+    /// Domain transaction with a fake encrypted name, it is empty in transfers as the new owner can't decrypt it
+    fn domain_transaction(name: &str, pub_key: &Bytes, owners: Vec<Bytes>) -> Transaction {
+        let encrypted = Bytes::from_bytes(&name.as_bytes()[..name.len() - 1]);
+        let data = DomainData::new(encrypted, get_domain_zone(name), Vec::new(), Vec::new(), owners);
+        Transaction::from_str(name.to_owned(), CLASS_DOMAIN.to_owned(), serde_json::to_string(&data).unwrap(), pub_key.clone())
+    }
+
+    fn transfer_transaction(name: &str, pub_key: &Bytes) -> Transaction {
+        let data = DomainData::new(Bytes::default(), get_domain_zone(name), Vec::new(), Vec::new(), Vec::new());
+        Transaction::from_str(name.to_owned(), CLASS_DOMAIN.to_owned(), serde_json::to_string(&data).unwrap(), pub_key.clone())
+    }
+
+    /// Chain with genesis block and `ygg` zone mined by `keystore`
+    fn chain_with_zone(keystore: &Keystore) -> Chain {
+        let mut chain = test_chain();
+        assert_eq!(add_next(&mut chain, keystore, None), Good);
+        assert_eq!(add_next(&mut chain, keystore, Some(zone_transaction("ygg", &keystore.get_public(), Vec::new()))), Good);
+        chain
+    }
+
+    fn make_block(class: &str) -> Block {
+        let transaction = Transaction::from_str(String::from("test.ygg"), String::from(class), String::from("{}"), Bytes::zero32());
+        Block::from_all_params(1, 0, 0, 0, 0, 0, Bytes::default(), Bytes::zero32(), Bytes::zero32(), Bytes::zero32(), Some(transaction))
+    }
+
+    #[test]
+    fn add_block_is_atomic() {
+        let mut chain = memory_chain();
+        assert!(chain.add_block(make_block("wrong")).is_err());
+        assert_eq!(chain.height(), 0);
+        assert!(chain.get_block(1).is_none());
+
+        assert!(chain.add_block(make_block("domain")).is_ok());
+        assert_eq!(chain.height(), 1);
+    }
+
+    #[test]
+    fn repair_half_applied_block() {
+        let mut chain = memory_chain();
+        let block = make_block("domain");
+        let identity = block.transaction.as_ref().unwrap().identity.clone();
+        chain.add_block_to_table(block).unwrap();
+        assert!(!chain.is_id_in_blockchain(&identity, false));
+
+        chain.atomic(|chain| chain.repair_db()).unwrap();
+        assert!(chain.is_id_in_blockchain(&identity, false));
+    }
+
+    #[test]
+    fn migrate_old_db() {
+        let db = sqlite::open(":memory:").unwrap();
+        db.execute(FIXTURE_DB_V0).unwrap();
+        let chain = Chain::open(Bytes::default(), db, PathBuf::new()).unwrap();
+        let options = chain.get_options();
+        assert_eq!(options.version, DB_VERSION);
+        assert_eq!(options.origin, "0000000A");
+        assert_eq!(chain.height(), 2);
+        assert!(chain.db.prepare("SELECT * FROM peers;").is_ok());
+    }
+
+    #[test]
+    fn remove_blocks_conflicting_with_checkpoint() {
+        let mut chain = memory_chain();
+        chain.add_block(make_block("domain")).unwrap();
+        let mut checkpoints = BTreeMap::new();
+        checkpoints.insert(1, Bytes::zero32());
+        chain.set_checkpoints(checkpoints.clone()).unwrap();
+        assert_eq!(chain.height(), 1);
+
+        checkpoints.insert(1, Bytes::new(vec![1u8; 32]));
+        chain.set_checkpoints(checkpoints).unwrap();
+        assert_eq!(chain.height(), 0);
+        assert!(chain.get_block(1).is_none());
+    }
+
+    #[test]
+    fn mine_regtest_genesis() {
+        let mut chain = memory_chain();
+        chain.consensus = ConsensusParams::regtest();
+        let keystore = generate_key(chain.consensus.keystore_difficulty, Arc::new(AtomicBool::new(true))).unwrap();
+        let mut block = Block::new(None, keystore.get_public(), Bytes::default(), chain.consensus.zone_difficulty);
+        block.index = 1;
+        block.timestamp = Utc::now().timestamp();
+        block.hash = loop {
+            let hash = blakeout_data(&block.as_bytes());
+            if hash_difficulty(&hash) >= block.difficulty {
+                break hash;
+            }
+            block.nonce += 1;
+        };
+        block.signature = Bytes::from_bytes(&keystore.sign(&block.as_bytes()));
+        assert_eq!(chain.check_new_block(&block), BlockQuality::Good);
+        chain.consensus = ConsensusParams::main();
+        assert_eq!(chain.check_new_block(&block), BlockQuality::Bad);
+    }
+
+    #[test]
+    fn transfer_domain_to_new_key() {
+        let (old, new) = (test_key(), test_key());
+        let mut chain = chain_with_zone(&old);
+        assert_eq!(add_next(&mut chain, &old, Some(domain_transaction("test.ygg", &old.get_public(), Vec::new()))), Good);
+        // Transfer is signed by the owner, but the transaction has the key of the new owner
+        assert_eq!(add_next(&mut chain, &old, Some(transfer_transaction("test.ygg", &new.get_public()))), Good);
+
+        let renewal = mine_next(&chain, &new, Some(domain_transaction("test.ygg", &new.get_public(), Vec::new())));
+        assert_eq!(chain.check_new_block(&renewal), Good);
+        let renewal = mine_next(&chain, &old, Some(domain_transaction("test.ygg", &old.get_public(), Vec::new())));
+        assert_eq!(chain.check_new_block(&renewal), Bad);
+    }
+
+    #[test]
+    fn domain_co_owners() {
+        let (owner, co_owner, other) = (test_key(), test_key(), test_key());
+        let mut chain = chain_with_zone(&owner);
+        let owners = vec![co_owner.get_public()];
+        assert_eq!(add_next(&mut chain, &owner, Some(domain_transaction("test.ygg", &owner.get_public(), owners.clone()))), Good);
+
+        // Co-owner can change records, but the transaction stays with the key of the owner
+        let update = mine_next(&chain, &co_owner, Some(domain_transaction("test.ygg", &owner.get_public(), owners.clone())));
+        assert_eq!(chain.check_new_block(&update), Good);
+        let change_owners = mine_next(&chain, &co_owner, Some(domain_transaction("test.ygg", &owner.get_public(), vec![co_owner.get_public(), other.get_public()])));
+        assert_eq!(chain.check_new_block(&change_owners), Bad);
+        let transfer = mine_next(&chain, &co_owner, Some(domain_transaction("test.ygg", &co_owner.get_public(), owners)));
+        assert_eq!(chain.check_new_block(&transfer), Bad);
+        let spoof = mine_next(&chain, &other, Some(domain_transaction("test.ygg", &owner.get_public(), Vec::new())));
+        assert_eq!(chain.check_new_block(&spoof), Bad);
+    }
+
+    #[test]
+    fn zone_co_owners() {
+        let (owner, co_owner, other) = (test_key(), test_key(), test_key());
+        let mut chain = test_chain();
+        assert_eq!(add_next(&mut chain, &owner, None), Good);
+        let owners = vec![co_owner.get_public()];
+        assert_eq!(add_next(&mut chain, &owner, Some(zone_transaction("ygg", &owner.get_public(), owners.clone()))), Good);
+
+        let update = mine_next(&chain, &co_owner, Some(zone_transaction("ygg", &owner.get_public(), owners.clone())));
+        assert_eq!(chain.check_new_block(&update), Good);
+        let change_owners = mine_next(&chain, &co_owner, Some(zone_transaction("ygg", &owner.get_public(), Vec::new())));
+        assert_eq!(chain.check_new_block(&change_owners), Bad);
+        let transfer = mine_next(&chain, &co_owner, Some(zone_transaction("ygg", &co_owner.get_public(), owners.clone())));
+        assert_eq!(chain.check_new_block(&transfer), Bad);
+        // Zones can't be transferred even by the owner
+        let transfer = mine_next(&chain, &owner, Some(zone_transaction("ygg", &other.get_public(), owners)));
+        assert_eq!(chain.check_new_block(&transfer), Bad);
+    }
+
+    /// Mines a fork of `count` domain blocks over the block at `index`
+    fn mine_fork(chain: &Chain, keystore: &Keystore, index: u64, count: usize) -> Vec<Block> {
+        let mut prev = chain.get_block(index).unwrap();
+        let mut blocks = Vec::new();
+        for i in 0..count {
+            let transaction = domain_transaction(&format!("fork{}.ygg", i), &keystore.get_public(), Vec::new());
+            let difficulty = chain.get_difficulty_for_transaction(&transaction);
+            let block = mine_block(Some(&prev), keystore, Some(transaction), difficulty);
+            blocks.push(block.clone());
+            prev = block;
+        }
+        blocks
+    }
+
+    #[test]
+    fn apply_fork_with_more_work() {
+        let keystore = test_key();
+        let mut chain = chain_with_zone(&keystore);
+        assert_eq!(add_next(&mut chain, &keystore, Some(domain_transaction("test.ygg", &keystore.get_public(), Vec::new()))), Good);
+        let our_hash = chain.last_hash();
+
+        // Same work as ours
+        assert_eq!(chain.apply_fork(mine_fork(&chain, &keystore, 2, 1)), Ok(false));
+        assert_eq!(chain.height(), 3);
+        assert_eq!(chain.last_hash(), our_hash);
+
+        let fork = mine_fork(&chain, &keystore, 2, 2);
+        assert!(chain.get_work(3) < fork.iter().map(|block| block.work()).sum());
+        assert_eq!(chain.apply_fork(fork.clone()), Ok(true));
+        assert_eq!(chain.height(), 4);
+        assert_eq!(chain.get_block(3).unwrap().hash, fork[0].hash);
+        assert_eq!(chain.last_hash(), fork[1].hash);
+        assert!(chain.get_ownership("fork1.ygg", false).is_some());
+        assert!(chain.get_ownership("test.ygg", false).is_none());
+    }
+
+    #[test]
+    fn apply_fork_with_bad_block() {
+        let keystore = test_key();
+        let mut chain = chain_with_zone(&keystore);
+        assert_eq!(add_next(&mut chain, &keystore, Some(domain_transaction("test.ygg", &keystore.get_public(), Vec::new()))), Good);
+        let our_hash = chain.last_hash();
+
+        let mut fork = mine_fork(&chain, &keystore, 2, 3);
+        fork[2].signature = Bytes::new(vec![0u8; 64]);
+        assert!(chain.apply_fork(fork).is_err());
+        // Removed blocks are back after rollback
+        assert_eq!(chain.height(), 3);
+        assert_eq!(chain.last_hash(), our_hash);
+        assert!(chain.get_ownership("test.ygg", false).is_some());
+        assert!(chain.get_ownership("fork0.ygg", false).is_none());
+    }
+
+    #[test]
+    fn refuse_blocks_conflicting_with_checkpoint() {
+        let keystore = test_key();
+        let mut chain = chain_with_zone(&keystore);
+        let block = mine_next(&chain, &keystore, Some(domain_transaction("test.ygg", &keystore.get_public(), Vec::new())));
+        let mut checkpoints = BTreeMap::new();
+        checkpoints.insert(3, Bytes::new(vec![1u8; 32]));
+        chain.set_checkpoints(checkpoints.clone()).unwrap();
+        assert_eq!(chain.check_new_block(&block), Bad);
+
+        checkpoints.insert(3, block.hash.clone());
+        chain.set_checkpoints(checkpoints).unwrap();
+        assert_eq!(chain.check_new_block(&block), Good);
+    }
+
+    #[test]
+    fn refuse_blocks_conflicting_with_built_in_checkpoint() {
+        let keystore = test_key();
+        let mut chain = test_chain();
+        let genesis = mine_next(&chain, &keystore, None);
+        assert_eq!(chain.check_new_block(&genesis), Good);
+
+        let settings = Settings { origin: String::from(MAIN_ORIGIN), ..Settings::default() };
+        assert!(settings.checkpoints.is_empty());
+        chain.set_checkpoints(settings.get_checkpoints().unwrap()).unwrap();
+        assert_eq!(chain.check_new_block(&genesis), Bad);
+    }
+
+    #[test]
+    fn refuse_fork_over_checkpoint() {
+        let keystore = test_key();
+        let mut chain = chain_with_zone(&keystore);
+        assert_eq!(add_next(&mut chain, &keystore, Some(domain_transaction("test.ygg", &keystore.get_public(), Vec::new()))), Good);
+        let our_hash = chain.last_hash();
+        let mut checkpoints = BTreeMap::new();
+        checkpoints.insert(3, our_hash.clone());
+        chain.set_checkpoints(checkpoints).unwrap();
+
+        // This fork has more work, but it can't replace the checkpoint
+        assert!(chain.apply_fork(mine_fork(&chain, &keystore, 2, 2)).is_err());
+        assert_eq!(chain.height(), 3);
+        assert_eq!(chain.last_hash(), our_hash);
+    }
+
+    #[test]
+    fn expired_domain_grace_period() {
+        let (owner, other) = (test_key(), test_key());
+        let mut chain = chain_with_zone(&owner);
+        assert_eq!(add_next(&mut chain, &owner, Some(domain_transaction("test.ygg", &owner.get_public(), Vec::new()))), Good);
+        let expires = chain.last_block.as_ref().unwrap().timestamp + DOMAIN_LIFETIME;
+        let identity = hash_identity("test.ygg", None);
+
+        assert!(!chain.is_id_available(&identity, &other.get_public(), false, expires - 1));
+        // Only the owner can renew it during the grace period
+        assert!(chain.is_id_available(&identity, &owner.get_public(), false, expires + 1));
+        assert!(!chain.is_id_available(&identity, &other.get_public(), false, expires + 1));
+        // And then anyone can take it
+        assert!(chain.is_id_available(&identity, &other.get_public(), false, expires + DOMAIN_GRACE_PERIOD + 1));
+    }
+
+    #[test]
+    fn refuse_newer_db() {
+        let chain = memory_chain();
+        chain.set_option("version", &(DB_VERSION + 1).to_string()).unwrap();
+        assert!(Chain::open(Bytes::default(), chain.db, PathBuf::new()).is_err());
+    }
+}
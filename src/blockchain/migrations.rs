@@ -0,0 +1,34 @@
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use sqlite::Connection;
+
+/// Ordered migration steps, step N upgrades DB from version N to N + 1
+const MIGRATIONS: &[&str] = &[
+    include_str!("sql/migrations/001_zone_ids.sql"),
+    include_str!("sql/migrations/002_peers.sql"),
+    include_str!("sql/migrations/003_peers_stats.sql"),
+];
+
+/// Runs all migration steps to upgrade DB from version `from` to version `to`
+pub fn migrate(db: &Connection, from: u32, to: u32) -> Result<(), String> {
+    for version in from..to {
+        let step = match MIGRATIONS.get(version as usize) {
+            None => return Err(format!("No migration from DB version {}", version)),
+            Some(step) => step
+        };
+        info!("Migrating blockchain DB from version {} to {}", version, version + 1);
+        db.execute(step).map_err(|e| format!("Error migrating DB from version {}: {}", version, e))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::blockchain::migrations::MIGRATIONS;
+    use crate::commons::DB_VERSION;
+
+    #[test]
+    fn migrations_count() {
+        assert_eq!(MIGRATIONS.len(), DB_VERSION as usize);
+    }
+}
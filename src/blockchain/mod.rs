@@ -7,5 +7,6 @@ pub mod block;
 pub mod chain;
 pub mod filter;
 pub mod hash_utils;
+pub mod migrations;
 pub mod types;
 
@@ -0,0 +1,42 @@
+-- Blockchain DB of version 0, made by ALFIS 0.4 and older, without options
+CREATE TABLE blocks (
+    'id' BIGINT NOT NULL PRIMARY KEY,
+    'timestamp' BIGINT NOT NULL,
+    'version' INT,
+    'difficulty' INTEGER,
+    'random' INTEGER,
+    'nonce' INTEGER,
+    'transaction' TEXT,
+    'prev_block_hash' BINARY,
+    'hash' BINARY,
+    'pub_key' BINARY,
+    'signature' BINARY
+);
+CREATE INDEX block_index ON blocks (id);
+CREATE INDEX keys ON blocks (pub_key);
+
+CREATE TABLE domains (
+    'id' BIGINT NOT NULL PRIMARY KEY,
+    'timestamp' BIGINT NOT NULL,
+    'identity' BINARY,
+    'confirmation' BINARY,
+    'data' TEXT,
+    'pub_key' BINARY
+);
+CREATE INDEX ids ON domains ('identity');
+
+CREATE TABLE zones (
+    'id' BIGINT NOT NULL PRIMARY KEY,
+    'timestamp' BIGINT NOT NULL,
+    'identity' BINARY,
+    'confirmation' BINARY,
+    'data' TEXT,
+    'pub_key' BINARY
+);
+
+CREATE TABLE options ('name' TEXT NOT NULL, 'value' TEXT NOT NULL);
+
+INSERT INTO blocks (id, timestamp, version, difficulty, random, nonce, 'transaction', prev_block_hash, hash, pub_key, signature)
+VALUES (1, 1616000000, 0, 28, 0, 0, '', X'', X'0000000A', X'0000000B', X'0000000C');
+INSERT INTO blocks (id, timestamp, version, difficulty, random, nonce, 'transaction', prev_block_hash, hash, pub_key, signature)
+VALUES (2, 1616000060, 0, 22, 0, 0, '', X'0000000A', X'0000000D', X'0000000B', X'0000000E');
@@ -0,0 +1 @@
+CREATE INDEX IF NOT EXISTS zone_ids ON zones ('identity');
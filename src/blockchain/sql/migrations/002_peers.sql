@@ -0,0 +1,6 @@
+CREATE TABLE IF NOT EXISTS peers (
+    'addr' TEXT NOT NULL PRIMARY KEY,
+    'last_seen' BIGINT NOT NULL,
+    'score' INTEGER NOT NULL,
+    'banned_until' BIGINT NOT NULL
+);
@@ -0,0 +1,3 @@
+ALTER TABLE peers ADD COLUMN 'successes' INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE peers ADD COLUMN 'failures' INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE peers ADD COLUMN 'ban_reason' TEXT NOT NULL DEFAULT '';
@@ -1,6 +1,7 @@
 use std::fmt;
 
-use serde::{Deserialize, Serialize, Serializer};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde::de::Error as DeError;
 use serde::ser::SerializeStruct;
 
 use crate::blockchain::hash_utils::*;
@@ -81,6 +82,7 @@ impl Serialize for Transaction {
 
 #[derive(Clone, Serialize, Deserialize, PartialEq)]
 pub struct DomainData {
+    #[serde(deserialize_with = "deserialize_name")]
     pub domain: Bytes,
     pub zone: String,
     pub records: Vec<DnsRecord>,
@@ -95,6 +97,18 @@ impl DomainData {
     }
 }
 
+/// Encrypted names can have any length, unlike keys and hashes, and transferred domains have no name at all
+fn deserialize_name<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Bytes, D::Error> {
+    let hex = String::deserialize(deserializer)?;
+    if hex.is_empty() {
+        return Ok(Bytes::default());
+    }
+    if hex.len() % 2 != 0 {
+        return Err(D::Error::custom("Wrong length of encrypted name"));
+    }
+    crate::from_hex(&hex).map(Bytes::new).map_err(|_| D::Error::custom("Wrong encrypted name"))
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 pub struct ZoneData {
     pub name: String,
@@ -1,5 +1,10 @@
+use serde::Serialize;
+
+use crate::Bytes;
+use crate::commons::{DOMAIN_GRACE_PERIOD, DOMAIN_LIFETIME};
+
 /// Represents a result of block check on block's arrival
-#[derive(PartialEq)]
+#[derive(Debug, PartialEq)]
 pub enum BlockQuality {
     Good,
     Twin,
@@ -19,6 +24,36 @@ pub enum MineResult {
     Cooldown { time: i64 },
 }
 
+/// A domain that is owned by our key
+#[derive(Clone, Debug, Serialize)]
+pub struct OwnedDomain {
+    /// Decrypted name, or empty string if we can't decrypt it
+    pub name: String,
+    pub identity: Bytes,
+    pub expires: i64,
+}
+
+/// Owners of domain or zone, as written in its last transaction
+#[derive(Clone, Debug)]
+pub struct Ownership {
+    /// The main owner, it can change co-owners and transfer domain
+    pub pub_key: Bytes,
+    /// Co-owners, they can change records and renew domain
+    pub owners: Vec<Bytes>,
+    pub timestamp: i64,
+}
+
+impl Ownership {
+    pub fn is_owner(&self, pub_key: &Bytes) -> bool {
+        self.pub_key.eq(pub_key) || self.owners.contains(pub_key)
+    }
+
+    /// Checks if domain is expired and its grace period is over
+    pub fn is_free(&self, time: i64) -> bool {
+        self.timestamp + DOMAIN_LIFETIME + DOMAIN_GRACE_PERIOD < time
+    }
+}
+
 #[derive(Debug)]
 pub struct Options {
     pub origin: String,
@@ -33,4 +68,25 @@ impl Options {
     pub fn empty() -> Self {
         Options { origin: String::new(), version: 0 }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Bytes;
+    use crate::blockchain::types::Ownership;
+    use crate::commons::{DOMAIN_GRACE_PERIOD, DOMAIN_LIFETIME};
+
+    #[test]
+    fn test_ownership() {
+        let owner = Bytes::from_bytes(&[1u8; 32]);
+        let co_owner = Bytes::from_bytes(&[2u8; 32]);
+        let stranger = Bytes::from_bytes(&[3u8; 32]);
+        let ownership = Ownership { pub_key: owner.clone(), owners: vec![co_owner.clone()], timestamp: 1000 };
+        assert!(ownership.is_owner(&owner));
+        assert!(ownership.is_owner(&co_owner));
+        assert!(!ownership.is_owner(&stranger));
+        assert!(!ownership.is_free(1000 + DOMAIN_LIFETIME));
+        assert!(!ownership.is_free(1000 + DOMAIN_LIFETIME + DOMAIN_GRACE_PERIOD));
+        assert!(ownership.is_free(1001 + DOMAIN_LIFETIME + DOMAIN_GRACE_PERIOD));
+    }
+}
@@ -134,7 +134,12 @@ impl fmt::Debug for Bytes {
 impl Serialize for Bytes {
     fn serialize<S>(&self, serializer: S) -> Result<<S as Serializer>::Ok, <S as Serializer>::Error> where
         S: Serializer {
-        serializer.serialize_str(&crate::commons::to_hex(&self.data))
+        // Binary formats get raw bytes, text formats get hex string
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&crate::commons::to_hex(&self.data))
+        } else {
+            serializer.serialize_bytes(&self.data)
+        }
     }
 }
 
@@ -166,7 +171,11 @@ impl<'de> Visitor<'de> for BytesVisitor {
 
 impl<'dd> Deserialize<'dd> for Bytes {
     fn deserialize<D>(deserializer: D) -> Result<Self, <D as Deserializer<'dd>>::Error> where D: Deserializer<'dd> {
-        deserializer.deserialize_str(BytesVisitor)
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_str(BytesVisitor)
+        } else {
+            deserializer.deserialize_bytes(BytesVisitor)
+        }
     }
 }
 
@@ -0,0 +1,329 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex, mpsc};
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+use alfis::{Block, Chain, Context, Miner, check_domain};
+use alfis::blockchain::types::{BlockQuality, MineResult};
+use alfis::commons::ZONE_MAX_LENGTH;
+use alfis::event::Event;
+use alfis::miner::{MineRequest, mine_request};
+
+/// Exit code when the domain can be mined, or the mined block was accepted
+pub const EXIT_OK: i32 = 0;
+/// Exit code when the domain cannot be mined, or the mined block was rejected
+pub const EXIT_REJECTED: i32 = 1;
+/// Exit code for broken record files, missing keys and so on
+pub const EXIT_ERROR: i32 = 2;
+
+/// Time for network to connect to some nodes and learn their heights
+const SYNC_START_DELAY: Duration = Duration::from_secs(10);
+/// If we can't sync for this long we just start mining
+const SYNC_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Checks if domain or zone `name` can be mined with loaded keys, prints the result and returns exit code
+pub fn check(context: &Arc<Mutex<Context>>, name: &str) -> i32 {
+    let name = name.to_lowercase();
+    let context = context.lock().unwrap();
+    let keystore = match context.get_keystore() {
+        None => {
+            println!("You don't have keys loaded, can't check {}", &name);
+            return EXIT_ERROR;
+        }
+        Some(keystore) => keystore
+    };
+    let result = if name.contains('.') {
+        context.chain.can_mine_domain(&name, &keystore.get_public())
+    } else if name.len() > ZONE_MAX_LENGTH || !check_domain(&name, false) || context.x_zones.has_zone(&name) {
+        MineResult::WrongName
+    } else if !context.chain.is_domain_available(&name, &keystore) {
+        MineResult::NotOwned
+    } else {
+        MineResult::Fine
+    };
+    println!("{} {}", &name, describe(&result));
+    match result {
+        MineResult::Fine => EXIT_OK,
+        _ => EXIT_REJECTED
+    }
+}
+
+/// Mines (or updates) domain or zone from record file, waits for the block to be accepted and returns exit code
+pub fn mine(context: &Arc<Mutex<Context>>, miner: &Arc<Mutex<Miner>>, file: &str) -> i32 {
+    let record = match load_record(file) {
+        Ok(record) => record,
+        Err(e) => {
+            println!("{}", e);
+            return EXIT_ERROR;
+        }
+    };
+    if context.lock().unwrap().get_keystore().is_none() {
+        println!("You don't have keys loaded, can't mine {}", &record.name);
+        return EXIT_ERROR;
+    }
+    wait_for_sync(context);
+
+    let (sender, receiver) = mpsc::channel();
+    // Sender is not Sync, and bus listeners have to be
+    let sender = Mutex::new(sender);
+    let mut speeds = HashMap::new();
+    let mut max = 0;
+    context.lock().unwrap().bus.register(move |_uuid, e| {
+        match e {
+            Event::MinerStarted => {
+                info!("Mining started");
+            }
+            Event::MinerStats { thread, speed, max_diff } => {
+                speeds.insert(thread, speed);
+                if max_diff > max {
+                    max = max_diff;
+                }
+                info!("Mining speed {} H/s, max found difficulty {}", speeds.values().sum::<u64>(), max);
+            }
+            Event::MinerStopped { success, full } => {
+                // Lockers are mined by the same miner, we need only our block
+                if full {
+                    let _ = sender.lock().unwrap().send(success);
+                    return false;
+                }
+            }
+            _ => {}
+        }
+        true
+    });
+
+    let name = record.name.to_lowercase();
+    let result = mine_request(context, miner, record);
+    match result {
+        MineResult::Fine => {}
+        result => {
+            println!("{} {}", &name, describe(&result));
+            return EXIT_REJECTED;
+        }
+    }
+
+    match receiver.recv() {
+        Ok(true) => {
+            println!("Block with {} is mined and accepted", &name);
+            EXIT_OK
+        }
+        _ => {
+            println!("Block with {} was not accepted", &name);
+            EXIT_REJECTED
+        }
+    }
+}
+
+/// Writes all blocks from DB to `file`, one block in JSON per line
+pub fn export(chain: &Chain, file: &str) -> i32 {
+    match export_blocks(chain, file) {
+        Ok(count) => {
+            println!("Exported {} blocks to {}", count, file);
+            EXIT_OK
+        }
+        Err(e) => {
+            println!("{}", e);
+            EXIT_ERROR
+        }
+    }
+}
+
+fn export_blocks(chain: &Chain, file: &str) -> Result<u64, String> {
+    let out = File::create(file).map_err(|e| format!("Error creating {}: {}", file, e))?;
+    let mut writer = BufWriter::new(out);
+    let mut count = 0;
+    for index in 1..=chain.height() {
+        let block = chain.get_block(index).ok_or_else(|| format!("Block {} is not found in DB", index))?;
+        let line = serde_json::to_string(&block).map_err(|e| e.to_string())?;
+        writeln!(writer, "{}", line).map_err(|e| format!("Error writing {}: {}", file, e))?;
+        count += 1;
+    }
+    writer.flush().map_err(|e| format!("Error writing {}: {}", file, e))?;
+    Ok(count)
+}
+
+/// Reads blocks from `file` made by `export` and adds them to DB, checking each of them as if it came from network
+pub fn import(chain: &mut Chain, file: &str) -> i32 {
+    let input = match File::open(file) {
+        Ok(input) => input,
+        Err(e) => {
+            println!("Error opening {}: {}", file, e);
+            return EXIT_ERROR;
+        }
+    };
+    match import_blocks(chain, input, file) {
+        Ok(count) => {
+            println!("Imported {} blocks from {}, blockchain height is {}", count, file, chain.height());
+            EXIT_OK
+        }
+        Err(e) => {
+            println!("{}", e);
+            EXIT_REJECTED
+        }
+    }
+}
+
+fn import_blocks(chain: &mut Chain, input: File, file: &str) -> Result<u64, String> {
+    let mut count = 0;
+    for (number, line) in BufReader::new(input).lines().enumerate() {
+        let line = line.map_err(|e| format!("Error reading {}: {}", file, e))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let block: Block = serde_json::from_str(&line).map_err(|e| format!("Error parsing line {} of {}: {}", number + 1, file, e))?;
+        match chain.check_new_block(&block) {
+            BlockQuality::Good => {
+                chain.add_block(block)?;
+                count += 1;
+                if count % 1000 == 0 {
+                    info!("Imported {} blocks", count);
+                }
+            }
+            BlockQuality::Twin => {}
+            quality => return Err(format!("Block {} is {:?}, stopping import", block.index, quality))
+        }
+    }
+    Ok(count)
+}
+
+/// Waits until we get all blocks that our peers have, it is useless to mine on top of old blocks
+fn wait_for_sync(context: &Arc<Mutex<Context>>) {
+    info!("Waiting for blockchain to sync");
+    let start = Instant::now();
+    thread::sleep(SYNC_START_DELAY);
+    loop {
+        {
+            let context = context.lock().unwrap();
+            if context.chain.height() >= context.chain.max_height() {
+                info!("Blockchain is synchronized at height {}", context.chain.height());
+                break;
+            }
+        }
+        if start.elapsed() >= SYNC_TIMEOUT {
+            warn!("Blockchain is not synchronized, mining anyway");
+            break;
+        }
+        thread::sleep(Duration::from_secs(1));
+    }
+}
+
+fn load_record(file: &str) -> Result<MineRequest, String> {
+    let text = fs::read_to_string(file).map_err(|e| format!("Error reading {}: {}", file, e))?;
+    let toml = matches!(Path::new(file).extension().and_then(|e| e.to_str()), Some("toml"));
+    parse_record(&text, toml).map_err(|e| format!("Error parsing {}: {}", file, e))
+}
+
+fn parse_record(text: &str, toml: bool) -> Result<MineRequest, String> {
+    if toml {
+        toml::from_str(text).map_err(|e| e.to_string())
+    } else {
+        serde_json::from_str(text).map_err(|e| e.to_string())
+    }
+}
+
+fn describe(result: &MineResult) -> String {
+    match result {
+        MineResult::Fine => String::from("can be mined"),
+        MineResult::WrongName => String::from("has a wrong name or is not available for mining"),
+        MineResult::WrongData => String::from("has an error in its data"),
+        MineResult::WrongKey => String::from("can't be mined with current key"),
+        MineResult::WrongZone => String::from("is in a zone that doesn't exist"),
+        MineResult::NotOwned => String::from("is already taken, and it is not yours"),
+        MineResult::Cooldown { time } => format!("can't be mined yet, you have cooldown for {} more minutes", time / 60)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, File};
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicBool;
+
+    use chrono::Utc;
+
+    use alfis::{Block, Bytes, Chain, Keystore, Settings, Transaction};
+    use alfis::blockchain::hash_utils::{blakeout_data, hash_difficulty};
+    use alfis::blockchain::transaction::ZoneData;
+    use alfis::commons::{CLASS_DOMAIN, CLASS_ZONE, ConsensusParams};
+    use alfis::keys::generate_key;
+
+    use crate::cli::{export_blocks, import_blocks, parse_record};
+
+    /// Empty regtest chain in its own directory
+    fn regtest_chain(dir: &str) -> Chain {
+        let data_dir = std::env::temp_dir().join(format!("alfis-{}-{}", dir, std::process::id()));
+        let _ = fs::remove_dir_all(&data_dir);
+        fs::create_dir_all(&data_dir).unwrap();
+        let settings = Settings { regtest: true, origin: String::new(), data_dir: data_dir.to_string_lossy().to_string(), ..Settings::default() };
+        Chain::new(&settings).unwrap()
+    }
+
+    fn mine(prev: Option<&Block>, keystore: &Keystore, transaction: Option<Transaction>) -> Block {
+        let prev_hash = prev.map(|block| block.hash.clone()).unwrap_or_default();
+        let mut block = Block::new(transaction, keystore.get_public(), prev_hash, ConsensusParams::regtest().zone_difficulty);
+        block.index = prev.map(|block| block.index + 1).unwrap_or(1);
+        block.timestamp = Utc::now().timestamp();
+        block.hash = loop {
+            let hash = blakeout_data(&block.as_bytes());
+            if hash_difficulty(&hash) >= block.difficulty {
+                break hash;
+            }
+            block.nonce += 1;
+        };
+        block.signature = Bytes::from_bytes(&keystore.sign(&block.as_bytes()));
+        block
+    }
+
+    #[test]
+    fn parse_record_files() {
+        let json = r#"{"name": "test.ygg", "records": [{"type": "A", "domain": "@", "addr": "10.0.0.1", "ttl": 3600}]}"#;
+        let record = parse_record(json, false).unwrap();
+        assert_eq!(record.class, CLASS_DOMAIN);
+        assert_eq!(record.records.len(), 1);
+
+        let toml = "class = \"zone\"\nname = \"test\"\ndifficulty = 24\nyggdrasil = true\n";
+        let record = parse_record(toml, true).unwrap();
+        assert_eq!(record.class, CLASS_ZONE);
+        assert_eq!(record.difficulty, 24);
+        assert!(record.yggdrasil);
+    }
+
+    #[test]
+    fn export_and_import_blocks() {
+        let mut chain = regtest_chain("export");
+        let keystore = generate_key(ConsensusParams::regtest().keystore_difficulty, Arc::new(AtomicBool::new(true))).unwrap();
+        let genesis = mine(None, &keystore, None);
+        chain.add_block(genesis.clone()).unwrap();
+        let data = ZoneData { name: String::from("ygg"), difficulty: 4, yggdrasil: false, owners: Vec::new() };
+        let transaction = Transaction::from_str(String::from("ygg"), CLASS_ZONE.to_owned(), serde_json::to_string(&data).unwrap(), keystore.get_public());
+        chain.add_block(mine(Some(&genesis), &keystore, Some(transaction))).unwrap();
+
+        let file = std::env::temp_dir().join(format!("alfis-blocks-{}.txt", std::process::id())).to_string_lossy().to_string();
+        assert_eq!(export_blocks(&chain, &file), Ok(2));
+
+        let mut imported = regtest_chain("import");
+        assert_eq!(import_blocks(&mut imported, File::open(&file).unwrap(), &file), Ok(2));
+        assert_eq!(imported.height(), chain.height());
+        for index in 1..=chain.height() {
+            assert_eq!(imported.get_block(index).unwrap().hash, chain.get_block(index).unwrap().hash);
+        }
+
+        // Changed block must stop the import
+        let text = fs::read_to_string(&file).unwrap();
+        let mut lines: Vec<String> = text.lines().map(String::from).collect();
+        let mut block: Block = serde_json::from_str(&lines[1]).unwrap();
+        block.nonce += 1;
+        lines[1] = serde_json::to_string(&block).unwrap();
+        fs::write(&file, lines.join("\n")).unwrap();
+        let mut tampered = regtest_chain("tampered");
+        assert!(import_blocks(&mut tampered, File::open(&file).unwrap(), &file).is_err());
+        assert_eq!(tampered.height(), 1);
+        let _ = fs::remove_file(&file);
+    }
+}
@@ -0,0 +1,56 @@
+use crate::commons::*;
+
+/// Parameters of consensus that must be the same for all nodes of one network
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ConsensusParams {
+    pub zone_difficulty: u32,
+    pub zone_min_difficulty: u32,
+    pub locker_difficulty: u32,
+    pub keystore_difficulty: u32,
+    pub locker_block_start: u64,
+    pub locker_block_lockers: u64,
+    pub locker_block_signs: u64,
+    pub locker_block_time: i64,
+    pub locker_block_interval: u64,
+    pub new_domains_interval: i64,
+}
+
+impl ConsensusParams {
+    /// Parameters of the main network
+    pub const fn main() -> Self {
+        ConsensusParams {
+            zone_difficulty: ZONE_DIFFICULTY,
+            zone_min_difficulty: ZONE_MIN_DIFFICULTY,
+            locker_difficulty: LOCKER_DIFFICULTY,
+            keystore_difficulty: KEYSTORE_DIFFICULTY,
+            locker_block_start: LOCKER_BLOCK_START,
+            locker_block_lockers: LOCKER_BLOCK_LOCKERS,
+            locker_block_signs: LOCKER_BLOCK_SIGNS,
+            locker_block_time: LOCKER_BLOCK_TIME,
+            locker_block_interval: LOCKER_BLOCK_INTERVAL,
+            new_domains_interval: NEW_DOMAINS_INTERVAL,
+        }
+    }
+
+    /// Parameters for local testing, everything is mined in seconds
+    pub const fn regtest() -> Self {
+        ConsensusParams {
+            zone_difficulty: 8,
+            zone_min_difficulty: 4,
+            locker_difficulty: 4,
+            keystore_difficulty: 4,
+            locker_block_start: 5,
+            locker_block_lockers: 3,
+            locker_block_signs: 2,
+            locker_block_time: 30,
+            locker_block_interval: 10,
+            new_domains_interval: 10,
+        }
+    }
+}
+
+impl Default for ConsensusParams {
+    fn default() -> Self {
+        ConsensusParams::main()
+    }
+}
@@ -1,5 +1,19 @@
-pub const DB_VERSION: u32 = 0;
+/// Version of blockchain DB schema, must be equal to the number of migrations
+pub const DB_VERSION: u32 = 3;
 pub const CHAIN_VERSION: u32 = 0;
+/// Version of network protocol, 1 - batch sync with GetBlocks/Blocks, 2 - hostnames in peer exchange
+pub const PROTOCOL_VERSION: u32 = 2;
+/// First protocol version with GetBlocks/Blocks
+pub const PROTOCOL_BATCH_SYNC: u32 = 1;
+/// First protocol version that understands hostnames (like .onion) in peer exchange
+pub const PROTOCOL_HOSTNAMES: u32 = 2;
+/// Version of binary encoding of network messages, 0 - JSON only, 1 - CBOR
+pub const ENCODING_VERSION: u32 = 1;
+
+/// Origin of the main blockchain, built-in checkpoints are only for it
+pub const MAIN_ORIGIN: &str = "0AE588D62D710422A7972EA1E8A659CC8E93DB59489ACE32C499CD279B000000";
+/// Built-in checkpoints of the main blockchain, height and hash of block
+pub const CHECKPOINTS: &[(u64, &str)] = &[(1, MAIN_ORIGIN)];
 
 pub const ZONE_DIFFICULTY: u32 = 28;
 pub const ZONE_MIN_DIFFICULTY: u32 = 22;
@@ -14,6 +28,8 @@ pub const LOCKER_BLOCK_INTERVAL: u64 = 50;
 
 pub const NEW_DOMAINS_INTERVAL: i64 = 86400; // One day in seconds
 pub const DOMAIN_LIFETIME: i64 = 86400 * 365; // One year
+pub const DOMAIN_GRACE_PERIOD: i64 = 86400 * 30; // Only previous owner can renew expired domain for a month
+pub const DOMAIN_EXPIRY_WARNING: i64 = 86400 * 30; // Start warning about our domains a month before expiry
 
 pub const ZONE_MAX_LENGTH: usize = 10;
 pub const MAX_RECONNECTS: u32 = 5;
@@ -1,13 +1,32 @@
+use std::fs::{File, OpenOptions};
 use std::num;
+use std::path::Path;
+use fs2::FileExt;
 use rand::Rng;
 
 pub mod constants;
+pub mod consensus;
 pub use constants::*;
+pub use consensus::ConsensusParams;
 use std::net::IpAddr;
 
 #[cfg(not(target_os = "macos"))]
 use thread_priority::*;
 
+/// Creates and locks the file, so that other processes can't use the same data.
+/// The lock is held until returned file is closed.
+pub fn lock_file(path: &Path) -> Result<File, String> {
+    let file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(false)
+        .open(path)
+        .map_err(|e| format!("Unable to open lock file {}: {}", path.display(), e))?;
+    file.try_lock_exclusive()
+        .map_err(|_| format!("Lock file {} is held by another process, is ALFIS already running with this data?", path.display()))?;
+    Ok(file)
+}
+
 /// Convert bytes array to HEX format
 pub fn to_hex(buf: &[u8]) -> String {
     let mut result = String::new();
@@ -115,7 +134,7 @@ pub fn setup_miner_thread(cpu: u32) {
 
 #[cfg(test)]
 mod test {
-    use crate::{check_domain, is_yggdrasil};
+    use crate::{check_domain, is_yggdrasil, lock_file};
     use std::net::IpAddr;
 
     #[test]
@@ -148,4 +167,14 @@ mod test {
         let addr: IpAddr = "2201::1".parse().unwrap();
         assert!(!is_yggdrasil(&addr));
     }
+
+    #[test]
+    fn test_lock_file() {
+        let path = std::env::temp_dir().join(format!("alfis-{}.lock", std::process::id()));
+        let lock = lock_file(&path).unwrap();
+        assert!(lock_file(&path).is_err());
+        drop(lock);
+        assert!(lock_file(&path).is_ok());
+        let _ = std::fs::remove_file(&path);
+    }
 }
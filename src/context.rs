@@ -1,5 +1,6 @@
 use crate::{Chain, Bus, Keystore, Settings, ExternalZones};
 use crate::event::Event;
+use crate::p2p::PeerInfo;
 #[allow(unused_imports)]
 use log::{trace, debug, info, warn, error};
 
@@ -10,18 +11,21 @@ pub struct Context {
     pub chain: Chain,
     pub x_zones: ExternalZones,
     pub bus: Bus<Event>,
+    /// Snapshot of connected peers, updated by network thread
+    pub peers: Vec<PeerInfo>,
 }
 
 impl Context {
     /// Creating an essential context to work with
     pub fn new(app_version: String, settings: Settings, keystore: Option<Keystore>, chain: Chain) -> Context {
-        Context { app_version, settings, keystore, chain, x_zones: ExternalZones::new(), bus: Bus::new() }
+        Context { app_version, settings, keystore, chain, x_zones: ExternalZones::new(), bus: Bus::new(), peers: Vec::new() }
     }
 
     /// Load keystore and return Context
     pub fn load_keystore<S: Into<String>>(mut self, name: S, password: S) -> Context {
         let filename = &name.into();
-        match Keystore::from_file(filename, &password.into()) {
+        let strength = self.chain.get_consensus().keystore_difficulty;
+        match Keystore::from_file(filename, &password.into(), strength) {
             None => {
                 warn!("Error loading keystore '{}'!", filename);
             },
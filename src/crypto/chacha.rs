@@ -27,6 +27,12 @@ impl Chacha {
         let nonce = Nonce::from_slice(nonce);
         Vec::from(self.cipher.decrypt(nonce, data.as_ref()).expect(FAILURE))
     }
+
+    /// Same as `decrypt`, but returns None if data is broken or the key is wrong
+    pub fn try_decrypt(&self, data: &[u8], nonce: &[u8]) -> Option<Vec<u8>> {
+        let nonce = Nonce::from_slice(nonce);
+        self.cipher.decrypt(nonce, data.as_ref()).ok()
+    }
 }
 
 impl Debug for Chacha {
@@ -1,3 +1,5 @@
 mod chacha;
+mod session;
 
-pub use chacha::Chacha;
\ No newline at end of file
+pub use chacha::Chacha;
+pub use session::{KeyExchange, Session};
@@ -0,0 +1,119 @@
+use std::fmt;
+use std::fmt::{Debug, Formatter};
+
+use rand_old::rngs::OsRng;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::blockchain::hash_utils::hash_sha256;
+use crate::crypto::Chacha;
+
+/// Our side of x25519 key exchange, one for every connection
+pub struct KeyExchange {
+    secret: StaticSecret,
+    public: PublicKey
+}
+
+impl KeyExchange {
+    pub fn new() -> Self {
+        let secret = StaticSecret::new(OsRng);
+        let public = PublicKey::from(&secret);
+        KeyExchange { secret, public }
+    }
+
+    pub fn get_public(&self) -> [u8; 32] {
+        self.public.to_bytes()
+    }
+
+    /// Makes session with the other side using its public key.
+    /// The `initiator` is the side that opened connection, sessions of both sides must be made with different values.
+    pub fn make_session(&self, their_public: &[u8], initiator: bool) -> Option<Session> {
+        if their_public.len() != 32 {
+            return None;
+        }
+        let mut bytes = [0u8; 32];
+        bytes.copy_from_slice(their_public);
+        let their_public = PublicKey::from(bytes);
+        let shared = self.secret.diffie_hellman(&their_public);
+        if !shared.was_contributory() {
+            return None;
+        }
+        // Both public keys go to the hash in the same order on both sides
+        let (first, second) = match initiator {
+            true => (self.public.as_bytes(), their_public.as_bytes()),
+            false => (their_public.as_bytes(), self.public.as_bytes())
+        };
+        let mut buf = Vec::with_capacity(96);
+        buf.extend_from_slice(shared.as_bytes());
+        buf.extend_from_slice(first);
+        buf.extend_from_slice(second);
+        let key = hash_sha256(&buf);
+        Some(Session { cipher: Chacha::new(&key), initiator, sent: 0, received: 0 })
+    }
+}
+
+impl Default for KeyExchange {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Debug for KeyExchange {
+    fn fmt(&self, fmt: &mut Formatter<'_>) -> fmt::Result {
+        fmt.write_str("KeyExchange")
+    }
+}
+
+/// Encrypts and decrypts frames of one connection.
+/// Every direction has its own counter for nonces, so frames can't be replayed or reordered.
+#[derive(Debug)]
+pub struct Session {
+    cipher: Chacha,
+    initiator: bool,
+    sent: u64,
+    received: u64
+}
+
+impl Session {
+    pub fn encrypt(&mut self, data: &[u8]) -> Vec<u8> {
+        let nonce = make_nonce(self.initiator, self.sent);
+        self.sent += 1;
+        self.cipher.encrypt(data, &nonce)
+    }
+
+    pub fn decrypt(&mut self, data: &[u8]) -> Option<Vec<u8>> {
+        let nonce = make_nonce(!self.initiator, self.received);
+        self.received += 1;
+        self.cipher.try_decrypt(data, &nonce)
+    }
+}
+
+fn make_nonce(initiator: bool, counter: u64) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[0] = if initiator { 1 } else { 2 };
+    nonce[4..].copy_from_slice(&counter.to_be_bytes());
+    nonce
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::crypto::KeyExchange;
+
+    #[test]
+    fn test_session() {
+        let client = KeyExchange::new();
+        let server = KeyExchange::new();
+        let mut client_session = client.make_session(&server.get_public(), true).unwrap();
+        let mut server_session = server.make_session(&client.get_public(), false).unwrap();
+
+        let frame = client_session.encrypt(b"Hello");
+        assert_eq!(server_session.decrypt(&frame).unwrap(), b"Hello");
+        let frame = server_session.encrypt(b"World");
+        assert_eq!(client_session.decrypt(&frame).unwrap(), b"World");
+        // Replayed frame has wrong nonce
+        assert!(client_session.decrypt(&frame).is_none());
+
+        let stranger = KeyExchange::new();
+        let mut stranger_session = stranger.make_session(&server.get_public(), true).unwrap();
+        assert!(stranger_session.decrypt(&server_session.encrypt(b"Secret")).is_none());
+    }
+}
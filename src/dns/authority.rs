@@ -72,11 +72,11 @@ impl<'a> Zones {
         }
     }
 
-    pub fn load(&mut self) -> Result<()> {
-        let zones_dir = match Path::new("zones").read_dir() {
+    pub fn load(&mut self, dir: &Path) -> Result<()> {
+        let zones_dir = match dir.read_dir() {
             Ok(result) => { result }
             Err(_) => {
-                debug!("Authority dir (zones) not found, skipping.");
+                debug!("Authority dir ({}) not found, skipping.", dir.display());
                 return Ok(());
             }
         };
@@ -119,10 +119,9 @@ impl<'a> Zones {
         Ok(())
     }
 
-    pub fn save(&mut self) -> Result<()> {
-        let zones_dir = Path::new("zones");
+    pub fn save(&mut self, dir: &Path) -> Result<()> {
         for zone in self.zones.values() {
-            let filename = zones_dir.join(Path::new(&zone.domain));
+            let filename = dir.join(Path::new(&zone.domain));
             let mut zone_file = match File::create(&filename) {
                 Ok(x) => x,
                 Err(_) => {
@@ -181,12 +180,12 @@ impl Authority {
         }
     }
 
-    pub fn load(&self) -> Result<()> {
+    pub fn load(&self, dir: &Path) -> Result<()> {
         let mut zones = self
             .zones
             .write()
             .map_err(|_| AuthorityError::PoisonedLock)?;
-        zones.load()?;
+        zones.load(dir)?;
 
         Ok(())
     }
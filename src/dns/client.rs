@@ -23,6 +23,7 @@ pub enum ClientError {
     PoisonedLock,
     LookupFailed,
     TimeOut,
+    WrongServer,
 }
 
 type Result<T> = std::result::Result<T, ClientError>;
@@ -1,6 +1,7 @@
 //! The `ServerContext in this thread holds the common state across the server
 
 use std::sync::atomic::{AtomicUsize, Ordering};
+use std::path::PathBuf;
 use std::sync::Arc;
 
 use derive_more::{Display, Error, From};
@@ -10,6 +11,9 @@ use crate::dns::cache::SynchronizedCache;
 use crate::dns::client::{DnsClient, DnsNetworkClient};
 use crate::dns::resolve::{DnsResolver, ForwardingDnsResolver, RecursiveDnsResolver};
 use crate::dns::filter::DnsFilter;
+use crate::dns::tls::make_client_config;
+use crate::dns::tls_client::{DnsHttpsClient, DnsTlsClient, HTTPS_SCHEME, TLS_SCHEME};
+use crate::dns::upstream::{UpstreamHealth, UpstreamStats};
 
 #[derive(Debug, Display, From, Error)]
 pub enum ContextError {
@@ -23,6 +27,9 @@ type Result<T> = std::result::Result<T, ContextError>;
 pub struct ServerStatistics {
     pub tcp_query_count: AtomicUsize,
     pub udp_query_count: AtomicUsize,
+    pub doh_query_count: AtomicUsize,
+    pub tls_query_count: AtomicUsize,
+    pub upstreams: UpstreamHealth,
 }
 
 impl ServerStatistics {
@@ -33,11 +40,24 @@ impl ServerStatistics {
     pub fn get_udp_query_count(&self) -> usize {
         self.udp_query_count.load(Ordering::Acquire)
     }
+
+    pub fn get_doh_query_count(&self) -> usize {
+        self.doh_query_count.load(Ordering::Acquire)
+    }
+
+    pub fn get_tls_query_count(&self) -> usize {
+        self.tls_query_count.load(Ordering::Acquire)
+    }
+
+    pub fn get_upstreams(&self) -> Vec<(String, UpstreamStats)> {
+        self.upstreams.get_stats()
+    }
 }
 
 pub enum ResolveStrategy {
     Recursive,
-    Forward { upstreams: Vec<String> },
+    /// Forward to upstreams, if `race` is set then two of them are queried at once
+    Forward { upstreams: Vec<String>, race: bool },
 }
 
 pub struct ServerContext {
@@ -45,15 +65,19 @@ pub struct ServerContext {
     pub cache: SynchronizedCache,
     pub filters: Vec<Box<dyn DnsFilter + Sync + Send>>,
     pub client: Box<dyn DnsClient + Sync + Send>,
+    /// Client for `tls://` upstreams
+    pub tls_client: Box<dyn DnsClient + Sync + Send>,
+    /// Client for `https://` upstreams
+    pub https_client: Box<dyn DnsClient + Sync + Send>,
     pub dns_listen: String,
-    pub api_port: u16,
     pub resolve_strategy: ResolveStrategy,
+    /// Domains with their own strategy, it is used for them and their subdomains
+    pub rules: Vec<(String, ResolveStrategy)>,
     pub allow_recursive: bool,
     pub enable_udp: bool,
     pub enable_tcp: bool,
-    pub enable_api: bool,
     pub statistics: ServerStatistics,
-    pub zones_dir: &'static str
+    pub zones_dir: PathBuf
 }
 
 impl Default for ServerContext {
@@ -69,18 +93,22 @@ impl ServerContext {
             cache: SynchronizedCache::new(),
             filters: Vec::new(),
             client: Box::new(DnsNetworkClient::new(10000 + (rand::random::<u16>() % 20000))),
+            tls_client: Box::new(DnsTlsClient::new(make_client_config())),
+            https_client: Box::new(DnsHttpsClient::new(make_client_config())),
             dns_listen: String::from("0.0.0.0:53"),
-            api_port: 5380,
             resolve_strategy: ResolveStrategy::Recursive,
+            rules: Vec::new(),
             allow_recursive: true,
             enable_udp: true,
             enable_tcp: true,
-            enable_api: false,
             statistics: ServerStatistics {
                 tcp_query_count: AtomicUsize::new(0),
                 udp_query_count: AtomicUsize::new(0),
+                doh_query_count: AtomicUsize::new(0),
+                tls_query_count: AtomicUsize::new(0),
+                upstreams: UpstreamHealth::new(),
             },
-            zones_dir: "zones",
+            zones_dir: PathBuf::from("zones"),
         }
     }
 
@@ -89,16 +117,51 @@ impl ServerContext {
         self.client.run()?;
 
         // Load authority data
-        self.authority.load()?;
+        self.authority.load(&self.zones_dir)?;
 
         Ok(())
     }
 
+    /// Returns client for this upstream, encrypted ones are chosen by scheme
+    pub fn get_client(&self, server: &str) -> &(dyn DnsClient + Sync + Send) {
+        if server.starts_with(TLS_SCHEME) {
+            self.tls_client.as_ref()
+        } else if server.starts_with(HTTPS_SCHEME) {
+            self.https_client.as_ref()
+        } else {
+            self.client.as_ref()
+        }
+    }
+
+    pub fn add_rule(&mut self, domain: &str, strategy: ResolveStrategy) {
+        let domain = domain.trim_matches('.').to_lowercase();
+        self.rules.push((domain, strategy));
+    }
+
+    /// Finds strategy of the longest rule matching this name
+    pub fn find_rule(&self, qname: &str) -> Option<&ResolveStrategy> {
+        let qname = qname.trim_end_matches('.').to_lowercase();
+        self.rules
+            .iter()
+            .filter(|(domain, _)| qname == *domain || qname.ends_with(&format!(".{}", domain)))
+            .max_by_key(|(domain, _)| domain.len())
+            .map(|(_, strategy)| strategy)
+    }
+
     pub fn create_resolver(&self, ptr: Arc<ServerContext>) -> Box<dyn DnsResolver> {
-        match self.resolve_strategy {
+        self.create_resolver_with(ptr, &self.resolve_strategy)
+    }
+
+    /// Creates resolver by the rule for this name, if there is one
+    pub fn create_rule_resolver(&self, ptr: Arc<ServerContext>, qname: &str) -> Option<Box<dyn DnsResolver>> {
+        self.find_rule(qname).map(|strategy| self.create_resolver_with(ptr, strategy))
+    }
+
+    fn create_resolver_with(&self, ptr: Arc<ServerContext>, strategy: &ResolveStrategy) -> Box<dyn DnsResolver> {
+        match *strategy {
             ResolveStrategy::Recursive => Box::new(RecursiveDnsResolver::new(ptr)),
-            ResolveStrategy::Forward { ref upstreams } => {
-                Box::new(ForwardingDnsResolver::new(ptr, upstreams.clone()))
+            ResolveStrategy::Forward { ref upstreams, race } => {
+                Box::new(ForwardingDnsResolver::new(ptr, upstreams.clone(), race))
             }
         }
     }
@@ -123,18 +186,22 @@ pub mod tests {
             cache: SynchronizedCache::new(),
             filters: Vec::new(),
             client: Box::new(DnsStubClient::new(callback)),
+            tls_client: Box::new(DnsTlsClient::new(make_client_config())),
+            https_client: Box::new(DnsHttpsClient::new(make_client_config())),
             dns_listen: String::from("0.0.0.0:53"),
-            api_port: 5380,
             resolve_strategy: ResolveStrategy::Recursive,
+            rules: Vec::new(),
             allow_recursive: true,
             enable_udp: true,
             enable_tcp: true,
-            enable_api: false,
             statistics: ServerStatistics {
                 tcp_query_count: AtomicUsize::new(0),
                 udp_query_count: AtomicUsize::new(0),
+                doh_query_count: AtomicUsize::new(0),
+                tls_query_count: AtomicUsize::new(0),
+                upstreams: UpstreamHealth::new(),
             },
-            zones_dir: "zones",
+            zones_dir: PathBuf::from("zones"),
         })
     }
 }
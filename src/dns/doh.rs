@@ -0,0 +1,311 @@
+//! DNS-over-HTTPS server, as described in RFC 8484
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::{channel, Sender};
+use std::sync::Arc;
+use std::thread::Builder;
+use std::time::Duration;
+
+use log::{debug, warn};
+use rand::random;
+use rustls::{ServerConfig, ServerSession, Session, StreamOwned};
+
+use crate::dns::buffer::{PacketBuffer, VectorPacketBuffer};
+use crate::dns::context::ServerContext;
+use crate::dns::protocol::DnsPacket;
+use crate::dns::server::{execute_query, DnsServer, ServerError};
+
+const DOH_PATH: &str = "/dns-query";
+const CONTENT_TYPE: &str = "application/dns-message";
+/// Max size of request line with all headers
+const MAX_HEAD_SIZE: u64 = 8192;
+/// Max size of DNS packet in request body
+const MAX_BODY_SIZE: usize = 65535;
+/// How many requests we serve in one connection before closing it
+const MAX_REQUESTS: usize = 100;
+/// Idle connections are closed after this time
+const IO_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Parts of HTTP request that we need to get a DNS query from it
+#[derive(Debug)]
+struct HttpRequest {
+    method: String,
+    target: String,
+    content_type: String,
+    close: bool,
+    body: Vec<u8>,
+}
+
+/// DNS-over-HTTPS server
+///
+/// Accepts GET and POST requests with `application/dns-message` at `/dns-query`.
+/// Without TLS config it serves plain HTTP, to be put behind a reverse proxy.
+pub struct DnsHttpsServer {
+    context: Arc<ServerContext>,
+    listen: String,
+    tls: Option<Arc<ServerConfig>>,
+    senders: Vec<Sender<TcpStream>>,
+    thread_count: usize,
+}
+
+impl DnsHttpsServer {
+    pub fn new(context: Arc<ServerContext>, listen: String, tls: Option<ServerConfig>, thread_count: usize) -> DnsHttpsServer {
+        DnsHttpsServer { context, listen, tls: tls.map(Arc::new), senders: Vec::new(), thread_count }
+    }
+}
+
+impl DnsServer for DnsHttpsServer {
+    fn run_server(mut self) -> Result<(), ServerError> {
+        let socket = TcpListener::bind(self.listen.as_str())?;
+
+        for thread_id in 0..self.thread_count {
+            let (tx, rx) = channel::<TcpStream>();
+            self.senders.push(tx);
+
+            let context = Arc::clone(&self.context);
+            let tls = self.tls.clone();
+
+            let name = "DnsHttpsServer-request-".to_string() + &thread_id.to_string();
+            let _ = Builder::new().name(name).spawn(move || {
+                while let Ok(stream) = rx.recv() {
+                    let _ = stream.set_read_timeout(Some(IO_TIMEOUT));
+                    let _ = stream.set_write_timeout(Some(IO_TIMEOUT));
+                    match &tls {
+                        Some(config) => {
+                            let mut stream = StreamOwned::new(ServerSession::new(config), stream);
+                            serve_connection(&context, &mut stream);
+                            stream.sess.send_close_notify();
+                            let _ = stream.flush();
+                        }
+                        None => {
+                            let mut stream = stream;
+                            serve_connection(&context, &mut stream);
+                        }
+                    }
+                }
+            })?;
+        }
+
+        let _ = Builder::new()
+            .name("DnsHttpsServer-incoming".into())
+            .spawn(move || {
+                for wrap_stream in socket.incoming() {
+                    let stream = match wrap_stream {
+                        Ok(stream) => stream,
+                        Err(err) => {
+                            warn!("Failed to accept DoH connection: {:?}", err);
+                            continue;
+                        }
+                    };
+
+                    // Hand it off to a worker thread
+                    let thread_no = random::<usize>() % self.thread_count;
+                    if let Err(e) = self.senders[thread_no].send(stream) {
+                        warn!("Failed to send DoH request for processing on thread {}: {}", thread_no, e);
+                    }
+                }
+            })?;
+
+        Ok(())
+    }
+}
+
+/// Serves requests from one client until it closes connection, or something goes wrong
+fn serve_connection<S: Read + Write>(context: &Arc<ServerContext>, stream: &mut S) {
+    let mut reader = BufReader::new(stream);
+    for _ in 0..MAX_REQUESTS {
+        let request = match read_request(&mut reader) {
+            Ok(Some(request)) => request,
+            Ok(None) => return,
+            Err(code) => {
+                let _ = write_response(reader.get_mut(), code, &[], None, true);
+                return;
+            }
+        };
+        let _ = context.statistics.doh_query_count.fetch_add(1, Ordering::Release);
+
+        let result = get_query(&request).and_then(|query| {
+            let mut buffer = VectorPacketBuffer::new();
+            buffer.buffer = query;
+            DnsPacket::from_buffer(&mut buffer).map_err(|_| 400)
+        });
+        let written = match result {
+            Ok(query) => {
+                let mut packet = execute_query(Arc::clone(context), &query);
+                let mut buffer = VectorPacketBuffer::new();
+                match packet.write(&mut buffer, MAX_BODY_SIZE) {
+                    Ok(_) => {
+                        let max_age = packet.answers.iter().map(|answer| answer.get_ttl()).min();
+                        let len = buffer.pos();
+                        write_response(reader.get_mut(), 200, &buffer.buffer[..len], max_age, request.close)
+                    }
+                    Err(_) => write_response(reader.get_mut(), 500, &[], None, true)
+                }
+            }
+            Err(code) => write_response(reader.get_mut(), code, &[], None, request.close)
+        };
+        if let Err(e) = written {
+            debug!("Failed to write DoH response: {}", e);
+            return;
+        }
+        if request.close {
+            return;
+        }
+    }
+}
+
+/// Reads one HTTP/1.x request, returns None if connection was closed before it.
+/// Errors are HTTP status codes to send back.
+fn read_request<R: BufRead>(reader: &mut R) -> Result<Option<HttpRequest>, u16> {
+    let mut head_size = 0;
+    let mut read_line = |reader: &mut R| -> Result<String, u16> {
+        let mut line = String::new();
+        let size = reader.by_ref().take(MAX_HEAD_SIZE - head_size).read_line(&mut line).map_err(|_| 400u16)?;
+        head_size += size as u64;
+        if size > 0 && !line.ends_with('\n') {
+            return Err(431);
+        }
+        Ok(line.trim_end().to_owned())
+    };
+
+    let line = read_line(reader)?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    let parts: Vec<&str> = line.split_whitespace().collect();
+    let (method, target, version) = match parts.as_slice() {
+        [method, target, version] if version.starts_with("HTTP/1.") => (method.to_string(), target.to_string(), version.to_string()),
+        _ => return Err(400)
+    };
+
+    let mut content_type = String::new();
+    let mut content_length = 0usize;
+    let mut close = version == "HTTP/1.0";
+    loop {
+        let line = read_line(reader)?;
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = match line.find(':') {
+            Some(pos) => (line[..pos].trim().to_lowercase(), line[pos + 1..].trim()),
+            None => return Err(400)
+        };
+        match name.as_str() {
+            "content-type" => content_type = value.to_lowercase(),
+            "content-length" => content_length = value.parse().map_err(|_| 400u16)?,
+            "connection" => {
+                let value = value.to_lowercase();
+                if value == "close" {
+                    close = true;
+                } else if value == "keep-alive" {
+                    close = false;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    if content_length > MAX_BODY_SIZE {
+        return Err(413);
+    }
+    let mut body = vec![0u8; content_length];
+    reader.read_exact(&mut body).map_err(|_| 400u16)?;
+    Ok(Some(HttpRequest { method, target, content_type, close, body }))
+}
+
+/// Gets DNS query from request body or from `dns` parameter of URL
+fn get_query(request: &HttpRequest) -> Result<Vec<u8>, u16> {
+    let (path, params) = match request.target.find('?') {
+        Some(pos) => (&request.target[..pos], &request.target[pos + 1..]),
+        None => (request.target.as_str(), "")
+    };
+    if path != DOH_PATH {
+        return Err(404);
+    }
+    match request.method.as_str() {
+        "GET" => {
+            let value = params
+                .split('&')
+                .find_map(|param| param.strip_prefix("dns="))
+                .ok_or(400u16)?;
+            base64::decode_config(value.trim_end_matches('='), base64::URL_SAFE_NO_PAD).map_err(|_| 400)
+        }
+        "POST" => {
+            let content_type = request.content_type.split(';').next().unwrap_or_default().trim();
+            if content_type != CONTENT_TYPE {
+                return Err(415);
+            }
+            Ok(request.body.clone())
+        }
+        _ => Err(405)
+    }
+}
+
+fn write_response<W: Write>(writer: &mut W, code: u16, body: &[u8], max_age: Option<u32>, close: bool) -> std::io::Result<()> {
+    let reason = match code {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        415 => "Unsupported Media Type",
+        431 => "Request Header Fields Too Large",
+        _ => "Internal Server Error"
+    };
+    let mut head = format!("HTTP/1.1 {} {}\r\nContent-Length: {}\r\n", code, reason, body.len());
+    if !body.is_empty() {
+        head.push_str(&format!("Content-Type: {}\r\n", CONTENT_TYPE));
+    }
+    if let Some(max_age) = max_age {
+        head.push_str(&format!("Cache-Control: max-age={}\r\n", max_age));
+    }
+    if close {
+        head.push_str("Connection: close\r\n");
+    }
+    head.push_str("\r\n");
+    writer.write_all(head.as_bytes())?;
+    writer.write_all(body)?;
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use crate::dns::buffer::{PacketBuffer, VectorPacketBuffer};
+    use crate::dns::protocol::{DnsPacket, DnsQuestion, QueryType};
+
+    use super::{get_query, read_request};
+
+    #[test]
+    fn parse_doh_requests() {
+        let mut packet = DnsPacket::new();
+        packet.questions.push(DnsQuestion::new(String::from("example.ygg"), QueryType::A));
+        let mut buffer = VectorPacketBuffer::new();
+        packet.write(&mut buffer, 512).unwrap();
+        let query = buffer.buffer[..buffer.pos()].to_vec();
+
+        let encoded = base64::encode_config(&query, base64::URL_SAFE_NO_PAD);
+        let get = format!("GET /dns-query?dns={} HTTP/1.1\r\nHost: localhost\r\nAccept: application/dns-message\r\n\r\n", encoded);
+        let mut reader = Cursor::new(get.into_bytes());
+        let request = read_request(&mut reader).unwrap().unwrap();
+        assert!(!request.close);
+        assert_eq!(get_query(&request).unwrap(), query);
+        assert!(read_request(&mut reader).unwrap().is_none());
+
+        let mut post = format!("POST /dns-query HTTP/1.1\r\nContent-Type: application/dns-message\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", query.len()).into_bytes();
+        post.extend_from_slice(&query);
+        let request = read_request(&mut Cursor::new(post)).unwrap().unwrap();
+        assert!(request.close);
+        assert_eq!(get_query(&request).unwrap(), query);
+
+        let post = "POST /dns-query HTTP/1.1\r\nContent-Type: text/plain\r\nContent-Length: 0\r\n\r\n";
+        let request = read_request(&mut Cursor::new(post.as_bytes())).unwrap().unwrap();
+        assert_eq!(get_query(&request), Err(415));
+        let get = "GET /query?dns=AAAA HTTP/1.1\r\n\r\n";
+        let request = read_request(&mut Cursor::new(get.as_bytes())).unwrap().unwrap();
+        assert_eq!(get_query(&request), Err(404));
+    }
+}
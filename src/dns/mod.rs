@@ -19,10 +19,14 @@ pub mod buffer;
 pub mod cache;
 pub mod client;
 pub mod context;
+pub mod doh;
 pub mod protocol;
 pub mod resolve;
 pub mod server;
 pub mod filter;
 pub mod hosts;
+pub mod tls;
+pub mod tls_client;
+pub mod upstream;
 
 mod netutil;
\ No newline at end of file
@@ -1,14 +1,18 @@
 //! resolver implementations implementing different strategies for answering
 //! incoming queries
 
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::channel;
 use std::sync::Arc;
+use std::thread::Builder;
+use std::time::{Duration, Instant};
 use std::vec::Vec;
 
 use derive_more::{Display, Error, From};
+use log::debug;
 
 use crate::dns::context::ServerContext;
 use crate::dns::protocol::{DnsPacket, QueryType, ResultCode};
-use rand::seq::IteratorRandom;
 
 #[derive(Debug, Display, From, Error)]
 pub enum ResolveError {
@@ -58,24 +62,83 @@ pub trait DnsResolver {
             }
         }
 
+        if let Some(mut resolver) = context.create_rule_resolver(Arc::clone(&context), qname) {
+            return resolver.perform(qname, qtype);
+        }
+
         self.perform(qname, qtype)
     }
 
     fn perform(&mut self, qname: &str, qtype: QueryType) -> Result<DnsPacket>;
 }
 
+/// How many upstreams we try for one query before giving up
+const MAX_ATTEMPTS: usize = 3;
+/// How many threads can query upstreams in race mode at once, over this limit we query upstreams one by one
+const MAX_RACE_THREADS: usize = 64;
+/// How long we wait for answers in race mode, clients of upstreams have shorter timeouts
+const RACE_TIMEOUT: Duration = Duration::from_secs(15);
+static RACE_THREADS: AtomicUsize = AtomicUsize::new(0);
+
 /// A Forwarding DNS Resolver
 ///
-/// This resolver uses an external DNS server to service a query
+/// This resolver uses an external DNS server to service a query.
+/// Upstreams are tried by their health, and the next one is used after error or SERVFAIL.
 pub struct ForwardingDnsResolver {
     context: Arc<ServerContext>,
     upstreams: Vec<String>,
+    race: bool,
 }
 
 impl ForwardingDnsResolver {
-    pub fn new(context: Arc<ServerContext>, upstreams: Vec<String>) -> ForwardingDnsResolver {
-        ForwardingDnsResolver { context, upstreams }
+    pub fn new(context: Arc<ServerContext>, upstreams: Vec<String>, race: bool) -> ForwardingDnsResolver {
+        ForwardingDnsResolver { context, upstreams, race }
+    }
+
+    /// Queries two upstreams in parallel, returns the first good answer, or the last bad one
+    fn race(&self, qname: &str, qtype: QueryType, upstreams: &[String]) -> Result<DnsPacket> {
+        let (tx, rx) = channel();
+        for upstream in upstreams {
+            let tx = tx.clone();
+            let context = Arc::clone(&self.context);
+            let (qname, upstream) = (qname.to_owned(), upstream.clone());
+            RACE_THREADS.fetch_add(1, Ordering::SeqCst);
+            let spawned = Builder::new().name(String::from("DnsResolver-race")).spawn(move || {
+                let _ = tx.send(query_upstream(&context, &upstream, &qname, qtype));
+                RACE_THREADS.fetch_sub(1, Ordering::SeqCst);
+            });
+            if let Err(e) = spawned {
+                RACE_THREADS.fetch_sub(1, Ordering::SeqCst);
+                return Err(e.into());
+            }
+        }
+        drop(tx);
+
+        let mut last = Err(ResolveError::NoServerFound);
+        let deadline = Instant::now() + RACE_TIMEOUT;
+        while let Ok(result) = rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            if is_good(&result) {
+                return result;
+            }
+            last = result;
+        }
+        last
+    }
+}
+
+/// Sends query to upstream and updates its health
+fn query_upstream(context: &ServerContext, upstream: &str, qname: &str, qtype: QueryType) -> Result<DnsPacket> {
+    let start = Instant::now();
+    let result = context.get_client(upstream).send_query(qname, qtype, upstream, true);
+    match &result {
+        Ok(packet) if packet.header.rescode != ResultCode::SERVFAIL => context.statistics.upstreams.record_success(upstream, start.elapsed()),
+        _ => context.statistics.upstreams.record_failure(upstream)
     }
+    Ok(result?)
+}
+
+fn is_good(result: &Result<DnsPacket>) -> bool {
+    matches!(result, Ok(packet) if packet.header.rescode != ResultCode::SERVFAIL)
 }
 
 impl DnsResolver for ForwardingDnsResolver {
@@ -84,15 +147,27 @@ impl DnsResolver for ForwardingDnsResolver {
     }
 
     fn perform(&mut self, qname: &str, qtype: QueryType) -> Result<DnsPacket> {
-        let mut random = rand::thread_rng();
-        let upstream = self.upstreams.iter().choose(&mut random).unwrap();
-        let result = match self.context.cache.lookup(qname, qtype) {
-            None => {
-                self.context.client.send_query(qname, qtype, upstream, true)?
+        if let Some(packet) = self.context.cache.lookup(qname, qtype) {
+            return Ok(packet);
+        }
+
+        let mut upstreams = self.context.statistics.upstreams.order(&self.upstreams);
+        upstreams.truncate(MAX_ATTEMPTS);
+        let mut result = Err(ResolveError::NoServerFound);
+        let mut tried = 0;
+        if self.race && upstreams.len() > 1 && RACE_THREADS.load(Ordering::SeqCst) + 2 <= MAX_RACE_THREADS {
+            result = self.race(qname, qtype, &upstreams[..2]);
+            tried = 2;
+        }
+        for upstream in &upstreams[tried..] {
+            if is_good(&result) {
+                break;
             }
-            Some(packet) => packet
-        };
+            debug!("Querying {:?} {} from {}", qtype, qname, upstream);
+            result = query_upstream(&self.context, upstream, qname, qtype);
+        }
 
+        let result = result?;
         self.context.cache.store(&result.answers)?;
 
         Ok(result)
@@ -208,6 +283,7 @@ mod tests {
 
     use std::sync::Arc;
 
+    use crate::dns::client::ClientError;
     use crate::dns::protocol::{DnsPacket, DnsRecord, QueryType, ResultCode, TransientTtl};
 
     use super::*;
@@ -236,7 +312,8 @@ mod tests {
         match Arc::get_mut(&mut context) {
             Some(mut ctx) => {
                 ctx.resolve_strategy = ResolveStrategy::Forward {
-                    upstreams: vec![String::from("127.0.0.1:53")]
+                    upstreams: vec![String::from("127.0.0.1:53")],
+                    race: false
                 };
             }
             None => panic!(),
@@ -295,6 +372,74 @@ mod tests {
         };
     }
 
+    #[test]
+    fn test_forwarding_failover() {
+        let mut context = create_test_context(Box::new(|qname, _, server, _| {
+            let mut packet = DnsPacket::new();
+            match server {
+                "10.0.0.1:53" => return Err(ClientError::TimeOut),
+                "10.0.0.2:53" => packet.header.rescode = ResultCode::SERVFAIL,
+                _ => packet.answers.push(DnsRecord::A {
+                    domain: qname.to_string(),
+                    addr: "127.0.0.1".parse().unwrap(),
+                    ttl: TransientTtl(3600),
+                })
+            }
+            Ok(packet)
+        }));
+        let upstreams = vec![String::from("10.0.0.1:53"), String::from("10.0.0.2:53"), String::from("10.0.0.3:53")];
+        Arc::get_mut(&mut context).unwrap().resolve_strategy = ResolveStrategy::Forward { upstreams: upstreams.clone(), race: false };
+
+        let mut resolver = context.create_resolver(Arc::clone(&context));
+        for name in ["a.com", "b.com", "c.com", "d.com"].iter() {
+            let res = resolver.resolve(name, QueryType::A, true).unwrap();
+            assert_eq!(1, res.answers.len());
+        }
+
+        let stats = context.statistics.get_upstreams();
+        let good = &stats.iter().find(|(upstream, _)| upstream == "10.0.0.3:53").unwrap().1;
+        assert_eq!(4, good.queries);
+        assert_eq!(0, good.failures);
+        assert!(stats.iter().filter(|(upstream, _)| upstream != "10.0.0.3:53").all(|(_, stats)| stats.failures == stats.queries));
+        // Upstreams that fail in a row are tried last
+        for _ in 0..3 {
+            context.statistics.upstreams.record_failure("10.0.0.1:53");
+            context.statistics.upstreams.record_failure("10.0.0.2:53");
+        }
+        assert_eq!("10.0.0.3:53", context.statistics.upstreams.order(&upstreams)[0]);
+
+        let mut resolver = ForwardingDnsResolver::new(Arc::clone(&context), upstreams, true);
+        let res = resolver.resolve("e.com", QueryType::A, true).unwrap();
+        assert_eq!(1, res.answers.len());
+    }
+
+    #[test]
+    fn test_forwarding_rules() {
+        let mut context = create_test_context(Box::new(|qname, _, server, _| {
+            let mut packet = DnsPacket::new();
+            packet.answers.push(DnsRecord::A {
+                domain: qname.to_string(),
+                addr: server.trim_end_matches(":53").parse().unwrap(),
+                ttl: TransientTtl(3600),
+            });
+            Ok(packet)
+        }));
+        let ctx = Arc::get_mut(&mut context).unwrap();
+        ctx.resolve_strategy = ResolveStrategy::Forward { upstreams: vec![String::from("10.0.0.1:53")], race: false };
+        ctx.add_rule("Corp.Local.", ResolveStrategy::Forward { upstreams: vec![String::from("10.0.0.2:53")], race: false });
+        ctx.add_rule("dev.corp.local", ResolveStrategy::Forward { upstreams: vec![String::from("10.0.0.3:53")], race: false });
+
+        let mut resolver = context.create_resolver(Arc::clone(&context));
+        let expected = [("host.corp.local", "10.0.0.2"), ("corp.local", "10.0.0.2"), ("host.dev.corp.local", "10.0.0.3"), ("notcorp.local", "10.0.0.1")];
+        for (name, addr) in expected.iter() {
+            let res = resolver.resolve(name, QueryType::A, true).unwrap();
+            match res.answers[0] {
+                DnsRecord::A { addr: ref answer, .. } => assert_eq!(addr, &answer.to_string()),
+                _ => panic!(),
+            }
+        }
+    }
+
     #[test]
     fn test_recursive_resolver_with_no_nameserver() {
         let context = create_test_context(Box::new(|_, _, _, _| {
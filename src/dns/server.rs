@@ -1,19 +1,21 @@
-//! UDP and TCP server implementations for DNS
+//! UDP, TCP and TLS server implementations for DNS
 
 use std::collections::VecDeque;
-use std::io::Write;
+use std::io::{Read, Write};
 use std::net::SocketAddr;
 use std::net::{Shutdown, TcpListener, TcpStream, UdpSocket};
-use std::sync::atomic::Ordering;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::mpsc::{channel, Sender};
 use std::sync::{Arc, Condvar, Mutex};
 use std::thread::Builder;
+use std::time::Duration;
 
 use derive_more::{Display, Error, From};
 use rand::random;
 use log::{error, warn, debug};
+use rustls::{ServerConfig, ServerSession, Session, StreamOwned};
 
-use crate::dns::buffer::{BytePacketBuffer, PacketBuffer, StreamPacketBuffer, VectorPacketBuffer};
+use crate::dns::buffer::{BytePacketBuffer, PacketBuffer, VectorPacketBuffer};
 use crate::dns::context::ServerContext;
 use crate::dns::netutil::{read_packet_length, write_packet_length};
 use crate::dns::protocol::{DnsPacket, DnsRecord, QueryType, ResultCode};
@@ -26,6 +28,11 @@ pub enum ServerError {
 
 type Result<T> = std::result::Result<T, ServerError>;
 
+/// Idle TLS connections are closed after this time
+const TLS_IO_TIMEOUT: Duration = Duration::from_secs(10);
+/// How many queries we serve in one TLS connection before closing it
+const TLS_MAX_QUERIES: usize = 100;
+
 macro_rules! return_or_report {
     ( $x:expr, $message:expr ) => {
         match $x {
@@ -321,32 +328,7 @@ impl DnsServer for DnsTcpServer {
                         Err(_) => continue,
                     };
 
-                    let _ = context.statistics.tcp_query_count.fetch_add(1, Ordering::Release);
-
-                    // When DNS packets are sent over TCP, they're prefixed with a two byte
-                    // length. We don't really need to know the length in advance, so we
-                    // just move past it and continue reading as usual
-                    ignore_or_report!(read_packet_length(&mut stream), "Failed to read query packet length");
-
-                    let request = {
-                        let mut stream_buffer = StreamPacketBuffer::new(&mut stream);
-                        return_or_report!(DnsPacket::from_buffer(&mut stream_buffer), "Failed to read query packet")
-                    };
-
-                    let mut res_buffer = VectorPacketBuffer::new();
-
-                    let mut packet = execute_query(Arc::clone(&context), &request);
-                    ignore_or_report!(packet.write(&mut res_buffer, 0xFFFF), "Failed to write packet to buffer");
-
-                    // As is the case for incoming queries, we need to send a 2 byte length
-                    // value before handing of the actual packet.
-                    let len = res_buffer.pos();
-                    ignore_or_report!(write_packet_length(&mut stream, len), "Failed to write packet size");
-
-                    // Now we can go ahead and write the actual packet
-                    let data = return_or_report!(res_buffer.get_range(0, len), "Failed to get packet data");
-
-                    ignore_or_report!(stream.write(data), "Failed to write response packet");
+                    serve_stream(&context, &mut stream, 1, &context.statistics.tcp_query_count);
 
                     ignore_or_report!(stream.shutdown(Shutdown::Both), "Failed to shutdown socket");
                 }
@@ -386,12 +368,115 @@ impl DnsServer for DnsTcpServer {
     }
 }
 
+/// DNS-over-TLS server
+///
+/// Uses the same framing as `DnsTcpServer`, but inside of TLS session,
+/// and serves many queries in one connection, as recommended in RFC 7858.
+pub struct DnsTlsServer {
+    context: Arc<ServerContext>,
+    listen: String,
+    tls: Arc<ServerConfig>,
+    senders: Vec<Sender<TcpStream>>,
+    thread_count: usize,
+}
+
+impl DnsTlsServer {
+    pub fn new(context: Arc<ServerContext>, listen: String, tls: ServerConfig, thread_count: usize) -> DnsTlsServer {
+        DnsTlsServer { context, listen, tls: Arc::new(tls), senders: Vec::new(), thread_count }
+    }
+}
+
+impl DnsServer for DnsTlsServer {
+    fn run_server(mut self) -> Result<()> {
+        let socket = TcpListener::bind(self.listen.as_str())?;
+
+        for thread_id in 0..self.thread_count {
+            let (tx, rx) = channel::<TcpStream>();
+            self.senders.push(tx);
+
+            let context = Arc::clone(&self.context);
+            let tls = Arc::clone(&self.tls);
+
+            let name = "DnsTlsServer-request-".to_string() + &thread_id.to_string();
+            let _ = Builder::new().name(name).spawn(move || {
+                while let Ok(stream) = rx.recv() {
+                    let _ = stream.set_read_timeout(Some(TLS_IO_TIMEOUT));
+                    let _ = stream.set_write_timeout(Some(TLS_IO_TIMEOUT));
+                    let mut stream = StreamOwned::new(ServerSession::new(&tls), stream);
+                    serve_stream(&context, &mut stream, TLS_MAX_QUERIES, &context.statistics.tls_query_count);
+                    stream.sess.send_close_notify();
+                    let _ = stream.flush();
+                }
+            })?;
+        }
+
+        let _ = Builder::new()
+            .name("DnsTlsServer-incoming".into())
+            .spawn(move || {
+                for wrap_stream in socket.incoming() {
+                    let stream = match wrap_stream {
+                        Ok(stream) => stream,
+                        Err(err) => {
+                            warn!("Failed to accept TLS connection: {:?}", err);
+                            continue;
+                        }
+                    };
+
+                    // Hand it off to a worker thread
+                    let thread_no = random::<usize>() % self.thread_count;
+                    if let Err(e) = self.senders[thread_no].send(stream) {
+                        warn!("Failed to send TLS request for processing on thread {}: {}", thread_no, e);
+                    }
+                }
+            })?;
+
+        Ok(())
+    }
+}
+
+/// Serves up to `max_queries` queries from a stream, every packet is prefixed with its two byte length
+fn serve_stream<S: Read + Write>(context: &Arc<ServerContext>, stream: &mut S, max_queries: usize, counter: &AtomicUsize) {
+    for _ in 0..max_queries {
+        let len = return_or_report!(read_packet_length(stream), "Failed to read query packet length");
+        let _ = counter.fetch_add(1, Ordering::Release);
+
+        let request = {
+            let mut req_buffer = VectorPacketBuffer::new();
+            req_buffer.buffer = vec![0; len as usize];
+            ignore_or_report!(stream.read_exact(&mut req_buffer.buffer), "Failed to read query packet");
+            return_or_report!(DnsPacket::from_buffer(&mut req_buffer), "Failed to parse query packet")
+        };
+
+        let mut res_buffer = VectorPacketBuffer::new();
+
+        let mut packet = execute_query(Arc::clone(context), &request);
+        ignore_or_report!(packet.write(&mut res_buffer, 0xFFFF), "Failed to write packet to buffer");
+
+        // As is the case for incoming queries, we need to send a 2 byte length
+        // value before handing of the actual packet.
+        let len = res_buffer.pos();
+        ignore_or_report!(write_packet_length(stream, len), "Failed to write packet size");
+
+        // Now we can go ahead and write the actual packet
+        let data = return_or_report!(res_buffer.get_range(0, len), "Failed to get packet data");
+
+        ignore_or_report!(stream.write_all(data), "Failed to write response packet");
+        ignore_or_report!(stream.flush(), "Failed to flush response packet");
+    }
+}
+
 #[cfg(test)]
 mod tests {
 
+    use std::fs::File;
+    use std::io::BufReader;
     use std::net::Ipv4Addr;
+    use std::path::Path;
     use std::sync::Arc;
 
+    use rustls::{ClientConfig, ClientSession};
+    use webpki::DNSNameRef;
+
     use crate::dns::protocol::{
         DnsPacket, DnsQuestion, DnsRecord, QueryType, ResultCode, TransientTtl,
     };
@@ -400,6 +485,7 @@ mod tests {
 
     use crate::dns::context::tests::create_test_context;
     use crate::dns::context::ResolveStrategy;
+    use crate::dns::tls::load_server_config;
 
     fn build_query(qname: &str, qtype: QueryType) -> DnsPacket {
         let mut query_packet = DnsPacket::new();
@@ -457,7 +543,8 @@ mod tests {
         match Arc::get_mut(&mut context) {
             Some(mut ctx) => {
                 ctx.resolve_strategy = ResolveStrategy::Forward {
-                    upstreams: vec![String::from("127.0.0.1:53")]
+                    upstreams: vec![String::from("127.0.0.1:53")],
+                    race: false
                 };
             }
             None => panic!(),
@@ -564,7 +651,8 @@ mod tests {
         match Arc::get_mut(&mut context2) {
             Some(mut ctx) => {
                 ctx.resolve_strategy = ResolveStrategy::Forward {
-                    upstreams: vec![String::from("127.0.0.1:53")]
+                    upstreams: vec![String::from("127.0.0.1:53")],
+                    race: false
                 };
             }
             None => panic!(),
@@ -577,4 +665,50 @@ mod tests {
             assert_eq!(0, res.answers.len());
         };
     }
+    #[test]
+    fn test_tls_server() {
+        let mut context = create_test_context(Box::new(|qname, _, _, _| {
+            let mut packet = DnsPacket::new();
+            packet.answers.push(DnsRecord::A { domain: qname.to_string(), addr: Ipv4Addr::new(127, 0, 0, 1), ttl: TransientTtl(3600) });
+            Ok(packet)
+        }));
+        match Arc::get_mut(&mut context) {
+            Some(ctx) => {
+                ctx.resolve_strategy = ResolveStrategy::Forward {
+                    upstreams: vec![String::from("127.0.0.1:53")],
+                    race: false
+                };
+            }
+            None => panic!(),
+        }
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("src/dns/fixtures");
+        let tls = load_server_config(&dir.join("test_cert.pem"), &dir.join("test_key.pem"), &[b"dot"]).unwrap();
+        let port = 20000 + random::<u16>() % 20000;
+        DnsTlsServer::new(Arc::clone(&context), format!("127.0.0.1:{}", port), tls, 1).run_server().unwrap();
+
+        let mut config = ClientConfig::new();
+        config.root_store.add_pem_file(&mut BufReader::new(File::open(dir.join("test_ca.pem")).unwrap())).unwrap();
+        let name = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+        let socket = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let mut stream = StreamOwned::new(ClientSession::new(&Arc::new(config), name), socket);
+
+        // Both queries go over one connection
+        for (id, qname) in [(1, "one.ygg"), (2, "two.ygg")].iter() {
+            let mut query = build_query(qname, QueryType::A);
+            query.header.id = *id;
+            let mut buffer = VectorPacketBuffer::new();
+            query.write(&mut buffer, 0xFFFF).unwrap();
+            write_packet_length(&mut stream, buffer.pos()).unwrap();
+            stream.write_all(buffer.get_range(0, buffer.pos()).unwrap()).unwrap();
+            stream.flush().unwrap();
+
+            let mut buffer = VectorPacketBuffer::new();
+            buffer.buffer = vec![0; read_packet_length(&mut stream).unwrap() as usize];
+            stream.read_exact(&mut buffer.buffer).unwrap();
+            let response = DnsPacket::from_buffer(&mut buffer).unwrap();
+            assert_eq!(*id, response.header.id);
+            assert_eq!(1, response.answers.len());
+        }
+        assert_eq!(2, context.statistics.tls_query_count.load(Ordering::Acquire));
+    }
 }
@@ -0,0 +1,47 @@
+//! TLS settings for encrypted DNS listeners and upstreams
+
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+use rustls::{ClientConfig, NoClientAuth, ServerConfig};
+use rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
+
+/// Loads certificate chain and private key from PEM files and makes server config with them.
+/// `protocols` are ALPN protocols that we announce to clients.
+pub fn load_server_config(cert: &Path, key: &Path, protocols: &[&[u8]]) -> Result<ServerConfig, String> {
+    let file = File::open(cert).map_err(|e| format!("Error opening certificate {}: {}", cert.display(), e))?;
+    let chain = certs(&mut BufReader::new(file)).map_err(|_| format!("Error reading certificate {}", cert.display()))?;
+    if chain.is_empty() {
+        return Err(format!("No certificates found in {}", cert.display()));
+    }
+
+    let file = File::open(key).map_err(|e| format!("Error opening private key {}: {}", key.display(), e))?;
+    let mut keys = pkcs8_private_keys(&mut BufReader::new(file)).unwrap_or_default();
+    if keys.is_empty() {
+        let file = File::open(key).map_err(|e| format!("Error opening private key {}: {}", key.display(), e))?;
+        keys = rsa_private_keys(&mut BufReader::new(file)).unwrap_or_default();
+    }
+    let key_der = keys.pop().ok_or_else(|| format!("No private keys found in {}", key.display()))?;
+
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config.set_single_cert(chain, key_der).map_err(|e| format!("Error loading certificate {}: {}", cert.display(), e))?;
+    config.set_protocols(&protocols.iter().map(|p| p.to_vec()).collect::<Vec<_>>());
+    Ok(config)
+}
+
+/// Makes client config that trusts well known root certificates
+pub fn make_client_config() -> ClientConfig {
+    let mut config = ClientConfig::new();
+    config.root_store.add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+    config
+}
+
+/// Adds root certificates from PEM file to client config, to trust private or self-signed upstreams
+pub fn add_root_certificates(config: &mut ClientConfig, ca: &Path) -> Result<(), String> {
+    let file = File::open(ca).map_err(|e| format!("Error opening certificates {}: {}", ca.display(), e))?;
+    match config.root_store.add_pem_file(&mut BufReader::new(file)) {
+        Ok((added, _)) if added > 0 => Ok(()),
+        _ => Err(format!("No certificates found in {}", ca.display()))
+    }
+}
@@ -0,0 +1,439 @@
+//! Clients for DNS-over-TLS and DNS-over-HTTPS upstreams
+//!
+//! Connections to upstreams are kept open and reused for next queries,
+//! so that we don't make TLS handshake for every query.
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use rustls::{ClientConfig, ClientSession, StreamOwned};
+use webpki::DNSNameRef;
+
+use crate::dns::buffer::{PacketBuffer, VectorPacketBuffer};
+use crate::dns::client::{ClientError, DnsClient};
+use crate::dns::netutil::{read_packet_length, write_packet_length};
+use crate::dns::protocol::{DnsPacket, DnsQuestion, QueryType};
+
+type Result<T> = std::result::Result<T, ClientError>;
+type TlsStream = StreamOwned<ClientSession, TcpStream>;
+
+pub const TLS_SCHEME: &str = "tls://";
+pub const HTTPS_SCHEME: &str = "https://";
+const DOT_PORT: u16 = 853;
+const HTTPS_PORT: u16 = 443;
+const DOH_PATH: &str = "/dns-query";
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const IO_TIMEOUT: Duration = Duration::from_secs(5);
+/// How many idle connections we keep for every upstream
+const MAX_IDLE: usize = 4;
+/// Max size of HTTP response headers
+const MAX_HEAD_SIZE: u64 = 8192;
+const MAX_RESPONSE_SIZE: usize = 65535;
+
+/// Where to connect, and what name must be in server's certificate
+struct Endpoint {
+    addr: SocketAddr,
+    name: String,
+    path: String,
+}
+
+/// Encrypted upstreams are set by IP, as resolving their names by system resolver can bring the query back to us.
+/// The name that must be in certificate of upstream goes after `#`.
+impl Endpoint {
+    /// Parses upstream like `tls://1.1.1.1:853#cloudflare-dns.com`
+    fn from_tls(server: &str) -> Result<Endpoint> {
+        let rest = server.strip_prefix(TLS_SCHEME).ok_or(ClientError::WrongServer)?;
+        let (host_port, name) = split_name(rest)?;
+        let addr = parse_addr(host_port, DOT_PORT)?;
+        Ok(Endpoint { addr, name, path: String::new() })
+    }
+
+    /// Parses upstream like `https://94.140.14.14/dns-query#dns.adguard.com`
+    fn from_https(server: &str) -> Result<Endpoint> {
+        let rest = server.strip_prefix(HTTPS_SCHEME).ok_or(ClientError::WrongServer)?;
+        let (rest, name) = split_name(rest)?;
+        let (host_port, path) = match rest.find('/') {
+            Some(pos) => (&rest[..pos], &rest[pos..]),
+            None => (rest, DOH_PATH)
+        };
+        let addr = parse_addr(host_port, HTTPS_PORT)?;
+        Ok(Endpoint { addr, name, path: path.to_owned() })
+    }
+
+    /// Value for HTTP `Host` header
+    fn host_header(&self) -> String {
+        match self.addr.port() {
+            HTTPS_PORT => self.name.clone(),
+            port => format!("{}:{}", self.name, port)
+        }
+    }
+}
+
+/// Checks that encrypted upstream has IP address and a name for certificate, plain ones are always good here
+pub fn check_upstream(server: &str) -> bool {
+    if server.starts_with(TLS_SCHEME) {
+        Endpoint::from_tls(server).is_ok()
+    } else if server.starts_with(HTTPS_SCHEME) {
+        Endpoint::from_https(server).is_ok()
+    } else {
+        true
+    }
+}
+
+/// Splits upstream to address and a name after `#`
+fn split_name(server: &str) -> Result<(&str, String)> {
+    match server.rfind('#') {
+        Some(pos) if pos + 1 < server.len() => Ok((&server[..pos], server[pos + 1..].to_owned())),
+        _ => Err(ClientError::WrongServer)
+    }
+}
+
+fn parse_addr(host_port: &str, default_port: u16) -> Result<SocketAddr> {
+    if let Ok(addr) = host_port.parse::<SocketAddr>() {
+        return Ok(addr);
+    }
+    match host_port.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+        Ok(ip) => Ok(SocketAddr::new(ip, default_port)),
+        Err(_) => Err(ClientError::WrongServer)
+    }
+}
+
+/// Idle TLS connections by upstream
+struct ConnectionPool {
+    config: Arc<ClientConfig>,
+    idle: Mutex<HashMap<String, Vec<TlsStream>>>,
+}
+
+impl ConnectionPool {
+    fn new(config: ClientConfig) -> ConnectionPool {
+        ConnectionPool { config: Arc::new(config), idle: Mutex::new(HashMap::new()) }
+    }
+
+    fn connect(&self, endpoint: &Endpoint) -> Result<TlsStream> {
+        let name = DNSNameRef::try_from_ascii_str(&endpoint.name).map_err(|_| ClientError::WrongServer)?;
+        let socket = TcpStream::connect_timeout(&endpoint.addr, CONNECT_TIMEOUT)?;
+        socket.set_read_timeout(Some(IO_TIMEOUT))?;
+        socket.set_write_timeout(Some(IO_TIMEOUT))?;
+        Ok(StreamOwned::new(ClientSession::new(&self.config, name), socket))
+    }
+
+    /// Makes `exchange` over idle connection to `server`, or over a new one if there is none, or it was closed by server.
+    /// The `exchange` returns answer and whether the connection can be used again.
+    fn exchange<F>(&self, server: &str, endpoint: &Endpoint, exchange: F) -> Result<DnsPacket>
+        where F: Fn(&mut TlsStream) -> Result<(DnsPacket, bool)> {
+        let idle = self.idle.lock().map_err(|_| ClientError::PoisonedLock)?.get_mut(server).and_then(|list| list.pop());
+        if let Some(mut stream) = idle {
+            if let Ok((packet, keep)) = exchange(&mut stream) {
+                if keep {
+                    self.put(server, stream);
+                }
+                return Ok(packet);
+            }
+        }
+
+        let mut stream = self.connect(endpoint)?;
+        let (packet, keep) = exchange(&mut stream)?;
+        if keep {
+            self.put(server, stream);
+        }
+        Ok(packet)
+    }
+
+    fn put(&self, server: &str, stream: TlsStream) {
+        if let Ok(mut idle) = self.idle.lock() {
+            let list = idle.entry(server.to_owned()).or_default();
+            if list.len() < MAX_IDLE {
+                list.push(stream);
+            }
+        }
+    }
+}
+
+fn build_query(qname: &str, qtype: QueryType, recursive: bool, id: u16) -> Result<Vec<u8>> {
+    let mut packet = DnsPacket::new();
+    packet.header.id = id;
+    packet.header.questions = 1;
+    packet.header.recursion_desired = recursive;
+    packet.questions.push(DnsQuestion::new(qname.to_string(), qtype));
+
+    let mut buffer = VectorPacketBuffer::new();
+    packet.write(&mut buffer, 0xFFFF)?;
+    Ok(buffer.buffer[..buffer.pos()].to_vec())
+}
+
+fn parse_response(data: Vec<u8>) -> Result<DnsPacket> {
+    let mut buffer = VectorPacketBuffer::new();
+    buffer.buffer = data;
+    Ok(DnsPacket::from_buffer(&mut buffer)?)
+}
+
+/// DNS-over-TLS client (RFC 7858), for upstreams like `tls://1.1.1.1:853#cloudflare-dns.com`
+pub struct DnsTlsClient {
+    total_sent: AtomicUsize,
+    total_failed: AtomicUsize,
+    /// Counter for assigning packet ids
+    seq: AtomicUsize,
+    pool: ConnectionPool,
+}
+
+impl DnsTlsClient {
+    pub fn new(config: ClientConfig) -> DnsTlsClient {
+        DnsTlsClient {
+            total_sent: AtomicUsize::new(0),
+            total_failed: AtomicUsize::new(0),
+            seq: AtomicUsize::new(0),
+            pool: ConnectionPool::new(config),
+        }
+    }
+
+    fn query(&self, qname: &str, qtype: QueryType, server: &str, recursive: bool) -> Result<DnsPacket> {
+        let endpoint = Endpoint::from_tls(server)?;
+        let id = self.seq.fetch_add(1, Ordering::SeqCst) as u16;
+        let query = build_query(qname, qtype, recursive, id)?;
+        self.pool.exchange(server, &endpoint, |stream| {
+            write_packet_length(stream, query.len())?;
+            stream.write_all(&query)?;
+            stream.flush()?;
+
+            let len = read_packet_length(stream)?;
+            let mut data = vec![0; len as usize];
+            stream.read_exact(&mut data)?;
+            let packet = parse_response(data)?;
+            if packet.header.id != id {
+                return Err(ClientError::LookupFailed);
+            }
+            Ok((packet, true))
+        })
+    }
+}
+
+impl DnsClient for DnsTlsClient {
+    fn get_sent_count(&self) -> usize {
+        self.total_sent.load(Ordering::Acquire)
+    }
+
+    fn get_failed_count(&self) -> usize {
+        self.total_failed.load(Ordering::Acquire)
+    }
+
+    fn run(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn send_query(&self, qname: &str, qtype: QueryType, server: &str, recursive: bool) -> Result<DnsPacket> {
+        let _ = self.total_sent.fetch_add(1, Ordering::Release);
+        let result = self.query(qname, qtype, server, recursive);
+        if result.is_err() {
+            let _ = self.total_failed.fetch_add(1, Ordering::Release);
+        }
+        result
+    }
+}
+
+/// DNS-over-HTTPS client (RFC 8484), for upstreams like `https://dns.adguard.com/dns-query`
+pub struct DnsHttpsClient {
+    total_sent: AtomicUsize,
+    total_failed: AtomicUsize,
+    pool: ConnectionPool,
+}
+
+impl DnsHttpsClient {
+    pub fn new(mut config: ClientConfig) -> DnsHttpsClient {
+        config.set_protocols(&[b"http/1.1".to_vec()]);
+        DnsHttpsClient {
+            total_sent: AtomicUsize::new(0),
+            total_failed: AtomicUsize::new(0),
+            pool: ConnectionPool::new(config),
+        }
+    }
+
+    fn query(&self, qname: &str, qtype: QueryType, server: &str, recursive: bool) -> Result<DnsPacket> {
+        let endpoint = Endpoint::from_https(server)?;
+        // Id is always zero, to make responses cacheable
+        let query = build_query(qname, qtype, recursive, 0)?;
+        let head = format!("POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/dns-message\r\nAccept: application/dns-message\r\nContent-Length: {}\r\n\r\n",
+                           &endpoint.path, endpoint.host_header(), query.len());
+        self.pool.exchange(server, &endpoint, |stream| {
+            stream.write_all(head.as_bytes())?;
+            stream.write_all(&query)?;
+            stream.flush()?;
+
+            let (data, keep) = read_http_response(&mut BufReader::new(stream))?;
+            Ok((parse_response(data)?, keep))
+        })
+    }
+}
+
+impl DnsClient for DnsHttpsClient {
+    fn get_sent_count(&self) -> usize {
+        self.total_sent.load(Ordering::Acquire)
+    }
+
+    fn get_failed_count(&self) -> usize {
+        self.total_failed.load(Ordering::Acquire)
+    }
+
+    fn run(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn send_query(&self, qname: &str, qtype: QueryType, server: &str, recursive: bool) -> Result<DnsPacket> {
+        let _ = self.total_sent.fetch_add(1, Ordering::Release);
+        let result = self.query(qname, qtype, server, recursive);
+        if result.is_err() {
+            let _ = self.total_failed.fetch_add(1, Ordering::Release);
+        }
+        result
+    }
+}
+
+/// Reads HTTP response, returns its body and whether the connection can be used again
+fn read_http_response<R: BufRead>(reader: &mut R) -> Result<(Vec<u8>, bool)> {
+    let status = read_line(reader)?;
+    let mut parts = status.split_whitespace();
+    let version = parts.next().unwrap_or_default();
+    if !version.starts_with("HTTP/1.") || parts.next() != Some("200") {
+        return Err(ClientError::LookupFailed);
+    }
+
+    let mut keep = version == "HTTP/1.1";
+    let mut chunked = false;
+    let mut length = None;
+    loop {
+        let line = read_line(reader)?;
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = match line.find(':') {
+            Some(pos) => (line[..pos].trim().to_lowercase(), line[pos + 1..].trim().to_lowercase()),
+            None => return Err(ClientError::LookupFailed)
+        };
+        match name.as_str() {
+            "content-length" => length = Some(value.parse::<usize>().map_err(|_| ClientError::LookupFailed)?),
+            "transfer-encoding" => chunked = value.contains("chunked"),
+            "connection" => keep = value != "close",
+            _ => {}
+        }
+    }
+
+    let mut body = Vec::new();
+    if chunked {
+        loop {
+            let line = read_line(reader)?;
+            let size = line.split(';').next().unwrap_or_default().trim();
+            let size = usize::from_str_radix(size, 16).map_err(|_| ClientError::LookupFailed)?;
+            if body.len() + size > MAX_RESPONSE_SIZE {
+                return Err(ClientError::LookupFailed);
+            }
+            if size == 0 {
+                // Skipping trailers
+                while !read_line(reader)?.is_empty() {}
+                break;
+            }
+            let start = body.len();
+            body.resize(start + size, 0);
+            reader.read_exact(&mut body[start..])?;
+            read_line(reader)?;
+        }
+    } else {
+        match length {
+            Some(length) if length <= MAX_RESPONSE_SIZE => {
+                body.resize(length, 0);
+                reader.read_exact(&mut body)?;
+            }
+            _ => return Err(ClientError::LookupFailed)
+        }
+    }
+    Ok((body, keep))
+}
+
+fn read_line<R: BufRead>(reader: &mut R) -> Result<String> {
+    let mut line = String::new();
+    reader.by_ref().take(MAX_HEAD_SIZE).read_line(&mut line)?;
+    if !line.ends_with('\n') {
+        return Err(ClientError::LookupFailed);
+    }
+    Ok(line.trim_end().to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    use crate::dns::client::DnsClient;
+    use crate::dns::context::ResolveStrategy;
+    use crate::dns::context::tests::create_test_context;
+    use crate::dns::doh::DnsHttpsServer;
+    use crate::dns::protocol::{DnsPacket, DnsRecord, QueryType, TransientTtl};
+    use crate::dns::server::{DnsServer, DnsTlsServer};
+    use crate::dns::tls::{add_root_certificates, load_server_config, make_client_config};
+
+    use super::{check_upstream, DnsHttpsClient, DnsTlsClient, Endpoint};
+
+    const CA: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/src/dns/fixtures/test_ca.pem");
+    const CERT: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/src/dns/fixtures/test_cert.pem");
+    const KEY: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/src/dns/fixtures/test_key.pem");
+
+    #[test]
+    fn parse_upstreams() {
+        let endpoint = Endpoint::from_https("https://94.140.14.14/dns-query#dns.adguard.com").unwrap();
+        assert_eq!(endpoint.addr, "94.140.14.14:443".parse().unwrap());
+        assert_eq!(endpoint.name, "dns.adguard.com");
+        assert_eq!(endpoint.path, "/dns-query");
+        assert_eq!(endpoint.host_header(), "dns.adguard.com");
+        let endpoint = Endpoint::from_tls("tls://[2606:4700:4700::1111]#cloudflare-dns.com").unwrap();
+        assert_eq!(endpoint.addr, "[2606:4700:4700::1111]:853".parse().unwrap());
+
+        // Names of upstreams would be resolved by ourselves
+        assert!(!check_upstream("https://dns.adguard.com/dns-query"));
+        assert!(!check_upstream("tls://dns.adguard.com#dns.adguard.com"));
+        assert!(!check_upstream("tls://1.1.1.1:853"));
+        assert!(check_upstream("tls://1.1.1.1:853#cloudflare-dns.com"));
+        assert!(check_upstream("1.1.1.1:53"));
+    }
+
+    #[test]
+    fn query_local_upstreams() {
+        let mut context = create_test_context(Box::new(|qname, _, _, _| {
+            let mut packet = DnsPacket::new();
+            packet.answers.push(DnsRecord::A { domain: qname.to_string(), addr: Ipv4Addr::new(127, 0, 0, 1), ttl: TransientTtl(3600) });
+            Ok(packet)
+        }));
+        Arc::get_mut(&mut context).unwrap().resolve_strategy = ResolveStrategy::Forward { upstreams: vec![String::from("127.0.0.1:53")], race: false };
+
+        let port = 20000 + rand::random::<u16>() % 20000;
+        let tls = load_server_config(Path::new(CERT), Path::new(KEY), &[b"dot"]).unwrap();
+        DnsTlsServer::new(Arc::clone(&context), format!("127.0.0.1:{}", port), tls, 1).run_server().unwrap();
+        let tls = load_server_config(Path::new(CERT), Path::new(KEY), &[b"http/1.1"]).unwrap();
+        DnsHttpsServer::new(Arc::clone(&context), format!("127.0.0.1:{}", port + 1), Some(tls), 1).run_server().unwrap();
+
+        let mut config = make_client_config();
+        add_root_certificates(&mut config, Path::new(CA)).unwrap();
+
+        let client = DnsTlsClient::new(config.clone());
+        let server = format!("tls://127.0.0.1:{}#localhost", port);
+        for name in &["one.ygg", "two.ygg"] {
+            let packet = client.send_query(name, QueryType::A, &server, true).unwrap();
+            assert_eq!(packet.answers.len(), 1);
+        }
+        assert_eq!(context.statistics.get_tls_query_count(), 2);
+        // Both queries were sent over one connection
+        assert_eq!(client.pool.idle.lock().unwrap()[&server].len(), 1);
+
+        let client = DnsHttpsClient::new(config);
+        let server = format!("https://127.0.0.1:{}/dns-query#localhost", port + 1);
+        for name in &["three.ygg", "four.ygg"] {
+            let packet = client.send_query(name, QueryType::A, &server, true).unwrap();
+            assert_eq!(packet.answers.len(), 1);
+        }
+        assert_eq!(context.statistics.get_doh_query_count(), 2);
+        assert_eq!(client.pool.idle.lock().unwrap()[&server].len(), 1);
+        assert_eq!(client.get_failed_count(), 0);
+    }
+}
@@ -0,0 +1,123 @@
+//! Health tracking of upstream servers for forwarding resolver
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use rand::seq::SliceRandom;
+
+/// After this many failures in a row upstream is considered down
+const FAILURES_TO_DOWN: u32 = 3;
+/// Upstream that is down gets a chance again after this time
+const DOWN_RETRY_AFTER: Duration = Duration::from_secs(30);
+
+/// Current state of one upstream
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct UpstreamStats {
+    pub queries: u64,
+    pub failures: u64,
+    pub failures_in_row: u32,
+    /// Smoothed response time in milliseconds
+    pub latency_ms: u64,
+    last_failure: Option<Instant>,
+}
+
+impl UpstreamStats {
+    pub fn is_down(&self) -> bool {
+        match self.last_failure {
+            Some(time) => self.failures_in_row >= FAILURES_TO_DOWN && time.elapsed() < DOWN_RETRY_AFTER,
+            None => false
+        }
+    }
+}
+
+/// Latency and failures of all upstreams we have used
+#[derive(Default)]
+pub struct UpstreamHealth {
+    stats: Mutex<HashMap<String, UpstreamStats>>,
+}
+
+impl UpstreamHealth {
+    pub fn new() -> UpstreamHealth {
+        UpstreamHealth::default()
+    }
+
+    pub fn record_success(&self, upstream: &str, elapsed: Duration) {
+        if let Ok(mut stats) = self.stats.lock() {
+            let stats = stats.entry(upstream.to_owned()).or_default();
+            let latency = elapsed.as_millis() as u64;
+            stats.latency_ms = match stats.queries - stats.failures {
+                0 => latency,
+                _ => (stats.latency_ms * 3 + latency) / 4
+            };
+            stats.queries += 1;
+            stats.failures_in_row = 0;
+        }
+    }
+
+    pub fn record_failure(&self, upstream: &str) {
+        if let Ok(mut stats) = self.stats.lock() {
+            let stats = stats.entry(upstream.to_owned()).or_default();
+            stats.queries += 1;
+            stats.failures += 1;
+            stats.failures_in_row += 1;
+            stats.last_failure = Some(Instant::now());
+        }
+    }
+
+    /// Returns upstreams in order to try them: working ones by failures in a row and latency, then the ones that are down.
+    /// Upstreams that we didn't use yet go first to measure them, equal ones are shuffled.
+    pub fn order(&self, upstreams: &[String]) -> Vec<String> {
+        let mut result = upstreams.to_vec();
+        result.shuffle(&mut rand::thread_rng());
+        if let Ok(stats) = self.stats.lock() {
+            result.sort_by_key(|upstream| match stats.get(upstream) {
+                Some(stats) => (stats.is_down(), stats.failures_in_row, stats.latency_ms),
+                None => (false, 0, 0)
+            });
+        }
+        result
+    }
+
+    pub fn get_stats(&self) -> Vec<(String, UpstreamStats)> {
+        match self.stats.lock() {
+            Ok(stats) => {
+                let mut result: Vec<_> = stats.iter().map(|(upstream, stats)| (upstream.clone(), stats.clone())).collect();
+                result.sort_by(|a, b| a.0.cmp(&b.0));
+                result
+            }
+            Err(_) => Vec::new()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::dns::upstream::UpstreamHealth;
+
+    #[test]
+    fn failed_upstreams_go_last() {
+        let upstreams = vec![String::from("1.1.1.1:53"), String::from("8.8.8.8:53"), String::from("9.9.9.9:53")];
+        let health = UpstreamHealth::new();
+        health.record_success(&upstreams[0], Duration::from_millis(50));
+        health.record_success(&upstreams[1], Duration::from_millis(20));
+        for _ in 0..10 {
+            assert_eq!(health.order(&upstreams)[0], upstreams[2]);
+        }
+
+        health.record_failure(&upstreams[2]);
+        for _ in 0..10 {
+            assert_eq!(health.order(&upstreams), vec![upstreams[1].clone(), upstreams[0].clone(), upstreams[2].clone()]);
+        }
+        health.record_failure(&upstreams[1]);
+        health.record_failure(&upstreams[2]);
+        for _ in 0..10 {
+            assert_eq!(health.order(&upstreams), vec![upstreams[0].clone(), upstreams[1].clone(), upstreams[2].clone()]);
+        }
+        // It is working again
+        health.record_success(&upstreams[2], Duration::from_millis(10));
+        assert_eq!(health.order(&upstreams)[0], upstreams[2]);
+    }
+}
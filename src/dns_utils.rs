@@ -3,13 +3,17 @@ use std::env;
 
 use crate::{Context, Settings};
 use crate::blockchain::filter::BlockchainFilter;
-use crate::dns::server::{DnsServer, DnsUdpServer, DnsTcpServer};
+use crate::dns::server::{DnsServer, DnsUdpServer, DnsTcpServer, DnsTlsServer};
+use crate::dns::doh::DnsHttpsServer;
+use crate::dns::tls::{add_root_certificates, load_server_config, make_client_config};
+use crate::dns::tls_client::{check_upstream, DnsHttpsClient, DnsTlsClient};
+use rustls::ServerConfig;
 use crate::dns::context::{ServerContext, ResolveStrategy};
 #[allow(unused_imports)]
 use log::{debug, error, info, LevelFilter, trace, warn};
 use crate::dns::hosts::HostsFilter;
 
-/// Starts UDP and TCP DNS-servers
+/// Starts UDP, TCP, DoH and DoT DNS-servers
 pub fn start_dns_server(context: &Arc<Mutex<Context>>, settings: &Settings) {
     let server_context = create_server_context(Arc::clone(&context), &settings);
 
@@ -26,6 +30,47 @@ pub fn start_dns_server(context: &Arc<Mutex<Context>>, settings: &Settings) {
             error!("Failed to bind TCP listener: {:?}", e);
         }
     }
+
+    if !settings.dns.doh_listen.is_empty() {
+        let tls = if settings.dns.tls_cert.is_empty() {
+            warn!("DoH server on {} works without TLS, set certificate if it is not behind reverse proxy", &settings.dns.doh_listen);
+            None
+        } else {
+            load_tls_config(settings, b"http/1.1")
+        };
+        if tls.is_some() || settings.dns.tls_cert.is_empty() {
+            let doh_server = DnsHttpsServer::new(Arc::clone(&server_context), settings.dns.doh_listen.clone(), tls, settings.dns.threads);
+            match doh_server.run_server() {
+                Ok(_) => info!("Started DoH server on {}", &settings.dns.doh_listen),
+                Err(e) => error!("Failed to bind DoH listener: {:?}", e)
+            }
+        }
+    }
+
+    if !settings.dns.dot_listen.is_empty() {
+        if settings.dns.tls_cert.is_empty() {
+            error!("DoT server needs a certificate, set tls_cert and tls_key in [dns] section");
+        } else if let Some(tls) = load_tls_config(settings, b"dot") {
+            let dot_server = DnsTlsServer::new(Arc::clone(&server_context), settings.dns.dot_listen.clone(), tls, settings.dns.threads);
+            match dot_server.run_server() {
+                Ok(_) => info!("Started DoT server on {}", &settings.dns.dot_listen),
+                Err(e) => error!("Failed to bind DoT listener: {:?}", e)
+            }
+        }
+    }
+}
+
+/// Loads certificate and key for encrypted listeners, that announce `protocol` in ALPN
+fn load_tls_config(settings: &Settings, protocol: &[u8]) -> Option<ServerConfig> {
+    let cert = settings.get_path(&settings.dns.tls_cert);
+    let key = settings.get_path(&settings.dns.tls_key);
+    match load_server_config(&cert, &key, &[protocol]) {
+        Ok(config) => Some(config),
+        Err(e) => {
+            error!("Failed to load TLS certificate: {}", e);
+            None
+        }
+    }
 }
 
 /// Creates DNS-context with all needed settings
@@ -33,10 +78,32 @@ fn create_server_context(context: Arc<Mutex<Context>>, settings: &Settings) -> A
     let mut server_context = ServerContext::new();
     server_context.allow_recursive = true;
     server_context.dns_listen = settings.dns.listen.clone();
+    server_context.zones_dir = settings.get_path("zones");
     server_context.resolve_strategy = match settings.dns.forwarders.is_empty() {
         true => { ResolveStrategy::Recursive }
-        false => { ResolveStrategy::Forward { upstreams: settings.dns.forwarders.clone() } }
+        false => { ResolveStrategy::Forward { upstreams: settings.dns.forwarders.clone(), race: settings.dns.race } }
     };
+    let upstreams = settings.dns.rules.iter().flat_map(|rule| rule.forwarders.iter()).chain(settings.dns.forwarders.iter());
+    for upstream in upstreams.filter(|upstream| !check_upstream(upstream)) {
+        error!("Wrong forwarder {}, encrypted forwarders need IP address and a name for certificate after '#'", upstream);
+    }
+    for rule in &settings.dns.rules {
+        let strategy = match rule.forwarders.is_empty() {
+            true => ResolveStrategy::Recursive,
+            false => ResolveStrategy::Forward { upstreams: rule.forwarders.clone(), race: settings.dns.race }
+        };
+        server_context.add_rule(&rule.domain, strategy);
+    }
+    if !settings.dns.tls_ca.is_empty() {
+        let mut config = make_client_config();
+        match add_root_certificates(&mut config, &settings.get_path(&settings.dns.tls_ca)) {
+            Ok(_) => {
+                server_context.tls_client = Box::new(DnsTlsClient::new(config.clone()));
+                server_context.https_client = Box::new(DnsHttpsClient::new(config));
+            }
+            Err(e) => error!("Failed to load certificates for forwarders: {}", e)
+        }
+    }
     // Add host filters
     for host in &settings.dns.hosts {
         if host == "system" {
@@ -18,4 +18,5 @@ pub enum Event {
     NetworkStatus { nodes: usize, blocks: u64 },
     Syncing { have: u64, height: u64 },
     SyncFinished,
+    DomainExpiring { name: String, expires: i64 },
 }
@@ -18,7 +18,6 @@ use log::{debug, error, info, trace, warn};
 use crate::blockchain::hash_utils::*;
 use crate::{Context, setup_miner_thread};
 use crate::event::Event;
-use crate::commons::KEYSTORE_DIFFICULTY;
 use crate::bytes::Bytes;
 use blakeout::Blakeout;
 use std::time::Instant;
@@ -28,6 +27,17 @@ use self::ed25519_dalek::ed25519::signature::Signature;
 use rand_old::{CryptoRng, RngCore};
 use rand_old::rngs::OsRng;
 use crate::crypto::Chacha;
+use std::convert::TryInto;
+
+const KEY_FILE_MAGIC: &[u8] = b"ALFISKEY";
+const KEY_FILE_VERSION: u8 = 1;
+const KEY_FILE_KDF_SCRYPT: u8 = 1;
+const KEY_FILE_LOG_N: u8 = 15;
+const KEY_FILE_R: u32 = 8;
+const KEY_FILE_P: u32 = 1;
+const KEY_FILE_SALT_LEN: usize = 32;
+const KEY_FILE_NONCE_LEN: usize = 12;
+const KEY_FILE_HEADER_LEN: usize = 8 + 1 + 1 + 1 + 4 + 4 + KEY_FILE_SALT_LEN + KEY_FILE_NONCE_LEN;
 
 #[derive(Debug)]
 pub struct Keystore {
@@ -65,28 +75,26 @@ impl Keystore {
         Keystore { keypair, hash: RefCell::new(Bytes::default()), path: String::new(), chacha }
     }
 
-    pub fn from_file(filename: &str, _password: &str) -> Option<Self> {
+    /// Loads keys from file, if their strength is less than `strength` they are not loaded
+    pub fn from_file(filename: &str, password: &str, strength: u32) -> Option<Self> {
         let path = Path::new(filename);
         match fs::read(&path) {
             Ok(key) => {
-                if key.len() == 32 {
-                    let mut keystore = Keystore::from_random_bytes(key.as_slice());
-                    keystore.path = path.to_str().unwrap().to_owned();
-                    let bytes = Bytes::from_bytes(&keystore.keypair.public.to_bytes());
-                    return if check_public_key_strength(&bytes, KEYSTORE_DIFFICULTY) {
-                        Some(keystore)
-                    } else {
-                        None
-                    };
-                }
-                let mut keystore = Self::from_bytes(key.as_slice());
+                let mut keystore = match key.len() {
+                    32 => Keystore::from_random_bytes(key.as_slice()),
+                    64 => Keystore::from_bytes(key.as_slice()),
+                    _ => {
+                        let bytes = decrypt_key_file(&key, password)?;
+                        Keystore::from_bytes(&bytes)
+                    }
+                };
                 keystore.path = path.to_str().unwrap().to_owned();
                 let bytes = Bytes::from_bytes(&keystore.keypair.public.to_bytes());
-                return if check_public_key_strength(&bytes, KEYSTORE_DIFFICULTY) {
+                if check_public_key_strength(&bytes, strength) {
                     Some(keystore)
                 } else {
                     None
-                };
+                }
             }
             Err(_) => {
                 None
@@ -94,11 +102,24 @@ impl Keystore {
         }
     }
 
-    pub fn save(&mut self, filename: &str, _password: &str) {
+    /// Checks if the key file is saved in encrypted format and needs a password to load
+    pub fn is_encrypted(filename: &str) -> bool {
+        match fs::read(Path::new(filename)) {
+            Ok(key) => { key.starts_with(KEY_FILE_MAGIC) }
+            Err(_) => { false }
+        }
+    }
+
+    /// Saves keys to file. If the password is not empty the keys are encrypted with it,
+    /// otherwise they are saved as raw bytes (the legacy format).
+    pub fn save(&mut self, filename: &str, password: &str) {
         match File::create(Path::new(filename)) {
             Ok(mut f) => {
-                //TODO implement key encryption
                 let bytes = self.keypair.to_bytes();
+                let bytes = match password.is_empty() {
+                    true => { bytes.to_vec() }
+                    false => { encrypt_key_file(&bytes, password) }
+                };
                 f.write_all(&bytes).expect("Error saving keystore");
                 self.path = filename.to_owned();
             }
@@ -130,8 +151,14 @@ impl Keystore {
     }
 
     pub fn check(message: &[u8], public_key: &[u8], signature: &[u8]) -> bool {
-        let key = PublicKey::from_bytes(public_key).expect("Wrong public key!");
-        let signature = Signature::from_bytes(signature).unwrap();
+        let key = match PublicKey::from_bytes(public_key) {
+            Ok(key) => key,
+            Err(_) => return false
+        };
+        let signature = match Signature::from_bytes(signature) {
+            Ok(signature) => signature,
+            Err(_) => return false
+        };
         match key.verify(message, &signature) {
             Ok(_) => { true }
             Err(_) => { false }
@@ -147,6 +174,11 @@ impl Keystore {
         let decrypted = self.chacha.decrypt(message, nonce);
         Bytes::from_bytes(&decrypted)
     }
+
+    /// Same as `decrypt`, but returns None if the message was not encrypted by this keystore
+    pub fn try_decrypt(&self, message: &[u8], nonce: &[u8]) -> Option<Bytes> {
+        self.chacha.try_decrypt(message, nonce).map(|decrypted| Bytes::from_bytes(&decrypted))
+    }
 }
 
 impl Clone for Keystore {
@@ -175,6 +207,7 @@ pub fn create_key(context: Arc<Mutex<Context>>) {
     context.lock().unwrap().bus.post(Event::KeyGeneratorStarted);
     let lower = context.lock().unwrap().settings.mining.lower;
     let threads = context.lock().unwrap().settings.mining.threads;
+    let difficulty = context.lock().unwrap().chain.get_consensus().keystore_difficulty;
     let threads = match threads {
         0 => num_cpus::get(),
         _ => threads
@@ -188,7 +221,7 @@ pub fn create_key(context: Arc<Mutex<Context>>) {
             if lower {
                 setup_miner_thread(cpu as u32);
             }
-            match generate_key(KEYSTORE_DIFFICULTY, mining.clone()) {
+            match generate_key(difficulty, mining.clone()) {
                 None => {
                     debug!("Keystore mining finished");
                 }
@@ -218,7 +251,8 @@ pub fn create_key(context: Arc<Mutex<Context>>) {
     });
 }
 
-fn generate_key(difficulty: u32, mining: Arc<AtomicBool>) -> Option<Keystore> {
+/// Generates keys with public key strength of at least `difficulty`, until `mining` is set to false
+pub fn generate_key(difficulty: u32, mining: Arc<AtomicBool>) -> Option<Keystore> {
     use self::rand::RngCore;
     let mut rng = rand::thread_rng();
     let mut time = Instant::now();
@@ -247,6 +281,87 @@ fn generate_key(difficulty: u32, mining: Arc<AtomicBool>) -> Option<Keystore> {
     }
 }
 
+/// Encrypts keypair bytes with a key derived from password by scrypt.
+///
+/// The file consists of a header and encrypted keys:
+/// magic (8 bytes) | version (1) | kdf (1) | log_n (1) | r (4) | p (4) | salt (32) | nonce (12) | ciphertext
+fn encrypt_key_file(keypair: &[u8], password: &str) -> Vec<u8> {
+    let mut csprng = OsRng;
+    let mut salt = [0u8; KEY_FILE_SALT_LEN];
+    csprng.fill_bytes(&mut salt);
+    let mut nonce = [0u8; KEY_FILE_NONCE_LEN];
+    csprng.fill_bytes(&mut nonce);
+    let params = scrypt::Params::new(KEY_FILE_LOG_N, KEY_FILE_R, KEY_FILE_P).expect("Wrong scrypt params");
+    let chacha = derive_file_key(password, &salt, &params).expect("Error deriving key");
+
+    let mut result = Vec::with_capacity(KEY_FILE_HEADER_LEN + keypair.len() + 16);
+    result.extend_from_slice(KEY_FILE_MAGIC);
+    result.push(KEY_FILE_VERSION);
+    result.push(KEY_FILE_KDF_SCRYPT);
+    result.push(KEY_FILE_LOG_N);
+    result.extend_from_slice(&KEY_FILE_R.to_be_bytes());
+    result.extend_from_slice(&KEY_FILE_P.to_be_bytes());
+    result.extend_from_slice(&salt);
+    result.extend_from_slice(&nonce);
+    result.extend_from_slice(&chacha.encrypt(keypair, &nonce));
+    result
+}
+
+/// Decrypts keypair bytes from the encrypted key file contents
+fn decrypt_key_file(data: &[u8], password: &str) -> Option<Vec<u8>> {
+    if data.len() <= KEY_FILE_HEADER_LEN || !data.starts_with(KEY_FILE_MAGIC) {
+        warn!("Unknown key file format!");
+        return None;
+    }
+    let mut pos = KEY_FILE_MAGIC.len();
+    let version = data[pos];
+    let kdf = data[pos + 1];
+    if version != KEY_FILE_VERSION || kdf != KEY_FILE_KDF_SCRYPT {
+        warn!("Unsupported key file version {} or KDF {}!", version, kdf);
+        return None;
+    }
+    pos += 2;
+    let log_n = data[pos];
+    pos += 1;
+    let r = u32::from_be_bytes(data[pos..pos + 4].try_into().unwrap());
+    pos += 4;
+    let p = u32::from_be_bytes(data[pos..pos + 4].try_into().unwrap());
+    pos += 4;
+    let salt = &data[pos..pos + KEY_FILE_SALT_LEN];
+    pos += KEY_FILE_SALT_LEN;
+    let nonce = &data[pos..pos + KEY_FILE_NONCE_LEN];
+    pos += KEY_FILE_NONCE_LEN;
+
+    // We never write stronger params than these, and bigger ones can make us allocate gigabytes
+    if log_n > KEY_FILE_LOG_N || r > KEY_FILE_R || p > KEY_FILE_P {
+        warn!("Too big KDF parameters in key file!");
+        return None;
+    }
+    let params = match scrypt::Params::new(log_n, r, p) {
+        Ok(params) => { params }
+        Err(_) => {
+            warn!("Wrong KDF parameters in key file!");
+            return None;
+        }
+    };
+    let chacha = derive_file_key(password, salt, &params)?;
+    match chacha.try_decrypt(&data[pos..], nonce) {
+        Some(keypair) if keypair.len() == 64 => { Some(keypair) }
+        _ => {
+            warn!("Unable to decrypt key file, wrong password?");
+            None
+        }
+    }
+}
+
+fn derive_file_key(password: &str, salt: &[u8], params: &scrypt::Params) -> Option<Chacha> {
+    let mut key = [0u8; 32];
+    match scrypt::scrypt(password.as_bytes(), salt, params, &mut key) {
+        Ok(_) => { Some(Chacha::new(&key)) }
+        Err(_) => { None }
+    }
+}
+
 fn get_chacha(keypair: &Keypair) -> Chacha {
     let mut digest = Blakeout::new();
     digest.update(&keypair.to_bytes());
@@ -257,6 +372,7 @@ fn get_chacha(keypair: &Keypair) -> Chacha {
 #[cfg(test)]
 mod tests {
     use crate::Keystore;
+    use crate::keys::{encrypt_key_file, decrypt_key_file};
 
     #[test]
     pub fn test_signature() {
@@ -266,4 +382,32 @@ mod tests {
         let signature = keystore.sign(data);
         assert!(Keystore::check(data, &keystore.get_public(), &signature), "Wrong signature!")
     }
-}
\ No newline at end of file
+
+    #[test]
+    pub fn test_key_file_encryption() {
+        let keystore: Keystore = Keystore::new();
+        let bytes = keystore.keypair.to_bytes();
+        let encrypted = encrypt_key_file(&bytes, "secret");
+        assert_ne!(&encrypted[encrypted.len() - 64..], &bytes[..]);
+        assert_eq!(decrypt_key_file(&encrypted, "secret").unwrap(), bytes.to_vec());
+        assert!(decrypt_key_file(&encrypted, "wrong").is_none());
+        assert!(decrypt_key_file(&encrypted[..40], "secret").is_none());
+    }
+
+    #[test]
+    pub fn test_key_file_oversized_params() {
+        let keystore: Keystore = Keystore::new();
+        let encrypted = encrypt_key_file(&keystore.keypair.to_bytes(), "secret");
+        // log_n goes after magic, version and kdf
+        let mut oversized = encrypted.clone();
+        oversized[10] = 40;
+        assert!(decrypt_key_file(&oversized, "secret").is_none());
+        // r and p are big-endian u32 after log_n
+        let mut oversized = encrypted.clone();
+        oversized[11..15].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(decrypt_key_file(&oversized, "secret").is_none());
+        let mut oversized = encrypted;
+        oversized[15..19].copy_from_slice(&1024u32.to_be_bytes());
+        assert!(decrypt_key_file(&oversized, "secret").is_none());
+    }
+}
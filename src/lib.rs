@@ -26,4 +26,5 @@ pub mod settings;
 pub mod bytes;
 pub mod x_zones;
 pub mod crypto;
+pub mod api;
 
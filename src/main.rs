@@ -4,6 +4,7 @@
 #![windows_subsystem = "windows"]
 
 use std::env;
+use std::fs::{self, File};
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::Duration;
@@ -15,12 +16,16 @@ use simple_logger::SimpleLogger;
 #[cfg(windows)]
 use winapi::um::wincon::{ATTACH_PARENT_PROCESS, AttachConsole, FreeConsole};
 
-use alfis::{Block, Bytes, Chain, Miner, Context, Network, Settings, dns_utils, Keystore, ZONE_DIFFICULTY};
+use alfis::{Block, Bytes, Chain, Miner, Context, Network, Settings, dns_utils, Keystore, api};
+use alfis::commons::lock_file;
 
 #[cfg(feature = "webgui")]
 mod web_ui;
+mod cli;
 
 const SETTINGS_FILENAME: &str = "alfis.toml";
+const LOCK_FILENAME: &str = "alfis.lock";
+const KEY_PASSWORD_ENV: &str = "ALFIS_KEY_PASSWORD";
 const LOG_TARGET_MAIN: &str = "alfis::Main";
 
 fn main() {
@@ -44,7 +49,12 @@ fn main() {
     opts.optflag("l", "list", "List blocks from DB and exit");
     opts.optflag("g", "generate", "Generate new config file. Generated config will be printed to console.");
     opts.optopt("c", "config", "Path to config file", "FILE");
+    opts.optopt("", "data-dir", "Directory for blockchain DB, keys and zones, overrides 'data_dir' in config", "DIR");
     opts.optopt("u", "upgrade", "Path to config file that you want to upgrade. Upgraded config will be printed to console.", "FILE");
+    opts.optopt("", "check", "Check if domain or zone can be mined with current key and exit", "NAME");
+    opts.optopt("", "mine", "Mine or update domain or zone from JSON or TOML record file, exit when it is done", "FILE");
+    opts.optopt("", "export", "Export all blocks to file, one JSON block per line, and exit", "FILE");
+    opts.optopt("", "import", "Import blocks from file made by --export, checking every block, and exit", "FILE");
 
     let opt_matches = match opts.parse(&args[1..]) {
         Ok(m) => m,
@@ -98,10 +108,36 @@ fn main() {
         .unwrap();
     info!(target: LOG_TARGET_MAIN, "Starting ALFIS {}", env!("CARGO_PKG_VERSION"));
 
-    let settings = Settings::load(&config_name).expect(&format!("Cannot load settings from {}!", &config_name));
+    let mut settings = match Settings::load(&config_name) {
+        Some(settings) => settings,
+        None => {
+            error!(target: LOG_TARGET_MAIN, "Cannot load settings from {}!", &config_name);
+            std::process::exit(1);
+        }
+    };
+    if let Some(data_dir) = opt_matches.opt_str("data-dir") {
+        settings.data_dir = data_dir;
+    }
     info!(target: LOG_TARGET_MAIN, "Loaded settings: {:?}", &settings);
-    let keystore = Keystore::from_file(&settings.key_file, "");
-    let chain: Chain = Chain::new(&settings);
+    let _lock = match open_data_dir(&settings) {
+        Ok(lock) => lock,
+        Err(e) => {
+            error!(target: LOG_TARGET_MAIN, "{}", e);
+            std::process::exit(1);
+        }
+    };
+    let key_file = settings.get_path(&settings.key_file).to_string_lossy().to_string();
+    let password = env::var(KEY_PASSWORD_ENV).unwrap_or_default();
+    if password.is_empty() && Keystore::is_encrypted(&key_file) {
+        warn!(target: LOG_TARGET_MAIN, "Key file {} is encrypted, set its password in {} environment variable", &key_file, KEY_PASSWORD_ENV);
+    }
+    let mut chain: Chain = match Chain::new(&settings) {
+        Ok(chain) => chain,
+        Err(e) => {
+            error!(target: LOG_TARGET_MAIN, "{}", e);
+            std::process::exit(1);
+        }
+    };
     if opt_matches.opt_present("l") {
         for i in 1..(chain.height() + 1) {
             if let Some(block) = chain.get_block(i) {
@@ -110,24 +146,50 @@ fn main() {
         }
         return;
     }
+    if let Some(file) = opt_matches.opt_str("export") {
+        std::process::exit(cli::export(&chain, &file));
+    }
+    if let Some(file) = opt_matches.opt_str("import") {
+        std::process::exit(cli::import(&mut chain, &file));
+    }
 
     match chain.get_block(1) {
         None => { info!(target: LOG_TARGET_MAIN, "No blocks found in DB"); }
         Some(block) => { trace!(target: LOG_TARGET_MAIN, "Loaded DB with origin {:?}", &block.hash); }
     }
+    let keystore = Keystore::from_file(&key_file, &password, chain.get_consensus().keystore_difficulty);
     let settings_copy = settings.clone();
     let context = Context::new(env!("CARGO_PKG_VERSION").to_owned(), settings, keystore, chain);
     let context: Arc<Mutex<Context>> = Arc::new(Mutex::new(context));
-    dns_utils::start_dns_server(&context, &settings_copy);
+    if let Some(name) = opt_matches.opt_str("check") {
+        std::process::exit(cli::check(&context, &name));
+    }
+    let mine_file = opt_matches.opt_str("mine");
+    // One-shot mining doesn't need DNS, and it would conflict with running instance
+    if mine_file.is_none() {
+        dns_utils::start_dns_server(&context, &settings_copy);
+    }
 
     let mut miner_obj = Miner::new(Arc::clone(&context));
     miner_obj.start_mining_thread();
     let miner: Arc<Mutex<Miner>> = Arc::new(Mutex::new(miner_obj));
 
     let mut network = Network::new(Arc::clone(&context));
-    network.start().expect("Error starting network component");
+    if let Err(e) = network.start() {
+        error!(target: LOG_TARGET_MAIN, "Error starting network component: {}", e);
+        std::process::exit(1);
+    }
+
+    if settings_copy.api.enabled && mine_file.is_none() {
+        if let Err(e) = api::start_api_server(Arc::clone(&context), Arc::clone(&miner)) {
+            error!(target: LOG_TARGET_MAIN, "{}", e);
+        }
+    }
 
     create_genesis_if_needed(&context, &miner);
+    if let Some(file) = mine_file {
+        std::process::exit(cli::mine(&context, &miner, &file));
+    }
     if no_gui {
         let sleep = Duration::from_millis(1000);
         loop {
@@ -145,6 +207,14 @@ fn main() {
     }
 }
 
+/// Creates data directory if needed and locks it, so that other instances can't use it
+fn open_data_dir(settings: &Settings) -> Result<File, String> {
+    if !settings.data_dir.is_empty() {
+        fs::create_dir_all(&settings.data_dir).map_err(|e| format!("Unable to create data directory {}: {}", &settings.data_dir, e))?;
+    }
+    lock_file(&settings.get_path(LOCK_FILENAME))
+}
+
 fn create_genesis_if_needed(context: &Arc<Mutex<Context>>, miner: &Arc<Mutex<Miner>>) {
     // If there is no origin in settings and no blockchain in DB, generate genesis block
     let context = context.lock().unwrap();
@@ -153,7 +223,8 @@ fn create_genesis_if_needed(context: &Arc<Mutex<Context>>, miner: &Arc<Mutex<Min
     if origin.is_empty() && last_block.is_none() {
         if let Some(keystore) = &context.keystore {
             // If blockchain is empty, we are going to mine a Genesis block
-            let block = Block::new(None, context.get_keystore().unwrap().get_public(), Bytes::default(), ZONE_DIFFICULTY);
+            let difficulty = context.chain.get_consensus().zone_difficulty;
+            let block = Block::new(None, context.get_keystore().unwrap().get_public(), Bytes::default(), difficulty);
             miner.lock().unwrap().add_block(block, keystore.clone());
         }
     }
@@ -8,13 +8,16 @@ use chrono::Utc;
 use log::{debug, error, info, trace, warn};
 use num_cpus;
 
-use crate::{Block, Bytes, Context, Keystore, setup_miner_thread};
-use crate::commons::{CHAIN_VERSION, LOCKER_DIFFICULTY, KEYSTORE_DIFFICULTY};
-use crate::blockchain::types::BlockQuality;
+use crate::{Block, Bytes, Context, Keystore, Transaction, setup_miner_thread, check_domain, get_domain_zone};
+use crate::commons::{CHAIN_VERSION, ZONE_MAX_LENGTH, CLASS_DOMAIN, CLASS_ZONE};
+use crate::blockchain::transaction::{ContactsData, DomainData, ZoneData};
+use crate::dns::protocol::DnsRecord;
+use crate::blockchain::types::{BlockQuality, MineResult, Ownership};
 use crate::blockchain::hash_utils::*;
 use crate::keys::check_public_key_strength;
 use crate::event::Event;
 use blakeout::Blakeout;
+use serde::Deserialize;
 use std::ops::Deref;
 
 pub struct Miner {
@@ -76,6 +79,7 @@ impl Miner {
         let mining = self.mining.clone();
         let blocks = self.jobs.clone();
         let cond_var = self.cond_var.clone();
+        let locker_difficulty = self.context.lock().unwrap().chain.get_consensus().locker_difficulty;
         self.context.lock().unwrap().bus.register(move |_uuid, e| {
             match e {
                 Event::NewBlockReceived => {}
@@ -85,7 +89,7 @@ impl Miner {
                 }
                 Event::ActionMineLocker { index, hash, keystore } => {
                     if !mining.load(Ordering::SeqCst) {
-                        let mut block = Block::new(None, Bytes::default(), hash, LOCKER_DIFFICULTY);
+                        let mut block = Block::new(None, Bytes::default(), hash, locker_difficulty);
                         block.index = index;
                         blocks.lock().unwrap().push(MineJob { block, keystore: keystore.deref().clone() });
                         cond_var.notify_all();
@@ -102,6 +106,16 @@ impl Miner {
         self.running.load(Ordering::Relaxed)
     }
 
+    /// Returns true if some block is being mined right now
+    pub fn is_busy(&self) -> bool {
+        self.mining.load(Ordering::Relaxed)
+    }
+
+    /// Returns the count of jobs waiting in queue
+    pub fn jobs_count(&self) -> usize {
+        self.jobs.lock().unwrap().len()
+    }
+
     fn mine_internal(context: Arc<Mutex<Context>>, mut job: MineJob, mining: Arc<AtomicBool>) {
         // Clear signature and hash just in case
         job.block.signature = Bytes::default();
@@ -111,7 +125,8 @@ impl Miner {
         if job.block.index > 0 && !job.block.prev_block_hash.is_empty() {
             info!("Mining locker block");
             job.block.pub_key = job.keystore.get_public();
-            if !check_public_key_strength(&job.block.pub_key, KEYSTORE_DIFFICULTY) {
+            let strength = context.lock().unwrap().chain.get_consensus().keystore_difficulty;
+            if !check_public_key_strength(&job.block.pub_key, strength) {
                 warn!("Can not mine block with weak public key!");
                 context.lock().unwrap().bus.post(Event::MinerStopped { success: false, full: false });
                 mining.store(false, Ordering::SeqCst);
@@ -184,8 +199,13 @@ impl Miner {
                             if block.index == 1 {
                                 context.settings.origin = block.hash.to_string();
                             }
-                            context.chain.add_block(block);
-                            success = true;
+                            match context.chain.add_block(block) {
+                                Ok(_) => {
+                                    success = true;
+                                    context.bus.post(Event::BlockchainChanged { index });
+                                }
+                                Err(e) => error!("Error saving mined block: {}", e)
+                            }
                         }
                         context.bus.post(Event::MinerStopped { success, full });
                         mining.store(false, Ordering::SeqCst);
@@ -203,6 +223,177 @@ pub struct MineJob {
     keystore: Keystore
 }
 
+/// Domain or zone description, as it comes from record files or API
+#[derive(Debug, Deserialize)]
+pub struct MineRequest {
+    #[serde(default = "default_class")]
+    pub class: String,
+    pub name: String,
+    #[serde(default)]
+    pub records: Vec<DnsRecord>,
+    #[serde(default)]
+    pub contacts: Vec<ContactsData>,
+    #[serde(default)]
+    pub owners: Vec<Bytes>,
+    #[serde(default)]
+    pub difficulty: u32,
+    #[serde(default)]
+    pub yggdrasil: bool
+}
+
+fn default_class() -> String {
+    String::from(CLASS_DOMAIN)
+}
+
+/// Converts request to domain or zone data and adds a job for mining it
+pub fn mine_request(context: &Arc<Mutex<Context>>, miner: &Arc<Mutex<Miner>>, request: MineRequest) -> MineResult {
+    let name = request.name.to_lowercase();
+    match request.class.as_str() {
+        CLASS_DOMAIN => {
+            let data = DomainData::new(Bytes::default(), get_domain_zone(&name), request.records, request.contacts, request.owners);
+            mine_domain(context, miner, &name, data)
+        }
+        CLASS_ZONE => {
+            let min_difficulty = context.lock().unwrap().chain.get_consensus().zone_min_difficulty;
+            let difficulty = if request.difficulty == 0 { min_difficulty } else { request.difficulty };
+            let data = ZoneData { name: name.clone(), difficulty, yggdrasil: request.yggdrasil, owners: request.owners };
+            mine_zone(context, miner, &name, data)
+        }
+        _ => MineResult::WrongData
+    }
+}
+
+/// Checks domain data and adds a job for mining (or updating) this domain with current keystore.
+/// Empty owners list keeps current co-owners of existing domain.
+pub fn mine_domain(context: &Arc<Mutex<Context>>, miner: &Arc<Mutex<Miner>>, name: &str, mut data: DomainData) -> MineResult {
+    let name = name.to_lowercase();
+    let (keystore, owner, difficulty) = {
+        let context = context.lock().unwrap();
+        let keystore = match context.get_keystore() {
+            None => return MineResult::WrongKey,
+            Some(keystore) => keystore
+        };
+        match context.chain.can_mine_domain(&name, &keystore.get_public()) {
+            MineResult::Fine => {}
+            result => return result
+        }
+        let zone = get_domain_zone(&name);
+        if data.zone != zone {
+            return MineResult::WrongData;
+        }
+        // The name is encrypted with a nonce from the last block, so `can_mine_domain` guarantees that it exists
+        let last_block = context.chain.last_block().unwrap();
+        data.domain = keystore.encrypt(name.as_bytes(), &last_block.hash.as_slice()[..12]);
+        let owner = keep_ownership(context.chain.get_ownership(&name, false), &keystore.get_public(), &mut data.owners);
+        (keystore, owner, context.chain.get_zone_difficulty(&zone))
+    };
+    let data = serde_json::to_string(&data).unwrap();
+    let transaction = Transaction::from_str(name, CLASS_DOMAIN.to_owned(), data, owner);
+    add_transaction(miner, transaction, difficulty, &keystore);
+    MineResult::Fine
+}
+
+/// Checks zone data and adds a job for mining (or updating) this zone with current keystore.
+/// Empty owners list keeps current co-owners of existing zone.
+pub fn mine_zone(context: &Arc<Mutex<Context>>, miner: &Arc<Mutex<Miner>>, name: &str, mut data: ZoneData) -> MineResult {
+    let name = name.to_lowercase();
+    if name.len() > ZONE_MAX_LENGTH || !check_domain(&name, false) || context.lock().unwrap().x_zones.has_zone(&name) {
+        return MineResult::WrongName;
+    }
+    let (keystore, ownership, consensus) = {
+        let context = context.lock().unwrap();
+        (context.get_keystore(), context.chain.get_ownership(&name, true), *context.chain.get_consensus())
+    };
+    if data.difficulty < consensus.zone_min_difficulty || data.name != name {
+        return MineResult::WrongData;
+    }
+    let keystore = match keystore {
+        None => return MineResult::WrongKey,
+        Some(keystore) => keystore
+    };
+    if let Some(ownership) = &ownership {
+        if !ownership.is_owner(&keystore.get_public()) {
+            return MineResult::NotOwned;
+        }
+    }
+    let owner = keep_ownership(ownership, &keystore.get_public(), &mut data.owners);
+    if data.owners.is_empty() {
+        data.owners = vec!(keystore.get_public());
+    }
+    let data = serde_json::to_string(&data).unwrap();
+    let transaction = Transaction::from_str(name, CLASS_ZONE.to_owned(), data, owner);
+    add_transaction(miner, transaction, consensus.zone_difficulty, &keystore);
+    MineResult::Fine
+}
+
+/// Gets the key that has to be the owner in new transaction, and fixes co-owners list.
+/// Co-owners can change only records, so we keep the main owner and the co-owners as they are.
+fn keep_ownership(ownership: Option<Ownership>, pub_key: &Bytes, owners: &mut Vec<Bytes>) -> Bytes {
+    match ownership {
+        Some(ownership) if ownership.is_owner(pub_key) => {
+            if ownership.pub_key != *pub_key || owners.is_empty() {
+                *owners = ownership.owners;
+            }
+            ownership.pub_key
+        }
+        _ => pub_key.clone()
+    }
+}
+
+/// Adds a job for mining a block that transfers domain from current keystore to a new owner
+pub fn transfer_domain(context: &Arc<Mutex<Context>>, miner: &Arc<Mutex<Miner>>, name: &str, owner: &Bytes) -> MineResult {
+    let name = name.to_lowercase();
+    let (keystore, transaction, difficulty, strength) = {
+        let context = context.lock().unwrap();
+        let keystore = match context.get_keystore() {
+            None => return MineResult::WrongKey,
+            Some(keystore) => keystore
+        };
+        let transaction = match context.chain.get_domain_transaction(&name) {
+            None => return MineResult::WrongName,
+            Some(transaction) => transaction
+        };
+        (keystore, transaction, context.chain.get_zone_difficulty(&get_domain_zone(&name)), context.chain.get_consensus().keystore_difficulty)
+    };
+    if transaction.pub_key != keystore.get_public() {
+        return MineResult::NotOwned;
+    }
+    if *owner == keystore.get_public() || !check_public_key_strength(owner, strength) {
+        return MineResult::WrongData;
+    }
+    let mut data = match serde_json::from_str::<DomainData>(&transaction.data) {
+        Ok(data) => data,
+        Err(_) => return MineResult::WrongData
+    };
+    // The name was encrypted by the previous owner, new owner won't be able to decrypt it
+    data.domain = Bytes::default();
+    // Co-owners of previous owner are not inherited
+    data.owners.clear();
+    let data = serde_json::to_string(&data).unwrap();
+    info!("Transferring domain {} to {:?}", &name, owner);
+    let transaction = Transaction::from_str(name, CLASS_DOMAIN.to_owned(), data, owner.clone());
+    add_transaction(miner, transaction, difficulty, &keystore);
+    MineResult::Fine
+}
+
+/// Creates a block with domain (or zone) transaction and adds it to miner's queue
+pub fn create_domain(context: Arc<Mutex<Context>>, miner: Arc<Mutex<Miner>>, class: &str, name: &str, data: &str, difficulty: u32, keystore: &Keystore) {
+    let name = name.to_owned();
+    info!("Generating domain or zone {}", &name);
+    if context.lock().unwrap().x_zones.has_zone(&name) {
+        error!("Unable to mine IANA/OpenNIC/etc zone {}!", &name);
+        return;
+    }
+    let transaction = Transaction::from_str(name, class.to_owned(), data.to_owned(), keystore.get_public());
+    add_transaction(&miner, transaction, difficulty, keystore);
+}
+
+/// Creates a block with this transaction, signed by keystore, and adds it to miner's queue
+fn add_transaction(miner: &Arc<Mutex<Miner>>, transaction: Transaction, difficulty: u32, keystore: &Keystore) {
+    let block = Block::new(Some(transaction), keystore.get_public(), Bytes::default(), difficulty);
+    miner.lock().unwrap().add_block(block, keystore.clone());
+}
+
 fn find_hash(context: Arc<Mutex<Context>>, mut block: Block, running: Arc<AtomicBool>, thread: usize) -> Option<Block> {
     let difficulty = block.difficulty;
     let full = block.transaction.is_some();
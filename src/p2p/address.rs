@@ -0,0 +1,115 @@
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+/// Address of a peer, hostnames (like `.onion` ones) are resolved by proxy and never by us
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum PeerAddr {
+    Ip(SocketAddr),
+    Host { host: String, port: u16 }
+}
+
+impl PeerAddr {
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self {
+            PeerAddr::Ip(addr) => Some(addr.ip()),
+            PeerAddr::Host { .. } => None
+        }
+    }
+
+    pub fn port(&self) -> u16 {
+        match self {
+            PeerAddr::Ip(addr) => addr.port(),
+            PeerAddr::Host { port, .. } => *port
+        }
+    }
+
+    pub fn is_onion(&self) -> bool {
+        match self {
+            PeerAddr::Ip(_) => false,
+            PeerAddr::Host { host, .. } => host.ends_with(".onion")
+        }
+    }
+
+    pub fn is_loopback(&self) -> bool {
+        matches!(self, PeerAddr::Ip(addr) if addr.ip().is_loopback())
+    }
+
+    /// The same address on another port
+    pub fn with_port(&self, port: u16) -> Self {
+        match self {
+            PeerAddr::Ip(addr) => PeerAddr::Ip(SocketAddr::new(addr.ip(), port)),
+            PeerAddr::Host { host, .. } => PeerAddr::Host { host: host.clone(), port }
+        }
+    }
+
+    /// If loopback address then we care about ip and port.
+    /// If regular address then we only care about the ip (or host) and ignore the port.
+    pub fn same_host(&self, other: &PeerAddr) -> bool {
+        match (self, other) {
+            (PeerAddr::Ip(addr), PeerAddr::Ip(other)) if addr.ip().is_loopback() => addr == other,
+            (PeerAddr::Ip(addr), PeerAddr::Ip(other)) => addr.ip() == other.ip(),
+            (PeerAddr::Host { host, .. }, PeerAddr::Host { host: other, .. }) => host.eq_ignore_ascii_case(other),
+            _ => false
+        }
+    }
+}
+
+impl From<SocketAddr> for PeerAddr {
+    fn from(addr: SocketAddr) -> Self {
+        PeerAddr::Ip(addr)
+    }
+}
+
+impl Display for PeerAddr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            PeerAddr::Ip(addr) => write!(f, "{}", addr),
+            PeerAddr::Host { host, port } => write!(f, "{}:{}", host, port)
+        }
+    }
+}
+
+impl FromStr for PeerAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(addr) = s.parse::<SocketAddr>() {
+            return Ok(PeerAddr::Ip(addr));
+        }
+        let (host, port) = match s.rfind(':') {
+            Some(pos) => (&s[..pos], &s[pos + 1..]),
+            None => return Err(format!("Address {} has no port", s))
+        };
+        let port = port.parse::<u16>().map_err(|_| format!("Wrong port in address {}", s))?;
+        let valid = !host.is_empty() && host.len() <= 253 && host
+            .split('.')
+            .all(|label| !label.is_empty() && label.len() <= 63 && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-'));
+        if !valid {
+            return Err(format!("Wrong host in address {}", s));
+        }
+        Ok(PeerAddr::Host { host: host.to_lowercase(), port })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::p2p::PeerAddr;
+
+    #[test]
+    fn parse_addresses() {
+        let onion = "2gzyxa5ihm7nsggfxnu52rck2vv4rvmdlkiu3zzui5du4xyclen53wid.onion:4244";
+        let addr: PeerAddr = onion.parse().unwrap();
+        assert!(addr.is_onion());
+        assert_eq!(addr.port(), 4244);
+        assert_eq!(addr.to_string(), onion);
+        assert!(addr.same_host(&addr.with_port(4245)));
+
+        let addr: PeerAddr = "[200:1234::1]:4244".parse().unwrap();
+        assert!(addr.ip().is_some());
+        assert!(!addr.is_onion());
+        assert!("test.onion".parse::<PeerAddr>().is_err());
+        assert!("bad_host.onion:4244".parse::<PeerAddr>().is_err());
+    }
+}
@@ -0,0 +1,207 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::Path;
+
+use chrono::Utc;
+#[allow(unused_imports)]
+use log::{trace, debug, info, warn, error};
+use sqlite::{Connection, State};
+
+use crate::blockchain::chain::DB_BUSY_TIMEOUT;
+use crate::p2p::PeerAddr;
+
+const SQL_GET_PEERS: &str = "SELECT addr, last_seen, successes, failures, ban_reason, banned_until FROM peers;";
+const SQL_SAVE_PEER: &str = "INSERT OR REPLACE INTO peers (addr, last_seen, score, banned_until, successes, failures, ban_reason) \
+                          VALUES (?, ?, ?, ?, ?, ?, ?);";
+
+/// Peer that we have seen some time, with its statistics
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct KnownPeer {
+    pub last_seen: i64,
+    pub successes: u32,
+    pub failures: u32,
+    pub ban_reason: String,
+    pub banned_until: i64
+}
+
+impl KnownPeer {
+    /// Good peers get higher score, and we connect to them first
+    pub fn score(&self) -> i64 {
+        self.successes as i64 - 2 * self.failures as i64
+    }
+
+    pub fn is_banned(&self, time: i64) -> bool {
+        self.banned_until > time
+    }
+}
+
+/// Known peers saved in the node's DB, so that they survive restarts
+pub struct KnownPeers {
+    db: Option<Connection>,
+    peers: HashMap<PeerAddr, KnownPeer>
+}
+
+impl KnownPeers {
+    /// Opens DB with `peers` table, if it fails we just don't save peers
+    pub fn open(path: &Path) -> Self {
+        let db = match sqlite::open(path) {
+            Ok(mut db) => {
+                let _ = db.set_busy_timeout(DB_BUSY_TIMEOUT);
+                Some(db)
+            }
+            Err(e) => {
+                warn!("Unable to open DB {} for known peers: {}", path.display(), e);
+                None
+            }
+        };
+        Self::with_db(db)
+    }
+
+    fn with_db(db: Option<Connection>) -> Self {
+        let mut known = KnownPeers { db, peers: HashMap::new() };
+        if let Err(e) = known.load() {
+            warn!("Unable to load known peers: {}", e);
+        }
+        debug!("Loaded {} known peers", known.peers.len());
+        known
+    }
+
+    fn load(&mut self) -> sqlite::Result<()> {
+        let db = match &self.db {
+            None => return Ok(()),
+            Some(db) => db
+        };
+        let mut statement = db.prepare(SQL_GET_PEERS)?;
+        while statement.next()? == State::Row {
+            let addr = match statement.read::<String>(0)?.parse::<PeerAddr>() {
+                Ok(addr) => addr,
+                Err(_) => continue
+            };
+            let peer = KnownPeer {
+                last_seen: statement.read::<i64>(1)?,
+                successes: statement.read::<i64>(2)? as u32,
+                failures: statement.read::<i64>(3)? as u32,
+                ban_reason: statement.read::<String>(4)?,
+                banned_until: statement.read::<i64>(5)?
+            };
+            self.peers.insert(addr, peer);
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, addr: &PeerAddr) -> Option<&KnownPeer> {
+        self.peers.get(addr)
+    }
+
+    pub fn get_score(&self, addr: &PeerAddr) -> i64 {
+        self.peers.get(addr).map(KnownPeer::score).unwrap_or(0)
+    }
+
+    /// Returns addresses of peers that we connected to before, the best are first
+    pub fn get_best(&self, count: usize) -> Vec<PeerAddr> {
+        let now = Utc::now().timestamp();
+        let mut peers: Vec<(&PeerAddr, &KnownPeer)> = self.peers
+            .iter()
+            .filter(|(_, peer)| peer.successes > 0 && peer.score() > 0 && !peer.is_banned(now))
+            .collect();
+        peers.sort_by_key(|(_, peer)| -peer.score());
+        peers.into_iter().take(count).map(|(addr, _)| addr.clone()).collect()
+    }
+
+    /// Returns IPs that are banned at the moment, with time until they are banned
+    pub fn get_banned(&self) -> Vec<(IpAddr, i64)> {
+        let now = Utc::now().timestamp();
+        self.peers
+            .iter()
+            .filter(|(_, peer)| peer.is_banned(now))
+            .filter_map(|(addr, peer)| addr.ip().map(|ip| (ip, peer.banned_until)))
+            .collect()
+    }
+
+    pub fn is_banned(&self, addr: &PeerAddr) -> bool {
+        let now = Utc::now().timestamp();
+        self.peers.get(addr).map(|peer| peer.is_banned(now)).unwrap_or(false)
+    }
+
+    pub fn add_success(&mut self, addr: &PeerAddr) {
+        let peer = self.peers.entry(addr.clone()).or_default();
+        peer.successes += 1;
+        peer.last_seen = Utc::now().timestamp();
+        self.save(addr);
+    }
+
+    pub fn add_failure(&mut self, addr: &PeerAddr) {
+        let peer = self.peers.entry(addr.clone()).or_default();
+        peer.failures += 1;
+        self.save(addr);
+    }
+
+    pub fn ban(&mut self, addr: &PeerAddr, reason: &str, until: i64) {
+        let peer = self.peers.entry(addr.clone()).or_default();
+        peer.ban_reason = reason.to_owned();
+        peer.banned_until = until;
+        self.save(addr);
+    }
+
+    fn save(&self, addr: &PeerAddr) {
+        if let (Some(db), Some(peer)) = (&self.db, self.peers.get(addr)) {
+            if let Err(e) = save_peer(db, addr, peer) {
+                warn!("Error saving peer {}: {}", addr, e);
+            }
+        }
+    }
+}
+
+fn save_peer(db: &Connection, addr: &PeerAddr, peer: &KnownPeer) -> sqlite::Result<()> {
+    let mut statement = db.prepare(SQL_SAVE_PEER)?;
+    statement.bind(1, addr.to_string().as_str())?;
+    statement.bind(2, peer.last_seen)?;
+    statement.bind(3, peer.score())?;
+    statement.bind(4, peer.banned_until)?;
+    statement.bind(5, peer.successes as i64)?;
+    statement.bind(6, peer.failures as i64)?;
+    statement.bind(7, peer.ban_reason.as_str())?;
+    statement.next()?;
+    Ok(())
+}
+
+#[cfg(test)]
+pub mod tests {
+    use chrono::Utc;
+
+    use crate::blockchain::migrations::migrate;
+    use crate::commons::DB_VERSION;
+    use crate::p2p::known_peers::KnownPeers;
+    use crate::p2p::PeerAddr;
+
+    pub fn memory_known_peers() -> KnownPeers {
+        let db = sqlite::open(":memory:").unwrap();
+        db.execute(include_str!("../blockchain/sql/create_db.sql")).unwrap();
+        migrate(&db, 0, DB_VERSION).unwrap();
+        KnownPeers::with_db(Some(db))
+    }
+
+    #[test]
+    fn known_peers_survive_restart() {
+        let mut known = memory_known_peers();
+
+        let good: PeerAddr = "1.1.1.1:4244".parse().unwrap();
+        let bad: PeerAddr = "2.2.2.2:4244".parse().unwrap();
+        let evil: PeerAddr = "3.3.3.3:4244".parse().unwrap();
+        known.add_success(&good);
+        known.add_success(&good);
+        known.add_success(&bad);
+        known.add_failure(&bad);
+        known.add_success(&evil);
+        let until = Utc::now().timestamp() + 100;
+        known.ban(&evil, "Forbidden zone", until);
+
+        // Reloading everything from DB
+        let known = KnownPeers::with_db(known.db);
+        assert_eq!(known.get(&good).unwrap().successes, 2);
+        assert_eq!(known.get_score(&bad), -1);
+        assert_eq!(known.get(&evil).unwrap().ban_reason, "Forbidden zone");
+        assert_eq!(known.get_best(10), vec![good]);
+        assert_eq!(known.get_banned(), vec![(evil.ip().unwrap(), until)]);
+    }
+}
@@ -2,36 +2,72 @@ extern crate serde;
 extern crate serde_json;
 
 use serde::{Deserialize, Serialize};
-use crate::Bytes;
+use crate::{Block, Bytes};
+use crate::commons::{ENCODING_VERSION, PROTOCOL_VERSION};
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Plain JSON encoding, understood by every node
+pub const ENCODING_JSON: u32 = 0;
+/// CBOR encoding, the packet starts with a byte of encoding version
+pub const ENCODING_CBOR: u32 = 1;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum Message {
     Error,
-    Hand { #[serde(default = "default_version")] app_version: String, origin: String, version: u32, public: bool, #[serde(default)] rand: String },
-    Shake { origin: String, version: u32, ok: bool, height: u64 },
+    Hand { #[serde(default = "default_version")] app_version: String, origin: String, version: u32, public: bool, #[serde(default)] rand: String, #[serde(default)] protocol: u32, #[serde(default)] encoding: u32, #[serde(default, skip_serializing_if = "Option::is_none")] session: Option<SessionKey> },
+    Shake { origin: String, version: u32, ok: bool, height: u64, #[serde(default)] protocol: u32, #[serde(default)] encoding: u32, #[serde(default, skip_serializing_if = "Option::is_none")] session: Option<SessionKey> },
     Ping { height: u64, hash: Bytes },
     Pong { height: u64, hash: Bytes },
     GetPeers,
     Peers { peers: Vec<String> },
     GetBlock { index: u64 },
-    Block { index: u64, block: String },
+    Block { index: u64, #[serde(with = "block_string")] block: Block },
+    GetBlocks { from: u64, count: u64 },
+    Blocks { blocks: Vec<Block> },
+}
+
+/// Public key of x25519 key exchange to encrypt connection, optionally signed by node's ed25519 key
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SessionKey {
+    pub key: Bytes,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub identity: Option<Bytes>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sign: Option<Bytes>
 }
 
 impl Message {
+    /// Decodes message in any known encoding, JSON always starts with `{` or `"`, binary ones with version byte
     pub fn from_bytes(bytes: Vec<u8>) -> Result<Self, ()> {
-        let text = String::from_utf8(bytes).unwrap_or(String::from("Error{}"));
-        match serde_json::from_str(&text) {
-            Ok(cmd) => Ok(cmd),
-            Err(_) => Err(())
+        match bytes.first().map(|b| *b as u32) {
+            Some(ENCODING_CBOR) => serde_cbor::from_slice(&bytes[1..]).map_err(|_| ()),
+            _ => {
+                let text = String::from_utf8(bytes).unwrap_or(String::from("Error{}"));
+                match serde_json::from_str(&text) {
+                    Ok(cmd) => Ok(cmd),
+                    Err(_) => Err(())
+                }
+            }
+        }
+    }
+
+    /// Encodes message with encoding negotiated with peer
+    pub fn to_bytes(&self, encoding: u32) -> Vec<u8> {
+        match encoding {
+            ENCODING_CBOR => {
+                let mut buf = vec![ENCODING_CBOR as u8];
+                serde_cbor::to_writer(&mut buf, &self).unwrap();
+                buf
+            }
+            _ => serde_json::to_vec(&self).unwrap()
         }
     }
 
-    pub fn hand(app_version: &str, origin: &str, version: u32, public: bool, rand: &str) -> Self {
-        Message::Hand { app_version: app_version.to_owned(), origin: origin.to_owned(), version, public, rand: rand.to_owned() }
+    pub fn hand(app_version: &str, origin: &str, version: u32, public: bool, rand: &str, session: Option<SessionKey>) -> Self {
+        Message::Hand { app_version: app_version.to_owned(), origin: origin.to_owned(), version, public, rand: rand.to_owned(), protocol: PROTOCOL_VERSION, encoding: ENCODING_VERSION, session }
     }
 
-    pub fn shake(origin: &str, version: u32, ok: bool, height: u64) -> Self {
-        Message::Shake { origin: origin.to_owned(), version, ok, height }
+    pub fn shake(origin: &str, version: u32, ok: bool, height: u64, session: Option<SessionKey>) -> Self {
+        Message::Shake { origin: origin.to_owned(), version, ok, height, protocol: PROTOCOL_VERSION, encoding: ENCODING_VERSION, session }
     }
 
     pub fn ping(height: u64, hash: Bytes) -> Self {
@@ -42,8 +78,8 @@ impl Message {
         Message::Pong { height, hash }
     }
 
-    pub fn block(height: u64, str: String) -> Self {
-        Message::Block { index: height, block: str }
+    pub fn block(height: u64, block: Block) -> Self {
+        Message::Block { index: height, block }
     }
 }
 
@@ -51,9 +87,37 @@ fn default_version() -> String {
     String::from("0.0.0")
 }
 
+/// In JSON the block is sent as a string for compatibility with older nodes, binary encodings have it as is
+mod block_string {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use serde::de::Error;
+    use crate::Block;
+
+    pub fn serialize<S>(block: &Block, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&serde_json::to_string(block).unwrap())
+        } else {
+            block.serialize(serializer)
+        }
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Block, D::Error> where D: Deserializer<'de> {
+        if deserializer.is_human_readable() {
+            let text = String::deserialize(deserializer)?;
+            serde_json::from_str(&text).map_err(D::Error::custom)
+        } else {
+            Block::deserialize(deserializer)
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use crate::{Block, Bytes, Keystore, Transaction};
+    use crate::blockchain::hash_utils::{blakeout_data, check_block_hash, check_block_signature};
+    use crate::commons::CLASS_DOMAIN;
     use crate::p2p::Message;
+    use crate::p2p::message::{ENCODING_CBOR, ENCODING_JSON};
 
     #[test]
     pub fn test_hand() {
@@ -62,4 +126,47 @@ mod tests {
         assert!(serde_json::from_str::<Message>("{\"Hand\":{\"origin\":\"\",\"version\":1,\"public\":false}}").is_ok());
     }
 
+    #[test]
+    pub fn test_protocol() {
+        // Old nodes don't send protocol version
+        match serde_json::from_str::<Message>("{\"Shake\":{\"origin\":\"\",\"version\":0,\"ok\":true,\"height\":5}}").unwrap() {
+            Message::Shake { protocol, .. } => assert_eq!(protocol, 0),
+            _ => panic!("Wrong message")
+        }
+        let message = serde_json::to_string(&Message::GetBlocks { from: 10, count: 100 }).unwrap();
+        assert!(matches!(serde_json::from_str::<Message>(&message).unwrap(), Message::GetBlocks { from: 10, count: 100 }));
+    }
+
+    #[test]
+    pub fn test_binary_encoding() {
+        let keystore = Keystore::new();
+        let transaction = Transaction::from_str(String::from("test.ygg"), String::from(CLASS_DOMAIN), String::from("{}"), keystore.get_public());
+        let mut block = Block::new(Some(transaction), keystore.get_public(), blakeout_data(b"previous"), 20);
+        block.index = 2;
+        block.timestamp = 1616000000;
+        block.hash = blakeout_data(&block.as_bytes());
+        block.signature = Bytes::from_bytes(&keystore.sign(&block.as_bytes()));
+        assert!(check_block_hash(&block) && check_block_signature(&block));
+
+        for message in vec![Message::block(block.index, block.clone()), Message::Blocks { blocks: vec![block.clone()] }] {
+            let json = message.to_bytes(ENCODING_JSON);
+            let binary = message.to_bytes(ENCODING_CBOR);
+            assert!(binary.len() < json.len());
+            assert_eq!(Message::from_bytes(json).unwrap(), message);
+            let decoded = Message::from_bytes(binary).unwrap();
+            assert_eq!(decoded, message);
+            let decoded = match decoded {
+                Message::Block { block, .. } => block,
+                Message::Blocks { mut blocks } => blocks.remove(0),
+                _ => panic!("Wrong message")
+            };
+            // Hashes and signatures are checked against canonical JSON
+            assert_eq!(decoded.as_bytes(), block.as_bytes());
+            assert!(check_block_hash(&decoded) && check_block_signature(&decoded));
+        }
+
+        // Older nodes send blocks as JSON string
+        let old = format!("{{\"Block\":{{\"index\":2,\"block\":{}}}}}", serde_json::to_string(&serde_json::to_string(&block).unwrap()).unwrap());
+        assert_eq!(Message::from_bytes(old.into_bytes()).unwrap(), Message::block(2, block));
+    }
 }
\ No newline at end of file
@@ -3,10 +3,15 @@ pub mod message;
 pub mod state;
 pub mod peer;
 pub mod peers;
+pub mod known_peers;
+pub mod address;
+pub mod proxy;
 
 pub use network::Network;
-pub use message::Message;
+pub use message::{Message, SessionKey};
 pub use state::State;
-pub use peer::Peer;
+pub use peer::{Peer, PeerInfo};
 pub use peers::Peers;
+pub use known_peers::{KnownPeer, KnownPeers};
+pub use address::PeerAddr;
 
@@ -7,7 +7,7 @@ use std::sync::{Arc, Mutex, MutexGuard};
 use std::time::{Duration, Instant};
 
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
-use mio::{Events, Interest, Poll, Registry, Token};
+use mio::{Events, Interest, Poll, Registry, Token, Waker};
 use mio::event::Event;
 use mio::net::{TcpListener, TcpStream};
 #[allow(unused_imports)]
@@ -15,17 +15,30 @@ use log::{trace, debug, info, warn, error};
 
 use std::net::{SocketAddr, IpAddr, SocketAddrV4, Shutdown};
 use std::collections::HashSet;
-use crate::{Context, Block, p2p::Message, p2p::State, p2p::Peer, p2p::Peers, Bytes, is_yggdrasil};
+use std::cmp::min;
+use crate::{Context, Block, Keystore, p2p::Message, p2p::SessionKey, p2p::State, p2p::Peer, p2p::Peers, p2p::KnownPeers, p2p::PeerAddr, Bytes, is_yggdrasil};
+use crate::crypto::KeyExchange;
+use crate::p2p::message::ENCODING_JSON;
+use crate::blockchain::chain::DB_NAME;
 use crate::blockchain::types::BlockQuality;
-use crate::commons::CHAIN_VERSION;
+use crate::commons::{CHAIN_VERSION, DOMAIN_EXPIRY_WARNING, DOMAIN_GRACE_PERIOD, PROTOCOL_HOSTNAMES};
 use std::sync::atomic::{AtomicBool, Ordering};
 use chrono::Utc;
 
 const SERVER: Token = Token(0);
+/// Token to wake network loop from other threads
+const WAKER: Token = Token(usize::MAX);
 const POLL_TIMEOUT: Option<Duration> = Some(Duration::from_millis(3000));
 pub const LISTEN_PORT: u16 = 4244;
 const MAX_PACKET_SIZE: usize = 1 * 1024 * 1024; // 1 Mb
+/// How many blocks we ask in one GetBlocks request
+pub(crate) const MAX_BLOCKS_BATCH: u64 = 100;
+/// Space in packet that we leave for message itself, not blocks in it
+const BATCH_OVERHEAD: usize = 1024;
 const MAX_READ_BLOCK_TIME: u128 = 500;
+const EXPIRY_CHECK_INTERVAL: u64 = 3600;
+/// How many blocks of forked chain we keep for one peer
+const MAX_FORK_BLOCKS: usize = 1000;
 
 pub struct Network {
     context: Arc<Mutex<Context>>
@@ -37,22 +50,28 @@ impl Network {
     }
 
     pub fn start(&mut self) -> Result<(), String> {
-        let (listen_addr, peers_addrs, yggdrasil_only) = {
+        let (net, db_path) = {
             let c = self.context.lock().unwrap();
-            (c.settings.net.listen.clone(), c.settings.net.peers.clone(), c.settings.net.yggdrasil_only)
+            (c.settings.net.clone(), c.settings.get_path(DB_NAME))
+        };
+        let (listen_addr, yggdrasil_only) = (net.listen.clone(), net.yggdrasil_only);
+        let proxy = match net.proxy.is_empty() {
+            true if net.proxy_only => return Err(String::from("You need to set 'proxy' to use 'proxy_only' mode")),
+            true => None,
+            false => Some(net.proxy.parse::<SocketAddr>().map_err(|_| format!("Error parsing proxy address {}", &net.proxy))?)
         };
-
-        let running = Arc::new(AtomicBool::new(true));
-        subscribe_to_bus(&mut self.context, Arc::clone(&running));
 
         // Starting server socket
-        let addr = listen_addr.parse().expect("Error parsing listen address");
-        let mut server = TcpListener::bind(addr).expect("Can't bind to address");
+        let addr = listen_addr.parse().map_err(|_| format!("Error parsing listen address {}", &listen_addr))?;
+        let mut server = TcpListener::bind(addr).map_err(|e| format!("Can't bind to address {}: {}", &listen_addr, e))?;
         debug!("Started node listener on {}", server.local_addr().unwrap());
 
         let mut events = Events::with_capacity(1024);
-        let mut poll = Poll::new().expect("Unable to create poll");
-        poll.registry().register(&mut server, SERVER, Interest::READABLE).expect("Error registering poll");
+        let mut poll = Poll::new().map_err(|e| format!("Unable to create poll: {}", e))?;
+        poll.registry().register(&mut server, SERVER, Interest::READABLE).map_err(|e| format!("Error registering poll: {}", e))?;
+        let waker = Waker::new(poll.registry(), WAKER).map_err(|e| format!("Error creating waker: {}", e))?;
+        let running = Arc::new(AtomicBool::new(true));
+        subscribe_to_bus(&mut self.context, Arc::clone(&running), waker);
         let context = Arc::clone(&self.context);
         thread::spawn(move || {
             // Give UI some time to appear :)
@@ -60,11 +79,17 @@ impl Network {
             // Unique token for each incoming connection.
             let mut unique_token = Token(SERVER.0 + 1);
             // States of peer connections, and some data to send when sockets become writable
-            let mut peers = Peers::new();
+            let mut peers = Peers::new(KnownPeers::open(&db_path));
+            peers.set_proxy(proxy, net.proxy_only);
+            peers.set_limits(net.max_inbound, net.max_outbound, net.max_per_ip, net.max_messages);
             // Starting peer connections to bootstrap nodes
-            peers.connect_peers(peers_addrs, &poll.registry(), &mut unique_token, yggdrasil_only);
+            peers.connect_peers(net.peers, &poll.registry(), &mut unique_token, yggdrasil_only);
 
             let mut peers_timer = Instant::now();
+            let mut expiry_timer: Option<Instant> = None;
+            // Domains with their expiry times that we have already warned about
+            let mut expiry_warned = HashSet::new();
+            let mut announced = context.lock().unwrap().chain.last_hash();
             loop {
                 // Poll Mio for events, blocking until we get an event.
                 poll.poll(&mut events, POLL_TIMEOUT).expect("Error polling sockets");
@@ -77,6 +102,7 @@ impl Network {
                     trace!("Event for socket {} is {:?}", event.token().0, &event);
                     // We can use the token we previously provided to `register` to determine for which socket the event is.
                     match event.token() {
+                        WAKER => {}
                         SERVER => {
                             debug!("Event for server socket {} is {:?}", event.token().0, &event);
                             // If this is an event for the server, it means a connection is ready to be accepted.
@@ -99,6 +125,12 @@ impl Network {
                                         continue;
                                     }
 
+                                    if !peers.can_accept(&address.ip()) {
+                                        stream.shutdown(Shutdown::Both).unwrap_or_else(|e|{ warn!("Error in shutdown, {}", e); });
+                                        let _ = poll.registry().reregister(&mut server, SERVER, Interest::READABLE);
+                                        continue;
+                                    }
+
                                     // If connection is from the same IP and not from loopback we ignore it to avoid connection loops
                                     let local_ip = stream.local_addr().unwrap_or("0.0.0.0:0".parse().unwrap());
                                     if !local_ip.ip().is_loopback() && local_ip.ip() == address.ip() {
@@ -109,7 +141,7 @@ impl Network {
                                         debug!("Accepted connection from: {} to local IP: {}", address, local_ip);
                                         let token = next(&mut unique_token);
                                         poll.registry().register(&mut stream, token, Interest::READABLE).expect("Error registering poll");
-                                        peers.add_peer(token, Peer::new(address, stream, State::Connected, true));
+                                        peers.add_peer(token, Peer::new(PeerAddr::Ip(address), stream, State::Connected, true));
                                     }
                                 }
                                 Err(_) => {}
@@ -132,6 +164,12 @@ impl Network {
                     }
                 }
                 events.clear();
+                peers.finish_proxy_connections(poll.registry(), &mut unique_token);
+
+                // Announcing new blocks right away, not waiting for pings
+                if let Some(block) = get_block_to_announce(&context, &mut announced) {
+                    peers.announce_block(poll.registry(), block);
+                }
 
                 if peers_timer.elapsed().as_millis() > 500 {
                     // Send pings to idle peers
@@ -142,6 +180,7 @@ impl Network {
                         if nodes > 0 {
                             context.bus.post(crate::event::Event::NetworkStatus { nodes, blocks: height });
                         }
+                        context.peers = peers.get_peers_info();
                         (height, context.chain.last_hash())
                     };
                     mine_signing_block(Arc::clone(&context));
@@ -149,6 +188,14 @@ impl Network {
                     peers.connect_new_peers(poll.registry(), &mut unique_token, yggdrasil_only);
                     peers_timer = Instant::now();
                 }
+
+                let check_expiry = match expiry_timer {
+                    None => true,
+                    Some(timer) => timer.elapsed().as_secs() > EXPIRY_CHECK_INTERVAL
+                };
+                if check_expiry && check_expiring_domains(&context, &mut expiry_warned) {
+                    expiry_timer = Some(Instant::now());
+                }
             }
             info!("Network loop finished");
         });
@@ -156,20 +203,38 @@ impl Network {
     }
 }
 
-fn subscribe_to_bus(context: &mut Arc<Mutex<Context>>, running: Arc<AtomicBool>) {
+fn subscribe_to_bus(context: &mut Arc<Mutex<Context>>, running: Arc<AtomicBool>, waker: Waker) {
     use crate::event::Event;
     context.lock().unwrap().bus.register(move |_uuid, e| {
         match e {
             Event::ActionQuit => {
                 running.store(false, Ordering::SeqCst);
+                let _ = waker.wake();
                 return false;
             }
+            Event::BlockchainChanged { .. } => {
+                let _ = waker.wake();
+            }
             _ => {}
         }
         true
     });
 }
 
+/// Returns our last block if it has changed since last announcement, we don't announce anything while syncing
+fn get_block_to_announce(context: &Arc<Mutex<Context>>, announced: &mut Bytes) -> Option<Block> {
+    let context = context.lock().unwrap();
+    let hash = context.chain.last_hash();
+    if hash == *announced {
+        return None;
+    }
+    *announced = hash;
+    if context.chain.height() < context.chain.max_height() {
+        return None;
+    }
+    context.chain.last_block()
+}
+
 fn handle_connection_event(context: Arc<Mutex<Context>>, peers: &mut Peers, registry: &Registry, event: &Event) -> bool {
     if event.is_error() || (event.is_read_closed() && event.is_write_closed()) {
         return false;
@@ -202,7 +267,7 @@ fn handle_connection_event(context: Arc<Mutex<Context>>, peers: &mut Peers, regi
                     }
                     peer.reset_spurious();
                     let mut stream = peer.get_stream();
-                    read_message(&mut stream)
+                    read_message(&mut stream).and_then(|data| peer.decrypt(data).ok_or(()))
                 }
             }
         };
@@ -211,15 +276,18 @@ fn handle_connection_event(context: Arc<Mutex<Context>>, peers: &mut Peers, regi
             let data = data.unwrap();
             match Message::from_bytes(data) {
                 Ok(message) => {
+                    if !peers.count_message(&event.token(), &message) {
+                        return false;
+                    }
                     let m = format!("{:?}", &message);
                     let new_state = handle_message(Arc::clone(&context), message, peers, &event.token());
                     let peer = peers.get_mut_peer(&event.token()).unwrap();
                     debug!("Got message from {}: {:?}", &peer.get_addr(), &m);
                     let stream = peer.get_stream();
                     match new_state {
-                        State::Message { data } => {
+                        State::Message { message } => {
                             registry.reregister(stream, event.token(), Interest::WRITABLE).unwrap();
-                            peer.set_state(State::Message { data });
+                            peer.set_state(State::Message { message });
                         }
                         State::Connecting => {}
                         State::Connected => {}
@@ -251,28 +319,35 @@ fn handle_connection_event(context: Arc<Mutex<Context>>, peers: &mut Peers, regi
                 match peer.get_state().clone() {
                     State::Connecting => {
                         debug!("Connected to peer {}, sending hello...", &peer.get_addr());
-                        let data: String = {
+                        let key = peer.new_key_exchange();
+                        let data = {
                             let c = context.lock().unwrap();
-                            let message = Message::hand(&c.app_version, &c.settings.origin, CHAIN_VERSION, c.settings.net.public, &my_id);
-                            serde_json::to_string(&message).unwrap()
+                            let session = make_session_key(&c, &key);
+                            let message = Message::hand(&c.app_version, &c.settings.origin, CHAIN_VERSION, c.settings.net.public, &my_id, Some(session));
+                            // We don't know what peer supports yet
+                            message.to_bytes(ENCODING_JSON)
                         };
-                        send_message(peer.get_stream(), &data.into_bytes()).unwrap_or_else(|e| warn!("Error sending hello {}", e));
+                        send_message(peer.get_stream(), &data).unwrap_or_else(|e| warn!("Error sending hello {}", e));
                         //debug!("Sent hello to {}", &peer.get_addr());
                     }
-                    State::Message { data } => {
-                        debug!("Sending data to {}: {}", &peer.get_addr(), &String::from_utf8(data.clone()).unwrap());
+                    State::Message { message } => {
+                        debug!("Sending message to {}: {:?}", &peer.get_addr(), &message);
+                        peer.track_request(&message);
+                        let data = peer.encode(&message);
+                        let data = peer.encrypt(data);
                         send_message(peer.get_stream(), &data).unwrap_or_else(|e| warn!("Error sending message {}", e));
+                        peer.activate_session();
                     }
                     State::Connected => {}
                     State::Idle { from } => {
                         debug!("Odd version of pings :)");
                         if from.elapsed().as_secs() >= 30 {
-                            let data: String = {
+                            let message = {
                                 let c = context.lock().unwrap();
-                                let message = Message::ping(c.chain.height(), c.chain.last_hash());
-                                serde_json::to_string(&message).unwrap()
+                                Message::ping(c.chain.height(), c.chain.last_hash())
                             };
-                            send_message(peer.get_stream(), &data.into_bytes()).unwrap_or_else(|e| warn!("Error sending ping {}", e));
+                            let data = peer.encrypt(peer.encode(&message));
+                            send_message(peer.get_stream(), &data).unwrap_or_else(|e| warn!("Error sending ping {}", e));
                         }
                     }
                     State::Error => {}
@@ -342,13 +417,13 @@ fn send_message(connection: &mut TcpStream, data: &Vec<u8>) -> io::Result<()> {
 }
 
 fn handle_message(context: Arc<Mutex<Context>>, message: Message, peers: &mut Peers, token: &Token) -> State {
-    let (my_height, my_hash, my_origin, my_version) = {
+    let (my_height, my_hash, my_origin, my_version, allow_plaintext) = {
         let context = context.lock().unwrap();
         // TODO cache it somewhere
-        (context.chain.height(), context.chain.last_hash(), &context.settings.origin.clone(), CHAIN_VERSION)
+        (context.chain.height(), context.chain.last_hash(), &context.settings.origin.clone(), CHAIN_VERSION, context.settings.net.allow_plaintext)
     };
     let answer = match message {
-        Message::Hand { app_version, origin, version, public, rand} => {
+        Message::Hand { app_version, origin, version, public, rand, protocol, encoding, session } => {
             debug!("Hello from v{}", &app_version);
             if peers.is_our_own_connect(&rand) {
                 warn!("Detected loop connect");
@@ -357,22 +432,63 @@ fn handle_message(context: Arc<Mutex<Context>>, message: Message, peers: &mut Pe
                 if origin.eq(my_origin) && version == my_version {
                     let peer = peers.get_mut_peer(token).unwrap();
                     peer.set_public(public);
-                    State::message(Message::shake(&origin, version, true, my_height))
+                    peer.set_protocol(protocol);
+                    // Peer reads every encoding it has told us about, even the Shake
+                    peer.set_encoding(encoding);
+                    let session = match session {
+                        Some(session) => {
+                            if !check_session_key(&session) {
+                                warn!("Wrong session key signature from {}", &peer.get_addr());
+                                return State::Banned;
+                            }
+                            let exchange = KeyExchange::new();
+                            match exchange.make_session(&session.key, false) {
+                                None => return State::Banned,
+                                Some(encrypted) => peer.set_pending_session(encrypted)
+                            }
+                            peer.set_identity(session.identity);
+                            Some(make_session_key(&context.lock().unwrap(), &exchange.get_public()))
+                        }
+                        None if !allow_plaintext => {
+                            info!("Peer {} doesn't support encryption, dropping", &peer.get_addr());
+                            return State::Banned;
+                        }
+                        None => None
+                    };
+                    State::message(Message::shake(&origin, version, true, my_height, session))
                 } else {
                     warn!("Handshake from unsupported chain or version");
                     State::Banned
                 }
             }
         }
-        Message::Shake { origin, version, ok, height } => {
+        Message::Shake { origin, version, ok, height, protocol, encoding, session } => {
             if origin.ne(my_origin) || version != my_version {
                 return State::Banned;
             }
+            let peer = peers.get_mut_peer(token).unwrap();
+            match session {
+                Some(session) => {
+                    if !check_session_key(&session) || !peer.start_session(&session.key) {
+                        warn!("Wrong session key from {}", &peer.get_addr());
+                        return State::Banned;
+                    }
+                    peer.set_identity(session.identity);
+                }
+                None if !allow_plaintext => {
+                    info!("Peer {} doesn't support encryption, dropping", &peer.get_addr());
+                    return State::Banned;
+                }
+                None => {}
+            }
             if ok {
+                peers.add_success(token);
                 let active_count = peers.get_peers_active_count();
                 let peer = peers.get_mut_peer(token).unwrap();
                 peer.set_height(height);
                 peer.set_active(true);
+                peer.set_protocol(protocol);
+                peer.set_encoding(encoding);
                 peer.reset_reconnects();
                 let mut context = context.lock().unwrap();
                 let blocks_count = context.chain.height();
@@ -381,7 +497,7 @@ fn handle_message(context: Arc<Mutex<Context>>, message: Message, peers: &mut Pe
                     context.chain.update_max_height(height);
                     context.bus.post(crate::event::Event::Syncing { have: my_height, height});
                     if active_count > 3 {
-                        State::message(Message::GetBlock { index: my_height + 1 })
+                        State::message(peer.blocks_request(my_height + 1))
                     } else {
                         State::message(Message::GetPeers)
                     }
@@ -397,10 +513,13 @@ fn handle_message(context: Arc<Mutex<Context>>, message: Message, peers: &mut Pe
             let peer = peers.get_mut_peer(token).unwrap();
             peer.set_height(height);
             peer.set_active(true);
-            if peer.is_higher(my_height) || ( height == my_height && my_hash != hash) {
+            if peer.is_higher(my_height) {
                 let mut context = context.lock().unwrap();
                 context.chain.update_max_height(height);
-                State::message(Message::GetBlock { index: my_height + 1 })
+                State::message(peer.blocks_request(my_height + 1))
+            } else if height == my_height && my_hash != hash {
+                // Fork of the same height, its last block will show where it starts
+                State::message(Message::GetBlock { index: my_height })
             } else {
                 State::message(Message::pong(my_height, my_hash))
             }
@@ -417,7 +536,8 @@ fn handle_message(context: Arc<Mutex<Context>>, message: Message, peers: &mut Pe
 
             if is_higher {
                 context.chain.update_max_height(height);
-                State::message(Message::GetBlock { index: my_height + 1 })
+                let peer = peers.get_peer(token).unwrap();
+                State::message(peer.blocks_request(my_height + 1))
             } else if my_hash != hash {
                 State::message(Message::GetBlock { index: my_height })
             } else {
@@ -426,7 +546,8 @@ fn handle_message(context: Arc<Mutex<Context>>, message: Message, peers: &mut Pe
         }
         Message::GetPeers => {
             let peer = peers.get_peer(token).unwrap();
-            State::message(Message::Peers { peers: peers.get_peers_for_exchange(&peer.get_addr()) })
+            let with_hosts = peer.get_protocol() >= PROTOCOL_HOSTNAMES;
+            State::message(Message::Peers { peers: peers.get_peers_for_exchange(&peer.get_addr(), with_hosts) })
         }
         Message::Peers { peers: new_peers } => {
             peers.add_peers_from_exchange(new_peers);
@@ -435,58 +556,89 @@ fn handle_message(context: Arc<Mutex<Context>>, message: Message, peers: &mut Pe
         Message::GetBlock { index } => {
             let context = context.lock().unwrap();
             match context.chain.get_block(index) {
-                Some(block) => State::message(Message::block(block.index, serde_json::to_string(&block).unwrap())),
+                Some(block) => State::message(Message::block(block.index, block)),
                 None => State::Error
             }
         }
         Message::Block { index, block } => {
             debug!("Received block {}", index);
-            let block: Block = match serde_json::from_str(&block) {
-                Ok(block) => block,
-                Err(_) => return State::Error
-            };
             let peer = peers.get_mut_peer(token).unwrap();
             peer.set_received_block(block.index);
+            // This peer has the block, we won't announce it back
+            if peer.is_lower(block.index) {
+                peer.set_height(block.index);
+            }
             if let Some(transaction) = &block.transaction {
                 if context.lock().unwrap().x_zones.has_hash(&transaction.identity.to_string()) {
                     // This peer has mined some of the forbidden zones
                     return State::Banned;
                 }
             }
-            let context = Arc::clone(&context);
             let peers_count = peers.get_peers_active_count();
+            let peer = peers.get_mut_peer(token).unwrap();
+            if peer.has_fork() || context.lock().unwrap().chain.is_fork(&block) {
+                return deal_with_fork(&context, peer, vec![block], peers_count);
+            }
+            let context = Arc::clone(&context);
             let _ = thread::Builder::new().name(String::from("Message::Block")).spawn(move || {
-                let mut context = context.lock().unwrap();
-                let max_height = context.chain.max_height();
-                match context.chain.check_new_block(&block) {
-                    BlockQuality::Good => {
-                        context.chain.add_block(block);
-                        let my_height = context.chain.height();
-                        context.bus.post(crate::event::Event::BlockchainChanged { index: my_height });
-                        // If it was the last block to sync
-                        if my_height == max_height {
-                            context.bus.post(crate::event::Event::SyncFinished);
-                        } else {
-                            context.bus.post(crate::event::Event::Syncing { have: my_height, height: max_height});
+                process_new_blocks(context, vec![block], peers_count);
+            });
+            State::idle()
+        }
+        Message::GetBlocks { from, count } => {
+            let context = context.lock().unwrap();
+            let count = min(count, MAX_BLOCKS_BATCH);
+            let mut blocks = Vec::new();
+            let mut size = 0;
+            for index in from..from.saturating_add(count) {
+                match context.chain.get_block(index) {
+                    Some(block) => {
+                        // We must fit in one packet, all blocks are counted with commas between them
+                        size += serde_json::to_vec(&block).unwrap().len() + 1;
+                        if size > MAX_PACKET_SIZE - BATCH_OVERHEAD {
+                            break;
                         }
-                        context.bus.post(crate::event::Event::NetworkStatus { nodes: peers_count, blocks: my_height });
-                    }
-                    BlockQuality::Twin => { debug!("Ignoring duplicate block {}", block.index); }
-                    BlockQuality::Future => { debug!("Ignoring future block {}", block.index); }
-                    BlockQuality::Bad => {
-                        // TODO save bad public keys to banned table
-                        debug!("Ignoring bad block {} with hash {:?}", block.index, block.hash);
+                        blocks.push(block);
                     }
-                    BlockQuality::Fork => {
-                        debug!("Got forked block {} with hash {:?}", block.index, block.hash);
-                        let last_block = context.chain.last_block().unwrap();
-                        if block.is_better_than(&last_block) {
-                            context.chain.replace_block(block.index, block).expect("Error replacing block with fork");
+                    None => break
+                }
+            }
+            match blocks.is_empty() {
+                true => State::Error,
+                false => State::message(Message::Blocks { blocks })
+            }
+        }
+        Message::Blocks { blocks } => {
+            debug!("Received {} blocks", blocks.len());
+            let last = match blocks.last() {
+                None => return State::idle(),
+                Some(block) => block.index
+            };
+            let peer = peers.get_mut_peer(token).unwrap();
+            peer.set_received_block(last);
+            {
+                let context = context.lock().unwrap();
+                for block in blocks.iter() {
+                    if let Some(transaction) = &block.transaction {
+                        if context.x_zones.has_hash(&transaction.identity.to_string()) {
+                            // This peer has mined some of the forbidden zones
+                            return State::Banned;
                         }
-                        //let peer = peers.get_mut_peer(token).unwrap();
-                        //deal_with_fork(context, peer, block);
                     }
                 }
+            }
+            let peers_count = peers.get_peers_active_count();
+            let peer = peers.get_mut_peer(token).unwrap();
+            let fork = peer.has_fork() || {
+                let context = context.lock().unwrap();
+                blocks.iter().any(|block| context.chain.is_fork(block))
+            };
+            if fork {
+                return deal_with_fork(&context, peer, blocks, peers_count);
+            }
+            let context = Arc::clone(&context);
+            let _ = thread::Builder::new().name(String::from("Message::Blocks")).spawn(move || {
+                process_new_blocks(context, blocks, peers_count);
             });
             State::idle()
         }
@@ -494,6 +646,100 @@ fn handle_message(context: Arc<Mutex<Context>>, message: Message, peers: &mut Pe
     answer
 }
 
+/// Makes our part of key exchange for Hand or Shake, signed by our node key if needed
+fn make_session_key(context: &Context, key: &[u8; 32]) -> SessionKey {
+    let (identity, sign) = match (&context.keystore, context.settings.net.bind_identity) {
+        (Some(keystore), true) => (Some(keystore.get_public()), Some(Bytes::from_bytes(&keystore.sign(key)))),
+        _ => (None, None)
+    };
+    SessionKey { key: Bytes::from_bytes(key), identity, sign }
+}
+
+/// Checks signature of session key if it is signed by node key
+fn check_session_key(session: &SessionKey) -> bool {
+    match (&session.identity, &session.sign) {
+        (None, None) => true,
+        (Some(identity), Some(sign)) => Keystore::check(&session.key, identity, sign),
+        _ => false
+    }
+}
+
+/// Checks and adds blocks that we got from some peer, stops on first block that we can't add
+fn process_new_blocks(context: Arc<Mutex<Context>>, blocks: Vec<Block>, peers_count: usize) {
+    let mut context = context.lock().unwrap();
+    let max_height = context.chain.max_height();
+    let mut added = false;
+    for block in blocks {
+        match context.chain.check_new_block(&block) {
+            BlockQuality::Good => {
+                if let Err(e) = context.chain.add_block(block) {
+                    error!("{}", e);
+                    break;
+                }
+                added = true;
+            }
+            BlockQuality::Twin => { debug!("Ignoring duplicate block {}", block.index); }
+            BlockQuality::Future => {
+                debug!("Ignoring future block {}", block.index);
+                break;
+            }
+            BlockQuality::Bad => {
+                // TODO save bad public keys to banned table
+                debug!("Ignoring bad block {} with hash {:?}", block.index, block.hash);
+                break;
+            }
+            BlockQuality::Fork => {
+                // Our chain has changed while these blocks were in flight, next pings will show the fork
+                debug!("Got forked block {} with hash {:?}", block.index, block.hash);
+                break;
+            }
+        }
+    }
+    if added {
+        post_chain_changed(&mut context, max_height, peers_count);
+    }
+}
+
+/// Tells everyone that our blockchain has changed
+fn post_chain_changed(context: &mut MutexGuard<Context>, max_height: u64, peers_count: usize) {
+    let my_height = context.chain.height();
+    context.bus.post(crate::event::Event::BlockchainChanged { index: my_height });
+    // If it was the last block to sync
+    if my_height >= max_height {
+        context.bus.post(crate::event::Event::SyncFinished);
+    } else {
+        context.bus.post(crate::event::Event::Syncing { have: my_height, height: max_height });
+    }
+    context.bus.post(crate::event::Event::NetworkStatus { nodes: peers_count, blocks: my_height });
+}
+
+/// Warns about our domains that expire soon, returns false if blockchain is not synced to check it.
+/// Every domain is warned about once, until it gets renewed and its expiry time changes.
+fn check_expiring_domains(context: &Arc<Mutex<Context>>, warned: &mut HashSet<(Bytes, i64)>) -> bool {
+    let mut context = context.lock().unwrap();
+    if context.chain.height() < context.chain.max_height() {
+        return false;
+    }
+    let keystore = match context.get_keystore() {
+        None => return true,
+        Some(keystore) => keystore
+    };
+    let now = Utc::now().timestamp();
+    for domain in context.chain.get_owned_domains(&keystore) {
+        // After the grace period it is not ours anymore
+        if domain.expires - now > DOMAIN_EXPIRY_WARNING || domain.expires + DOMAIN_GRACE_PERIOD < now {
+            continue;
+        }
+        if !warned.insert((domain.identity.clone(), domain.expires)) {
+            continue;
+        }
+        let name = if domain.name.is_empty() { domain.identity.to_string() } else { domain.name };
+        warn!("Domain {} expires in {} days", &name, (domain.expires - now) / 86400);
+        context.bus.post(crate::event::Event::DomainExpiring { name, expires: domain.expires });
+    }
+    true
+}
+
 /// Sends an Event to miner to start mining locker block if "locker" is our public key
 fn mine_signing_block(context: Arc<Mutex<Context>>) {
     let mut context = context.lock().unwrap();
@@ -518,47 +764,62 @@ fn mine_signing_block(context: Arc<Mutex<Context>>) {
     }
 }
 
-#[allow(dead_code)]
-fn deal_with_fork(context: MutexGuard<Context>, peer: &mut Peer, block: Block) {
-    peer.add_fork_block(block);
-    let mut vector: Vec<&Block> = peer.get_fork().values().collect();
-    vector.sort_by(|a, b| a.index.cmp(&b.index));
-    if vector[0].index == 0 {
-        return;
-    }
-    if let Some(prev_block) = context.chain.get_block(vector[0].index - 1) {
-        // If this block is not root of the fork (we need to go ~deeper~ more backwards)
-        if vector[0].prev_block_hash != prev_block.hash {
-            return;
-        }
-        // Okay, prev_block is the common root for our chain and the fork
-        let mut check_ok = true;
-        vector.insert(0, &prev_block);
-        let mut prev_block = &vector[0];
-        for block in &vector {
-            if block == prev_block {
-                continue;
-            }
-            if !check_block(block, prev_block) {
-                check_ok = false;
-                break;
-            }
-            prev_block = block;
-        }
-        match check_ok {
-            true => {
-                // TODO count fork chain "work" and decide which chain is "better"
-            }
-            false => {
-                warn!("Fork chain is wrong!");
-                peer.set_state(State::Banned);
+/// Collects blocks of forked chain from the peer, going back to the common block and then forward to the top.
+/// When all blocks are here we switch to the fork if it has more work than our chain.
+fn deal_with_fork(context: &Arc<Mutex<Context>>, peer: &mut Peer, blocks: Vec<Block>, peers_count: usize) -> State {
+    {
+        let context = context.lock().unwrap();
+        for block in blocks {
+            // Blocks that we already have are common for both chains
+            if let Some(my_block) = context.chain.get_block(block.index) {
+                if my_block.hash == block.hash {
+                    continue;
+                }
             }
+            peer.add_fork_block(block);
         }
+    }
+    if peer.get_fork().len() > MAX_FORK_BLOCKS {
+        warn!("Fork from {} is too long, ignoring it", peer.get_addr());
+        peer.take_fork();
+        return State::idle();
+    }
+    let (first, last) = match (peer.get_fork().keys().min(), peer.get_fork().keys().max()) {
+        (Some(first), Some(last)) => (*first, *last),
+        _ => return State::idle()
     };
-}
+    if first <= 1 {
+        warn!("Fork from {} has different genesis block!", peer.get_addr());
+        peer.take_fork();
+        return State::Banned;
+    }
+
+    let prev_hash = peer.get_fork().get(&first).unwrap().prev_block_hash.clone();
+    let common = match context.lock().unwrap().chain.get_block(first - 1) {
+        None => false,
+        Some(block) => block.hash == prev_hash
+    };
+    // If this block is not root of the fork (we need to go ~deeper~ more backwards)
+    if !common {
+        return State::message(peer.prev_blocks_request(first));
+    }
+    // Okay, we have the common block, now we need all blocks of the fork up to the top
+    if last < peer.get_height() {
+        return State::message(peer.blocks_request(last + 1));
+    }
 
-fn check_block(block: &Block, prev: &Block) -> bool {
-    prev.index == block.index - 1 && prev.hash == block.prev_block_hash
+    let blocks = peer.take_fork();
+    let context = Arc::clone(context);
+    let _ = thread::Builder::new().name(String::from("Fork")).spawn(move || {
+        let mut context = context.lock().unwrap();
+        let max_height = context.chain.max_height();
+        match context.chain.apply_fork(blocks) {
+            Ok(true) => post_chain_changed(&mut context, max_height, peers_count),
+            Ok(false) => debug!("Our chain is better than the fork"),
+            Err(e) => warn!("Fork chain is wrong: {}", e)
+        }
+    });
+    State::idle()
 }
 
 pub(crate) fn next(current: &mut Token) -> Token {
@@ -574,3 +835,70 @@ fn would_block(err: &io::Error) -> bool {
 fn interrupted(err: &io::Error) -> bool {
     err.kind() == io::ErrorKind::Interrupted
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use mio::Token;
+
+    use crate::{Block, Bytes, Context, Settings};
+    use crate::blockchain::chain::tests::memory_chain;
+    use crate::p2p::{Message, Peers, State};
+    use crate::p2p::known_peers::tests::memory_known_peers;
+    use crate::p2p::network::{deal_with_fork, get_block_to_announce, handle_message, MAX_FORK_BLOCKS};
+    use crate::p2p::peer::tests::test_peer;
+
+    fn block(index: u64) -> Block {
+        Block::from_all_params(index, 0, 0, 0, 0, 0, Bytes::zero32(), Bytes::new(vec![index as u8; 32]), Bytes::zero32(), Bytes::zero64(), None)
+    }
+
+    #[test]
+    fn announce_every_block_once() {
+        let context = Arc::new(Mutex::new(Context::new(String::from("test"), Settings::default(), None, memory_chain())));
+        let mut announced = context.lock().unwrap().chain.last_hash();
+        assert!(get_block_to_announce(&context, &mut announced).is_none());
+
+        context.lock().unwrap().chain.add_block(block(1)).unwrap();
+        assert_eq!(get_block_to_announce(&context, &mut announced).unwrap().index, 1);
+        assert!(get_block_to_announce(&context, &mut announced).is_none());
+
+        // While syncing nobody needs our blocks
+        context.lock().unwrap().chain.update_max_height(5);
+        context.lock().unwrap().chain.add_block(block(2)).unwrap();
+        assert!(get_block_to_announce(&context, &mut announced).is_none());
+    }
+
+    #[test]
+    fn ask_for_last_block_of_equal_fork() {
+        let context = Arc::new(Mutex::new(Context::new(String::from("test"), Settings::default(), None, memory_chain())));
+        context.lock().unwrap().chain.add_block(block(1)).unwrap();
+        let mut peers = Peers::new(memory_known_peers());
+        peers.add_peer(Token(1), test_peer("10.0.0.1:4244", false));
+
+        let ping = Message::ping(1, Bytes::zero32());
+        match handle_message(Arc::clone(&context), ping, &mut peers, &Token(1)) {
+            State::Message { message } => assert!(matches!(*message, Message::GetBlock { index: 1 })),
+            state => panic!("Wrong answer to ping {:?}", state)
+        }
+        let ping = Message::ping(1, block(1).hash);
+        match handle_message(Arc::clone(&context), ping, &mut peers, &Token(1)) {
+            State::Message { message } => assert!(matches!(*message, Message::Pong { .. })),
+            state => panic!("Wrong answer to ping {:?}", state)
+        }
+    }
+
+    #[test]
+    fn ignore_too_long_fork() {
+        let context = Arc::new(Mutex::new(Context::new(String::from("test"), Settings::default(), None, memory_chain())));
+        let mut peer = test_peer("127.0.0.1:4244", false);
+        peer.set_height(MAX_FORK_BLOCKS as u64 + 10);
+        // We don't have the root of this fork yet, so we ask for previous blocks
+        let blocks = (3..MAX_FORK_BLOCKS as u64 + 3).map(block).collect();
+        assert!(!deal_with_fork(&context, &mut peer, blocks, 1).is_idle());
+        assert_eq!(peer.get_fork().len(), MAX_FORK_BLOCKS);
+
+        assert!(deal_with_fork(&context, &mut peer, vec![block(2)], 1).is_idle());
+        assert!(!peer.has_fork());
+    }
+}
@@ -1,12 +1,18 @@
-use std::net::SocketAddr;
 use std::collections::HashMap;
+use std::cmp::{max, min};
+use std::time::{Duration, Instant};
 use mio::net::TcpStream;
-use crate::p2p::State;
-use crate::Block;
+use serde::Serialize;
+use crate::p2p::{Message, PeerAddr, State};
+use crate::p2p::network::MAX_BLOCKS_BATCH;
+use crate::{Block, Bytes};
+use crate::crypto::{KeyExchange, Session};
+use crate::commons::{ENCODING_VERSION, PROTOCOL_BATCH_SYNC};
+use crate::p2p::message::ENCODING_JSON;
 
 #[derive(Debug)]
 pub struct Peer {
-    addr: SocketAddr,
+    addr: PeerAddr,
     stream: TcpStream,
     state: State,
     id: String,
@@ -17,11 +23,24 @@ pub struct Peer {
     reconnects: u32,
     spurious: u32,
     received_block: u64,
-    fork: HashMap<u64, Block>
+    protocol: u32,
+    encoding: u32,
+    fork: HashMap<u64, Block>,
+    exchange: Option<KeyExchange>,
+    session: Option<Session>,
+    pending_session: Option<Session>,
+    identity: Option<Bytes>,
+    messages: u32,
+    messages_from: Instant,
+    /// Our requests for blocks that the peer didn't answer yet
+    requests: u32
 }
 
+/// Period in which we count messages from peer
+const MESSAGES_PERIOD: Duration = Duration::from_secs(60);
+
 impl Peer {
-    pub fn new(addr: SocketAddr, stream: TcpStream, state: State, inbound: bool) -> Self {
+    pub fn new(addr: PeerAddr, stream: TcpStream, state: State, inbound: bool) -> Self {
         Peer {
             addr,
             stream,
@@ -34,11 +53,20 @@ impl Peer {
             reconnects: 0,
             spurious: 0,
             received_block: 0,
-            fork: HashMap::new()
+            protocol: 0,
+            encoding: ENCODING_JSON,
+            fork: HashMap::new(),
+            exchange: None,
+            session: None,
+            pending_session: None,
+            identity: None,
+            messages: 0,
+            messages_from: Instant::now(),
+            requests: 0
         }
     }
 
-    pub fn get_addr(&self) -> SocketAddr {
+    pub fn get_addr(&self) -> PeerAddr {
         self.addr.clone()
     }
 
@@ -46,8 +74,20 @@ impl Peer {
         &mut self.stream
     }
 
-    pub fn set_stream(&mut self, stream: TcpStream) {
+    /// Sets new connection to this peer, everything negotiated over the old one is forgotten
+    pub fn reset_connection(&mut self, stream: TcpStream) {
         self.stream = stream;
+        self.exchange = None;
+        self.session = None;
+        self.pending_session = None;
+        self.protocol = 0;
+        self.encoding = ENCODING_JSON;
+        // Blocks of a fork from the new connection can be from another fork
+        self.fork.clear();
+        // Answers to old requests will never come
+        self.requests = 0;
+        self.messages = 0;
+        self.messages_from = Instant::now();
     }
 
     pub fn get_state(&self) -> &State {
@@ -62,6 +102,10 @@ impl Peer {
         &self.id
     }
 
+    pub fn get_height(&self) -> u64 {
+        self.height
+    }
+
     pub fn set_height(&mut self, height: u64) {
         self.height = height;
     }
@@ -82,6 +126,120 @@ impl Peer {
         self.height > self.received_block && self.height > height && self.get_state().is_idle()
     }
 
+    /// Remembers requests for blocks that we send, answers to them are not counted as flood
+    pub fn track_request(&mut self, message: &Message) {
+        if matches!(message, Message::GetBlock { .. } | Message::GetBlocks { .. }) {
+            self.requests += 1;
+        }
+    }
+
+    /// Counts received message, returns false if the peer has sent more than `limit` messages in a minute.
+    /// Blocks that we have asked for are not counted, we need a lot of them while syncing.
+    pub fn count_message(&mut self, message: &Message, limit: u32) -> bool {
+        if matches!(message, Message::Block { .. } | Message::Blocks { .. }) && self.requests > 0 {
+            self.requests -= 1;
+            return true;
+        }
+        if self.messages_from.elapsed() >= MESSAGES_PERIOD {
+            self.messages = 0;
+            self.messages_from = Instant::now();
+        }
+        self.messages += 1;
+        self.messages <= limit
+    }
+
+    pub fn set_protocol(&mut self, protocol: u32) {
+        self.protocol = protocol;
+    }
+
+    pub fn get_protocol(&self) -> u32 {
+        self.protocol
+    }
+
+    /// Sets encoding of outgoing messages to the best one supported by both sides
+    pub fn set_encoding(&mut self, encoding: u32) {
+        self.encoding = min(encoding, ENCODING_VERSION);
+    }
+
+    pub fn encode(&self, message: &Message) -> Vec<u8> {
+        message.to_bytes(self.encoding)
+    }
+
+    /// Creates a request for blocks starting from `index`, old peers can give only one block at a time
+    pub fn blocks_request(&self, index: u64) -> Message {
+        if self.protocol >= PROTOCOL_BATCH_SYNC {
+            Message::GetBlocks { from: index, count: MAX_BLOCKS_BATCH }
+        } else {
+            Message::GetBlock { index }
+        }
+    }
+
+    /// Makes request for blocks right below `index`, used to find a common block with forked chain
+    pub fn prev_blocks_request(&self, index: u64) -> Message {
+        if self.protocol >= PROTOCOL_BATCH_SYNC {
+            let from = max(index.saturating_sub(MAX_BLOCKS_BATCH), 1);
+            Message::GetBlocks { from, count: index - from }
+        } else {
+            Message::GetBlock { index: index - 1 }
+        }
+    }
+
+    /// Makes new key for key exchange and returns its public part
+    pub fn new_key_exchange(&mut self) -> [u8; 32] {
+        let exchange = KeyExchange::new();
+        let public = exchange.get_public();
+        self.exchange = Some(exchange);
+        public
+    }
+
+    /// Makes encrypted session from our key exchange and public key of the peer, used by the side that sent Hand
+    pub fn start_session(&mut self, their_public: &[u8]) -> bool {
+        match self.exchange.take().and_then(|exchange| exchange.make_session(their_public, true)) {
+            None => false,
+            Some(session) => {
+                self.session = Some(session);
+                true
+            }
+        }
+    }
+
+    /// The session will be started after we send next message (Shake) in plaintext
+    pub fn set_pending_session(&mut self, session: Session) {
+        self.pending_session = Some(session);
+    }
+
+    pub fn activate_session(&mut self) {
+        if self.pending_session.is_some() {
+            self.session = self.pending_session.take();
+        }
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.session.is_some()
+    }
+
+    pub fn encrypt(&mut self, data: Vec<u8>) -> Vec<u8> {
+        match &mut self.session {
+            None => data,
+            Some(session) => session.encrypt(&data)
+        }
+    }
+
+    pub fn decrypt(&mut self, data: Vec<u8>) -> Option<Vec<u8>> {
+        match &mut self.session {
+            None => Some(data),
+            Some(session) => session.decrypt(&data)
+        }
+    }
+
+    pub fn set_identity(&mut self, identity: Option<Bytes>) {
+        self.identity = identity;
+    }
+
+    pub fn get_identity(&self) -> Option<&Bytes> {
+        self.identity.as_ref()
+    }
+
     pub fn is_public(&self) -> bool {
         self.public
     }
@@ -138,13 +296,129 @@ impl Peer {
         &self.fork
     }
 
+    pub fn has_fork(&self) -> bool {
+        !self.fork.is_empty()
+    }
+
+    /// Takes all collected fork blocks sorted by index
+    pub fn take_fork(&mut self) -> Vec<Block> {
+        let mut blocks: Vec<Block> = self.fork.drain().map(|(_, block)| block).collect();
+        blocks.sort_by_key(|block| block.index);
+        blocks
+    }
+
     /// If loopback address then we care about ip and port.
-    /// If regular address then we only care about the ip and ignore the port.
-    pub fn equals(&self, addr: &SocketAddr) -> bool {
-        if self.addr.ip().is_loopback() {
-            self.addr == *addr
-        } else {
-            self.addr.ip() == addr.ip()
+    /// If regular address then we only care about the ip (or host) and ignore the port.
+    pub fn equals(&self, addr: &PeerAddr) -> bool {
+        self.addr.same_host(addr)
+    }
+}
+
+/// A snapshot of peer state to show it outside of network thread
+#[derive(Clone, Debug, Serialize)]
+pub struct PeerInfo {
+    pub addr: String,
+    pub height: u64,
+    pub inbound: bool,
+    pub public: bool,
+    pub encrypted: bool,
+    pub identity: Option<Bytes>,
+}
+
+impl From<&Peer> for PeerInfo {
+    fn from(peer: &Peer) -> Self {
+        PeerInfo {
+            addr: peer.get_addr().to_string(),
+            height: peer.get_height(),
+            inbound: peer.is_inbound(),
+            public: peer.is_public(),
+            encrypted: peer.is_encrypted(),
+            identity: peer.get_identity().cloned()
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::net::TcpListener;
+
+    use crate::{Block, Bytes};
+    use crate::crypto::KeyExchange;
+    use crate::p2p::{Message, Peer, PeerAddr, State};
+
+    /// Real connection on loopback, that nobody talks to
+    fn test_stream() -> mio::net::TcpStream {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        stream.set_nonblocking(true).unwrap();
+        mio::net::TcpStream::from_std(stream)
+    }
+
+    /// Idle peer with `addr`, it doesn't talk to anyone
+    pub fn test_peer(addr: &str, inbound: bool) -> Peer {
+        Peer::new(addr.parse::<PeerAddr>().unwrap(), test_stream(), State::idle(), inbound)
+    }
+
+    #[test]
+    fn handshake_after_reconnect() {
+        let mut peer = test_peer("10.0.0.1:4244", false);
+        for _ in 0..2 {
+            // Hand and Shake go in plaintext
+            assert_eq!(peer.decrypt(b"Shake".to_vec()).unwrap(), b"Shake");
+            let their = KeyExchange::new();
+            let mut their_session = their.make_session(&peer.new_key_exchange(), false).unwrap();
+            assert!(peer.start_session(&their.get_public()));
+            assert!(peer.is_encrypted());
+            let frame = peer.encrypt(b"Ping".to_vec());
+            assert_eq!(their_session.decrypt(&frame).unwrap(), b"Ping");
+            peer.set_protocol(2);
+
+            peer.reset_connection(test_stream());
+            assert!(!peer.is_encrypted());
+            assert_eq!(peer.get_protocol(), 0);
+        }
+    }
+
+    #[test]
+    fn forget_fork_after_reconnect() {
+        let mut peer = test_peer("10.0.0.1:4244", false);
+        peer.add_fork_block(Block::from_all_params(5, 0, 0, 0, 0, 0, Bytes::zero32(), Bytes::zero32(), Bytes::zero32(), Bytes::zero64(), None));
+        assert!(peer.has_fork());
+        peer.reset_connection(test_stream());
+        assert!(!peer.has_fork());
+    }
+
+    #[test]
+    fn count_unsolicited_messages() {
+        let mut peer = test_peer("10.0.0.1:4244", false);
+        let blocks = Message::Blocks { blocks: Vec::new() };
+        for _ in 0..3 {
+            peer.track_request(&Message::GetBlocks { from: 1, count: 10 });
+        }
+        peer.track_request(&Message::GetPeers);
+        // Answers to our requests are free
+        for _ in 0..3 {
+            assert!(peer.count_message(&blocks, 2));
+        }
+        assert!(peer.count_message(&Message::GetPeers, 2));
+        assert!(peer.count_message(&blocks, 2));
+        assert!(!peer.count_message(&blocks, 2));
+    }
+
+    #[test]
+    fn reset_message_counters_on_reconnect() {
+        let mut peer = test_peer("10.0.0.1:4244", false);
+        let blocks = Message::Blocks { blocks: Vec::new() };
+        peer.track_request(&Message::GetBlocks { from: 1, count: 10 });
+        peer.track_request(&Message::GetBlocks { from: 11, count: 10 });
+        assert!(peer.count_message(&Message::GetPeers, 2));
+        assert!(peer.count_message(&Message::GetPeers, 2));
+
+        peer.reset_connection(test_stream());
+        assert_eq!(peer.requests, 0);
+        assert!(peer.count_message(&Message::GetPeers, 2));
+        // Nothing was requested over the new connection
+        assert!(peer.count_message(&blocks, 2));
+        assert!(!peer.count_message(&blocks, 2));
+    }
+}
@@ -1,29 +1,92 @@
+use std::cmp::max;
 use std::collections::{HashMap, HashSet};
+use std::io;
 use std::net::{SocketAddr, IpAddr, Shutdown, ToSocketAddrs};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
 use mio::{Token, Interest, Registry};
 use mio::net::TcpStream;
-use crate::p2p::{Peer, State, Message};
+use crate::p2p::{KnownPeers, Peer, PeerAddr, PeerInfo, State, Message, proxy};
 use crate::p2p::network::LISTEN_PORT;
 use crate::p2p::network::next;
 use rand::random;
 use rand::seq::IteratorRandom;
 #[allow(unused_imports)]
 use log::{trace, debug, info, warn, error};
-use crate::{Bytes, is_yggdrasil, commons};
+use crate::{Block, Bytes, is_yggdrasil, commons};
 use crate::commons::MAX_RECONNECTS;
+use chrono::Utc;
 
 pub struct Peers {
     peers: HashMap<Token, Peer>,
-    new_peers: Vec<SocketAddr>,
-    ignored: HashSet<IpAddr>,
-    my_id: String
+    new_peers: Vec<PeerAddr>,
+    /// Ignored IPs with time until they are ignored
+    ignored: HashMap<IpAddr, i64>,
+    known: KnownPeers,
+    my_id: String,
+    proxy: Option<SocketAddr>,
+    proxy_only: bool,
+    /// Addresses that we are connecting to through proxy right now
+    proxy_pending: HashSet<PeerAddr>,
+    proxy_sender: Sender<ProxyConnection>,
+    proxy_receiver: Receiver<ProxyConnection>,
+    max_inbound: usize,
+    max_outbound: usize,
+    max_per_ip: usize,
+    max_messages: u32
+}
+
+/// Result of connection through proxy, it is made in another thread as it can take a while
+struct ProxyConnection {
+    addr: PeerAddr,
+    /// Token of the peer that we reconnect to, or None for a new one
+    token: Option<Token>,
+    stream: io::Result<std::net::TcpStream>
 }
 
 const PING_PERIOD: u64 = 60;
+/// How many known peers we try to connect on start
+const KNOWN_PEERS_TO_CONNECT: usize = 10;
+/// How long a banned peer stays banned, in seconds
+const BAN_TIME: i64 = 86400;
+/// How long we ignore peers that flood us with messages, in seconds
+const FLOOD_BAN_TIME: i64 = 600;
 
 impl Peers {
-    pub fn new() -> Self {
-        Peers { peers: HashMap::new(), new_peers: Vec::new(), ignored: HashSet::new(), my_id: commons::random_string(6) }
+    pub fn new(known: KnownPeers) -> Self {
+        let ignored = known.get_banned().into_iter().collect();
+        let new_peers = known.get_best(KNOWN_PEERS_TO_CONNECT);
+        let (proxy_sender, proxy_receiver) = channel();
+        Peers {
+            peers: HashMap::new(),
+            new_peers,
+            ignored,
+            known,
+            my_id: commons::random_string(6),
+            proxy: None,
+            proxy_only: false,
+            proxy_pending: HashSet::new(),
+            proxy_sender,
+            proxy_receiver,
+            max_inbound: usize::MAX,
+            max_outbound: usize::MAX,
+            max_per_ip: usize::MAX,
+            max_messages: u32::MAX
+        }
+    }
+
+    /// Sets limits of connections and messages, so that nobody can eat all our resources
+    pub fn set_limits(&mut self, max_inbound: usize, max_outbound: usize, max_per_ip: usize, max_messages: u32) {
+        self.max_inbound = max_inbound;
+        self.max_outbound = max_outbound;
+        self.max_per_ip = max_per_ip;
+        self.max_messages = max_messages;
+    }
+
+    /// Sets SOCKS5 proxy for outbound connections, if `proxy_only` is set we never connect directly
+    pub fn set_proxy(&mut self, proxy: Option<SocketAddr>, proxy_only: bool) {
+        self.proxy = proxy;
+        self.proxy_only = proxy_only;
     }
 
     pub fn add_peer(&mut self, token: Token, peer: Peer) {
@@ -48,6 +111,9 @@ impl Peers {
                 match peer.get_state() {
                     State::Connecting => {
                         debug!("Peer connection {} to {:?} has timed out", &token.0, &peer.get_addr());
+                        if !peer.is_inbound() {
+                            self.known.add_failure(&peer.get_addr());
+                        }
                     }
                     State::Connected => {
                         debug!("Peer connection {} to {:?} disconnected", &token.0, &peer.get_addr());
@@ -57,6 +123,9 @@ impl Peers {
                     }
                     State::Error => {
                         debug!("Peer connection {} to {:?} has shut down on error", &token.0, &peer.get_addr());
+                        if !peer.is_inbound() {
+                            self.known.add_failure(&peer.get_addr());
+                        }
                     }
                     State::Banned => {
                         debug!("Peer connection {} to {:?} has shut down, banned", &token.0, &peer.get_addr());
@@ -78,6 +147,7 @@ impl Peers {
     }
 
     pub fn add_peers_from_exchange(&mut self, peers: Vec<String>) {
+        let can_resolve = self.proxy.is_some();
         let peers: HashSet<String> = peers
             .iter()
             .fold(HashSet::new(), |mut peers, peer| {
@@ -87,7 +157,7 @@ impl Peers {
         debug!("Got {} peers: {:?}", peers.len(), &peers);
         // TODO make it return error if these peers are wrong and seem like an attack
         for peer in peers.iter() {
-            let addr: SocketAddr = match peer.parse() {
+            let addr: PeerAddr = match peer.parse() {
                 Err(_) => {
                     warn!("Error parsing peer {}", peer);
                     continue;
@@ -95,9 +165,15 @@ impl Peers {
                 Ok(addr) => addr
             };
 
+            // Only proxy can resolve hostnames for us
+            if addr.ip().is_none() && !can_resolve {
+                trace!("Skipping address from exchange: {}", &addr);
+                continue;
+            }
+
             if self.peers
                 .iter()
-                .find(|(_token, peer)| peer.get_addr().ip() == addr.ip())
+                .find(|(_token, peer)| peer.get_addr().same_host(&addr))
                 .is_some() {
                 //debug!("Skipping address from exchange: {}", &addr);
                 continue;
@@ -105,13 +181,13 @@ impl Peers {
 
             if self.new_peers
                 .iter()
-                .find(|a| a.ip().eq(&addr.ip()))
+                .find(|a| a.same_host(&addr))
                 .is_some() {
                 //debug!("Skipping address from exchange: {}", &addr);
                 continue;
             }
 
-            if self.ignored.contains(&addr.ip()) {
+            if self.is_ignored(&addr) {
                 trace!("Skipping address from exchange: {}", &addr);
                 continue;
             }
@@ -142,7 +218,8 @@ impl Peers {
         self.my_id.eq(rand)
     }
 
-    pub fn get_peers_for_exchange(&self, peer_address: &SocketAddr) -> Vec<String> {
+    /// Returns public peers for `peer_address`, older peers can't parse hostnames, so `with_hosts` is false for them
+    pub fn get_peers_for_exchange(&self, peer_address: &PeerAddr, with_hosts: bool) -> Vec<String> {
         let mut result: Vec<String> = Vec::new();
         for (_, peer) in self.peers.iter() {
             if peer.disabled() {
@@ -152,12 +229,25 @@ impl Peers {
                 continue;
             }
             if peer.is_public() {
-                result.push(SocketAddr::new(peer.get_addr().ip(), LISTEN_PORT).to_string());
+                match peer.get_addr() {
+                    PeerAddr::Ip(addr) => result.push(SocketAddr::new(addr.ip(), LISTEN_PORT).to_string()),
+                    // We have connected to them, so the port is right
+                    addr if with_hosts => result.push(addr.to_string()),
+                    _ => {}
+                }
             }
         }
         result
     }
 
+    /// Returns info about all active peers
+    pub fn get_peers_info(&self) -> Vec<PeerInfo> {
+        self.peers.values()
+            .filter(|peer| peer.active())
+            .map(PeerInfo::from)
+            .collect()
+    }
+
     pub fn get_peers_active_count(&self) -> usize {
         let mut count = 0;
         for (_, peer) in self.peers.iter() {
@@ -168,15 +258,27 @@ impl Peers {
         count
     }
 
+    /// Remembers that we have successfully connected to this peer
+    pub fn add_success(&mut self, token: &Token) {
+        if let Some(peer) = self.peers.get(token) {
+            if !peer.is_inbound() {
+                self.known.add_success(&peer.get_addr());
+            }
+        }
+    }
+
     pub fn ignore_peer(&mut self, registry: &Registry, token: &Token) {
         let peer = self.peers.get_mut(token).unwrap();
         peer.set_state(State::Banned);
-        let ip = peer.get_addr().ip().clone();
+        let addr = peer.get_addr();
+        self.known.ban(&addr, "Protocol violation", Utc::now().timestamp() + BAN_TIME);
         self.close_peer(registry, token);
-        self.ignored.insert(ip);
+        if let Some(ip) = addr.ip() {
+            self.ignore_ip(&ip);
+        }
         match self.peers
             .iter()
-            .find(|(_, p)| p.get_addr().ip() == ip)
+            .find(|(_, p)| p.get_addr().same_host(&addr))
             .map(|(t, _)| t.clone()) {
             None => {}
             Some(t) => {
@@ -187,10 +289,84 @@ impl Peers {
     }
 
     pub fn ignore_ip(&mut self, ip: &IpAddr) {
-        self.ignored.insert(ip.clone());
+        self.ignored.insert(*ip, i64::MAX);
+    }
+
+    /// Ignores IP for some `seconds`, for peers that misbehave but may get better
+    pub fn ignore_ip_for(&mut self, ip: &IpAddr, seconds: i64) {
+        let until = Utc::now().timestamp() + seconds;
+        let time = self.ignored.entry(*ip).or_insert(until);
+        *time = max(*time, until);
+    }
+
+    pub fn is_ip_ignored(&self, ip: &IpAddr) -> bool {
+        match self.ignored.get(ip) {
+            None => false,
+            Some(until) => *until > Utc::now().timestamp()
+        }
+    }
+
+    /// Banned hosts are only in known peers, as they have no IP
+    fn is_ignored(&self, addr: &PeerAddr) -> bool {
+        match addr.ip() {
+            Some(ip) => self.is_ip_ignored(&ip),
+            None => self.known.is_banned(addr)
+        }
+    }
+
+    /// Checks if we can accept one more connection from this IP
+    pub fn can_accept(&self, ip: &IpAddr) -> bool {
+        if self.is_ip_ignored(ip) {
+            return false;
+        }
+        let inbound = self.peers.values().filter(|peer| peer.is_inbound()).count();
+        if inbound >= self.max_inbound {
+            debug!("Too many incoming connections, dropping connection from {}", ip);
+            return false;
+        }
+        self.check_ip_limit(ip)
+    }
+
+    /// Loopback is not limited, it may be Tor or some other proxy
+    fn check_ip_limit(&self, ip: &IpAddr) -> bool {
+        if ip.is_loopback() {
+            return true;
+        }
+        let count = self.peers.values().filter(|peer| !peer.disabled() && peer.get_addr().ip() == Some(*ip)).count();
+        if count >= self.max_per_ip {
+            debug!("Too many connections with {}", ip);
+            return false;
+        }
+        true
     }
 
-    pub fn skip_peer_connection(&self, addr: &SocketAddr) -> bool {
+    fn get_outbound_count(&self) -> usize {
+        let connected = self.peers.values().filter(|peer| !peer.is_inbound() && !peer.disabled()).count();
+        connected + self.proxy_pending.len()
+    }
+
+    /// Counts message from peer, if it sends too many messages we ban it for some time, and it has to be closed
+    pub fn count_message(&mut self, token: &Token, message: &Message) -> bool {
+        let max_messages = self.max_messages;
+        let addr = match self.peers.get_mut(token) {
+            None => return false,
+            Some(peer) => {
+                if peer.count_message(message, max_messages) {
+                    return true;
+                }
+                peer.set_state(State::Banned);
+                peer.get_addr()
+            }
+        };
+        warn!("Peer {} sends too many messages, ignoring it", &addr);
+        self.known.ban(&addr, "Too many messages", Utc::now().timestamp() + FLOOD_BAN_TIME);
+        if let Some(ip) = addr.ip() {
+            self.ignore_ip_for(&ip, FLOOD_BAN_TIME);
+        }
+        false
+    }
+
+    pub fn skip_peer_connection(&self, addr: &PeerAddr) -> bool {
         for (_, peer) in self.peers.iter() {
             if peer.equals(addr) && (!peer.is_public() || peer.active() || peer.disabled()) {
                 return true;
@@ -199,6 +375,21 @@ impl Peers {
         false
     }
 
+    /// Sends new block to active peers that are lower than this block, busy peers will get it by pings
+    pub fn announce_block(&mut self, registry: &Registry, block: Block) {
+        let mut count = 0;
+        for (token, peer) in self.peers.iter_mut() {
+            if peer.active() && peer.get_state().is_idle() && peer.is_lower(block.index) {
+                peer.set_state(State::message(Message::block(block.index, block.clone())));
+                registry.reregister(peer.get_stream(), *token, Interest::WRITABLE).unwrap();
+                count += 1;
+            }
+        }
+        if count > 0 {
+            debug!("Announced block {} to {} peers", block.index, count);
+        }
+    }
+
     pub fn send_pings(&mut self, registry: &Registry, height: u64, hash: Bytes) {
         let mut ping_sent = false;
         for (token, peer) in self.peers.iter_mut() {
@@ -235,7 +426,7 @@ impl Peers {
                 Some((token, peer)) => {
                     debug!("Found some peer higher than we are, sending block request");
                     registry.reregister(peer.get_stream(), token.clone(), Interest::WRITABLE).unwrap();
-                    peer.set_state(State::message(Message::GetBlock { index: height + 1 }));
+                    peer.set_state(State::message(peer.blocks_request(height + 1)));
                     ping_sent = true;
                 }
             }
@@ -262,7 +453,9 @@ impl Peers {
         self.peers.retain(|_, p| {
             let offline = p.get_state().need_reconnect() && p.reconnects() >= MAX_RECONNECTS;
             if offline {
-                offline_ips.push(p.get_addr().ip());
+                if let Some(ip) = p.get_addr().ip() {
+                    offline_ips.push(ip);
+                }
             }
             !offline
         });
@@ -270,18 +463,27 @@ impl Peers {
             self.ignore_ip(&ip);
         }
 
-        for (token, peer) in self.peers.iter_mut() {
-            if peer.get_state().need_reconnect() {
-                let addr = peer.get_addr();
-                if let Ok(mut stream) = TcpStream::connect(addr.clone()) {
+        // We make reconnects only to one at a time
+        let reconnect = self.peers
+            .iter()
+            .find(|(_, peer)| peer.get_state().need_reconnect())
+            .map(|(token, peer)| (*token, peer.get_addr()));
+        if let Some((token, addr)) = reconnect {
+            if self.use_proxy(&addr) {
+                debug!("Trying to connect to peer {} through proxy", &addr);
+                let peer = self.peers.get_mut(&token).unwrap();
+                peer.set_state(State::offline());
+                peer.inc_reconnects();
+                self.connect_through_proxy(&addr, Some(token));
+            } else if let PeerAddr::Ip(socket_addr) = addr {
+                if let Ok(mut stream) = TcpStream::connect(socket_addr) {
                     debug!("Trying to connect to peer {}", &addr);
-                    registry.register(&mut stream, token.clone(), Interest::WRITABLE).unwrap();
+                    registry.register(&mut stream, token, Interest::WRITABLE).unwrap();
+                    let peer = self.peers.get_mut(&token).unwrap();
                     peer.set_state(State::Connecting);
                     peer.inc_reconnects();
-                    peer.set_stream(stream);
+                    peer.reset_connection(stream);
                 }
-                // We make reconnects only to one at a time
-                break;
             }
         }
     }
@@ -290,6 +492,9 @@ impl Peers {
         if self.new_peers.is_empty() {
             return;
         }
+        // Peers that worked well before go first
+        let known = &self.known;
+        self.new_peers.sort_by_key(|addr| -known.get_score(addr));
         for addr in &self.new_peers.clone() {
             self.connect_peer(&addr, registry, unique_token, yggdrasil_only);
         }
@@ -299,26 +504,55 @@ impl Peers {
     /// Connecting to configured (bootstrap) peers
     pub fn connect_peers(&mut self, peers_addrs: Vec<String>, registry: &Registry, unique_token: &mut Token, yggdrasil_only: bool) {
         for peer in peers_addrs.iter() {
+            let addr: PeerAddr = match peer.parse() {
+                Ok(addr) => addr,
+                Err(e) => { error!("{}", e); continue; }
+            };
+            // Hostnames are resolved by proxy if we have one, we must not make DNS requests in proxy only mode
+            if addr.ip().is_some() || self.proxy_only || (self.proxy.is_some() && addr.is_onion()) {
+                self.connect_peer(&addr, registry, unique_token, yggdrasil_only);
+                continue;
+            }
             let addresses: Vec<SocketAddr> = match peer.to_socket_addrs() {
                 Ok(peers) => { peers.collect() }
                 Err(_) => { error!("Can't resolve address {}", &peer); continue; }
             };
 
             for addr in addresses {
-                self.connect_peer(&addr, registry, unique_token, yggdrasil_only);
+                self.connect_peer(&PeerAddr::Ip(addr), registry, unique_token, yggdrasil_only);
             }
         }
     }
 
-    fn connect_peer(&mut self, addr: &SocketAddr, registry: &Registry, unique_token: &mut Token, yggdrasil_only: bool) {
-        if self.ignored.contains(&addr.ip()) {
+    fn connect_peer(&mut self, addr: &PeerAddr, registry: &Registry, unique_token: &mut Token, yggdrasil_only: bool) {
+        if self.is_ignored(addr) {
+            return;
+        }
+        if self.get_outbound_count() >= self.max_outbound {
+            trace!("Too many outgoing connections, skipping {}", &addr);
+            return;
+        }
+        if let Some(ip) = addr.ip() {
+            if !self.check_ip_limit(&ip) {
+                return;
+            }
+        }
+        if yggdrasil_only && !addr.ip().map(|ip| is_yggdrasil(&ip)).unwrap_or(false) {
+            debug!("Ignoring not Yggdrasil address '{}'", &addr);
             return;
         }
-        if yggdrasil_only && !is_yggdrasil(&addr.ip()) {
-            debug!("Ignoring not Yggdrasil address '{}'", &addr.ip());
+        if self.use_proxy(addr) {
+            self.connect_through_proxy(addr, None);
             return;
         }
-        if let Ok(mut stream) = TcpStream::connect(addr.clone()) {
+        let socket_addr = match addr {
+            PeerAddr::Ip(socket_addr) => *socket_addr,
+            PeerAddr::Host { .. } => {
+                debug!("Can't connect to {} without proxy", &addr);
+                return;
+            }
+        };
+        if let Ok(mut stream) = TcpStream::connect(socket_addr) {
             let token = next(unique_token);
             debug!("Created connection {}, to peer {}", &token.0, &addr);
             registry.register(&mut stream, token, Interest::WRITABLE).unwrap();
@@ -327,9 +561,67 @@ impl Peers {
             self.peers.insert(token, peer);
         }
     }
+
+    /// Hostnames can be reached only through proxy, and in proxy only mode everything goes through it
+    fn use_proxy(&self, addr: &PeerAddr) -> bool {
+        self.proxy.is_some() && (self.proxy_only || addr.ip().is_none())
+    }
+
+    fn connect_through_proxy(&mut self, addr: &PeerAddr, token: Option<Token>) {
+        let proxy = match self.proxy {
+            None => return,
+            Some(proxy) => proxy
+        };
+        if !self.proxy_pending.insert(addr.clone()) {
+            return;
+        }
+        let sender = self.proxy_sender.clone();
+        let addr = addr.clone();
+        let _ = thread::Builder::new().name(String::from("proxy")).spawn(move || {
+            let stream = proxy::connect(&proxy, &addr);
+            let _ = sender.send(ProxyConnection { addr, token, stream });
+        });
+    }
+
+    /// Registers connections that were made through proxy since last call
+    pub fn finish_proxy_connections(&mut self, registry: &Registry, unique_token: &mut Token) {
+        while let Ok(connection) = self.proxy_receiver.try_recv() {
+            let addr = connection.addr;
+            self.proxy_pending.remove(&addr);
+            let mut stream = match connection.stream {
+                Ok(stream) => TcpStream::from_std(stream),
+                Err(e) => {
+                    debug!("Error connecting to peer {} through proxy: {}", &addr, e);
+                    self.known.add_failure(&addr);
+                    continue;
+                }
+            };
+            match connection.token {
+                Some(token) => {
+                    if let Some(peer) = self.peers.get_mut(&token) {
+                        registry.register(&mut stream, token, Interest::WRITABLE).unwrap();
+                        peer.set_state(State::Connecting);
+                        peer.reset_connection(stream);
+                    }
+                }
+                None => {
+                    let token = next(unique_token);
+                    debug!("Created connection {}, to peer {} through proxy", &token.0, &addr);
+                    registry.register(&mut stream, token, Interest::WRITABLE).unwrap();
+                    let mut peer = Peer::new(addr, stream, State::Connecting, false);
+                    peer.set_public(true);
+                    self.peers.insert(token, peer);
+                }
+            }
+        }
+    }
 }
 
-fn skip_private_addr(addr: &SocketAddr) -> bool {
+fn skip_private_addr(addr: &PeerAddr) -> bool {
+    let addr = match addr {
+        PeerAddr::Ip(addr) => addr,
+        PeerAddr::Host { .. } => return false
+    };
     if addr.ip().is_loopback() {
         return true;
     }
@@ -348,4 +640,63 @@ fn skip_private_addr(addr: &SocketAddr) -> bool {
     }
 
     false
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use mio::{Interest, Poll, Token};
+
+    use crate::{Block, Bytes};
+    use crate::p2p::{PeerAddr, Peers};
+    use crate::p2p::known_peers::tests::memory_known_peers;
+    use crate::p2p::peer::tests::test_peer;
+
+    #[test]
+    fn announce_block_to_lower_peers() {
+        let poll = Poll::new().unwrap();
+        let mut peers = Peers::new(memory_known_peers());
+        for (token, height) in [(Token(1), 1), (Token(2), 2)] {
+            let mut peer = test_peer(&format!("10.0.0.{}:4244", token.0), false);
+            peer.set_active(true);
+            peer.set_height(height);
+            poll.registry().register(peer.get_stream(), token, Interest::READABLE).unwrap();
+            peers.add_peer(token, peer);
+        }
+
+        // The second peer has sent us this block
+        let block = Block::from_all_params(2, 0, 0, 0, 0, 0, Bytes::zero32(), Bytes::zero32(), Bytes::zero32(), Bytes::zero64(), None);
+        peers.announce_block(poll.registry(), block.clone());
+        assert!(!peers.get_peer(&Token(1)).unwrap().get_state().is_idle());
+        assert!(peers.get_peer(&Token(2)).unwrap().get_state().is_idle());
+    }
+
+    #[test]
+    fn keep_ban_time_of_known_peers() {
+        let mut known = memory_known_peers();
+        let addr: PeerAddr = "10.0.0.1:4244".parse().unwrap();
+        let until = Utc::now().timestamp() + 100;
+        known.ban(&addr, "Too many messages", until);
+        let peers = Peers::new(known);
+        assert_eq!(peers.ignored.get(&addr.ip().unwrap()), Some(&until));
+        assert!(!peers.can_accept(&addr.ip().unwrap()));
+    }
+
+    #[test]
+    fn limit_connections() {
+        let mut peers = Peers::new(memory_known_peers());
+        peers.set_limits(2, 10, 1, 100);
+        let ip = "10.0.0.1".parse().unwrap();
+        assert!(peers.can_accept(&ip));
+        peers.add_peer(Token(1), test_peer("10.0.0.1:4244", false));
+        assert!(!peers.check_ip_limit(&ip));
+        assert!(!peers.can_accept(&ip));
+        // Loopback can be a proxy for many peers
+        peers.add_peer(Token(2), test_peer("127.0.0.1:4244", true));
+        assert!(peers.can_accept(&"127.0.0.1".parse().unwrap()));
+
+        peers.add_peer(Token(3), test_peer("10.0.0.3:4244", true));
+        assert!(peers.check_ip_limit(&"10.0.0.4".parse().unwrap()));
+        assert!(!peers.can_accept(&"10.0.0.4".parse().unwrap()));
+    }
+}
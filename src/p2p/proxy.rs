@@ -0,0 +1,17 @@
+use std::io;
+use std::net::{SocketAddr, TcpStream};
+
+use socks::Socks5Stream;
+
+use crate::p2p::PeerAddr;
+
+/// Connects to `addr` through SOCKS5 proxy, hostnames are sent to proxy as is, so they don't leak to our DNS
+pub fn connect(proxy: &SocketAddr, addr: &PeerAddr) -> io::Result<TcpStream> {
+    let stream = match addr {
+        PeerAddr::Ip(addr) => Socks5Stream::connect(proxy, *addr)?,
+        PeerAddr::Host { host, port } => Socks5Stream::connect(proxy, (host.as_str(), *port))?
+    };
+    let stream = stream.into_inner();
+    stream.set_nonblocking(true)?;
+    Ok(stream)
+}
@@ -6,7 +6,7 @@ pub enum State {
     Connecting,
     Connected,
     Idle { from: Instant },
-    Message { data: Vec<u8> },
+    Message { message: Box<Message> },
     Error,
     Banned,
     Offline { from: Instant },
@@ -22,8 +22,7 @@ impl State {
     }
 
     pub fn message(message: Message) -> Self {
-        let response = serde_json::to_string(&message).unwrap();
-        State::Message {data: Vec::from(response.as_bytes()) }
+        State::Message { message: Box::new(message) }
     }
 
     pub fn is_idle(&self) -> bool {
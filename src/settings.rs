@@ -1,24 +1,38 @@
+use std::collections::BTreeMap;
 use std::fs::File;
 use std::io::Read;
+use std::path::{Path, PathBuf};
 
 use serde::{Deserialize, Serialize};
 #[allow(unused_imports)]
 use log::{debug, error, info, LevelFilter, trace, warn};
 
 use crate::Bytes;
+use crate::commons::{CHECKPOINTS, MAIN_ORIGIN, ConsensusParams};
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Settings {
     #[serde(default)]
     pub origin: String,
+    /// Local test network with tiny difficulties and short intervals
+    #[serde(default)]
+    pub regtest: bool,
     #[serde(default)]
     pub key_file: String,
+    /// Directory for blockchain DB, keys and zones, empty means current directory
+    #[serde(default)]
+    pub data_dir: String,
     #[serde(default)]
     pub net: Net,
     #[serde(default)]
     pub dns: Dns,
     #[serde(default)]
     pub mining: Mining,
+    #[serde(default)]
+    pub api: Api,
+    /// Blocks that we know are good, they override built-in ones
+    #[serde(default)]
+    pub checkpoints: Vec<Checkpoint>,
 }
 
 impl Settings {
@@ -38,6 +52,41 @@ impl Settings {
         }
     }
 
+    /// Resolves `file` against `data_dir`, absolute paths are left as they are
+    pub fn get_path(&self, file: &str) -> PathBuf {
+        let path = Path::new(file);
+        if self.data_dir.is_empty() || path.is_absolute() {
+            return path.to_path_buf();
+        }
+        Path::new(&self.data_dir).join(path)
+    }
+
+    /// Returns built-in checkpoints (if we are on the main chain) with ones from settings over them
+    pub fn get_checkpoints(&self) -> Result<BTreeMap<u64, Bytes>, String> {
+        let mut result = BTreeMap::new();
+        if !self.regtest && self.origin.eq_ignore_ascii_case(MAIN_ORIGIN) {
+            for (height, hash) in CHECKPOINTS {
+                result.insert(*height, Bytes::from_bytes(&crate::from_hex(hash).unwrap()));
+            }
+        }
+        for checkpoint in &self.checkpoints {
+            let hash = crate::from_hex(&checkpoint.hash)
+                .ok()
+                .filter(|hash| hash.len() == 32)
+                .ok_or_else(|| format!("Wrong hash in checkpoint {}", checkpoint.height))?;
+            result.insert(checkpoint.height, Bytes::from_bytes(&hash));
+        }
+        Ok(result)
+    }
+
+    pub fn get_consensus(&self) -> ConsensusParams {
+        if self.regtest {
+            ConsensusParams::regtest()
+        } else {
+            ConsensusParams::main()
+        }
+    }
+
     pub fn get_origin(&self) -> Bytes {
         if self.origin.eq("") {
             return Bytes::zero32();
@@ -51,14 +100,33 @@ impl Default for Settings {
     fn default() -> Self {
         Self {
             origin: String::from("00000102C2F9BFD2803284D93327F089D60FC72A06F19AF2384567F2646B8348"),
+            regtest: false,
             key_file: String::from("default.key"),
+            data_dir: String::new(),
             net: Net::default(),
             dns: Default::default(),
-            mining: Mining::default()
+            mining: Mining::default(),
+            api: Api::default(),
+            checkpoints: Vec::new()
         }
     }
 }
 
+/// Domain that is resolved by its own forwarders, or recursively if there are none
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DnsRule {
+    pub domain: String,
+    #[serde(default)]
+    pub forwarders: Vec<String>
+}
+
+/// Height and hash of a block that we know is good
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub height: u64,
+    pub hash: String
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Dns {
     #[serde(default = "default_listen_dns")]
@@ -68,6 +136,27 @@ pub struct Dns {
     pub forwarders: Vec<String>,
     #[serde(default)]
     pub hosts: Vec<String>,
+    /// Address for DNS-over-HTTPS listener, empty means disabled
+    #[serde(default)]
+    pub doh_listen: String,
+    /// Address for DNS-over-TLS listener (usual port is 853), empty means disabled
+    #[serde(default)]
+    pub dot_listen: String,
+    /// Certificate chain in PEM for encrypted listeners, without it DoH works over plain HTTP
+    #[serde(default)]
+    pub tls_cert: String,
+    /// Private key in PEM for `tls_cert`
+    #[serde(default)]
+    pub tls_key: String,
+    /// Additional root certificates in PEM to trust for `tls://` and `https://` forwarders
+    #[serde(default)]
+    pub tls_ca: String,
+    /// Query two forwarders at once and take the first answer
+    #[serde(default)]
+    pub race: bool,
+    /// Domains that are resolved not by `forwarders`, like internal zones
+    #[serde(default)]
+    pub rules: Vec<DnsRule>,
 }
 
 impl Default for Dns {
@@ -76,7 +165,14 @@ impl Default for Dns {
             listen: String::from("127.0.0.1:53"),
             threads: 20,
             forwarders: vec![String::from("94.140.14.14:53"), String::from("94.140.15.15:53")],
-            hosts: Vec::new()
+            hosts: Vec::new(),
+            doh_listen: String::new(),
+            dot_listen: String::new(),
+            tls_cert: String::new(),
+            tls_key: String::new(),
+            tls_ca: String::new(),
+            race: false,
+            rules: Vec::new()
         }
     }
 }
@@ -99,6 +195,30 @@ pub struct Net {
     pub public: bool,
     #[serde(default)]
     pub yggdrasil_only: bool,
+    /// Allow unencrypted connections with old nodes
+    #[serde(default = "default_allow_plaintext")]
+    pub allow_plaintext: bool,
+    /// Sign session keys with our node key, so that peers know who we are
+    #[serde(default)]
+    pub bind_identity: bool,
+    /// SOCKS5 proxy for connections to peers, like "127.0.0.1:9050" of Tor
+    #[serde(default)]
+    pub proxy: String,
+    /// Connect to all peers through proxy, never directly
+    #[serde(default)]
+    pub proxy_only: bool,
+    /// How many peers can connect to us
+    #[serde(default = "default_max_inbound")]
+    pub max_inbound: usize,
+    /// How many peers we connect to
+    #[serde(default = "default_max_outbound")]
+    pub max_outbound: usize,
+    /// How many connections can be from one IP, loopback is not limited
+    #[serde(default = "default_max_per_ip")]
+    pub max_per_ip: usize,
+    /// How many messages one peer can send us in a minute
+    #[serde(default = "default_max_messages")]
+    pub max_messages: u32,
 }
 
 impl Default for Net {
@@ -107,7 +227,36 @@ impl Default for Net {
             peers: vec![String::from("test-ip4.alfis.name:4244"), String::from("test-ip6.alfis.name:4244")],
             listen: String::from("[::]:4244"),
             public: true,
-            yggdrasil_only: false
+            yggdrasil_only: false,
+            allow_plaintext: true,
+            bind_identity: false,
+            proxy: String::new(),
+            proxy_only: false,
+            max_inbound: default_max_inbound(),
+            max_outbound: default_max_outbound(),
+            max_per_ip: default_max_per_ip(),
+            max_messages: default_max_messages()
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Api {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_listen_api")]
+    pub listen: String,
+    /// If set, requests must have `Authorization: Bearer <token>` header
+    #[serde(default)]
+    pub token: String,
+}
+
+impl Default for Api {
+    fn default() -> Self {
+        Api {
+            enabled: false,
+            listen: default_listen_api(),
+            token: String::new()
         }
     }
 }
@@ -120,6 +269,68 @@ fn default_listen_dns() -> String {
     String::from("0.0.0.0:53")
 }
 
+fn default_listen_api() -> String {
+    String::from("127.0.0.1:5380")
+}
+
 fn default_threads() -> usize {
     20
-}
\ No newline at end of file
+}
+
+fn default_allow_plaintext() -> bool {
+    true
+}
+
+fn default_max_inbound() -> usize {
+    64
+}
+
+fn default_max_outbound() -> usize {
+    16
+}
+
+fn default_max_per_ip() -> usize {
+    3
+}
+
+fn default_max_messages() -> u32 {
+    600
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::{Bytes, Settings};
+    use crate::commons::MAIN_ORIGIN;
+    use crate::settings::Checkpoint;
+
+    #[test]
+    fn resolve_paths_in_data_dir() {
+        let settings = Settings { data_dir: String::from("/var/lib/alfis"), ..Settings::default() };
+        assert_eq!(settings.get_path("blockchain.db"), Path::new("/var/lib/alfis/blockchain.db"));
+        assert_eq!(settings.get_path("zones/ygg"), Path::new("/var/lib/alfis/zones/ygg"));
+        assert_eq!(settings.get_path("/etc/alfis/key.toml"), Path::new("/etc/alfis/key.toml"));
+
+        let settings = Settings { data_dir: String::new(), ..Settings::default() };
+        assert_eq!(settings.get_path("blockchain.db"), Path::new("blockchain.db"));
+    }
+
+    #[test]
+    fn merge_checkpoints() {
+        let hash = "00".repeat(32);
+        let checkpoints = vec![Checkpoint { height: 1, hash: hash.clone() }, Checkpoint { height: 10, hash: hash.clone() }];
+        let settings = Settings { origin: String::from(MAIN_ORIGIN), checkpoints, ..Settings::default() };
+        let result = settings.get_checkpoints().unwrap();
+        assert_eq!(result.len(), 2);
+        assert_eq!(result[&1], Bytes::zero32());
+
+        let settings = Settings { origin: String::from(MAIN_ORIGIN), ..Settings::default() };
+        assert_eq!(settings.get_checkpoints().unwrap()[&1].to_string(), MAIN_ORIGIN);
+        // Built-in checkpoints are only for the main chain
+        let settings = Settings { origin: String::from(MAIN_ORIGIN), regtest: true, ..Settings::default() };
+        assert!(settings.get_checkpoints().unwrap().is_empty());
+        let settings = Settings { origin: String::new(), ..Settings::default() };
+        assert!(settings.get_checkpoints().unwrap().is_empty());
+    }
+}
@@ -8,20 +8,20 @@ use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::{Duration, Instant};
 
-use chrono::{DateTime, Local};
+use chrono::{DateTime, Local, TimeZone};
 #[allow(unused_imports)]
 use log::{debug, error, info, LevelFilter, trace, warn};
 use serde::Deserialize;
 use web_view::Content;
 
-use alfis::{Block, Bytes, Context, get_domain_zone, Keystore, Transaction, ZONE_MIN_DIFFICULTY};
+use alfis::{Bytes, Context, Keystore, from_hex};
 use alfis::{check_domain, keys};
 use alfis::blockchain::transaction::{DomainData, ZoneData};
 use alfis::blockchain::types::MineResult;
-use alfis::commons::{ZONE_DIFFICULTY, ZONE_MAX_LENGTH, CLASS_DOMAIN, CLASS_ZONE};
+use alfis::commons::ZONE_MAX_LENGTH;
 use alfis::dns::protocol::DnsRecord;
 use alfis::event::Event;
-use alfis::miner::Miner;
+use alfis::miner::{Miner, mine_domain, mine_zone, transfer_domain};
 use Cmd::*;
 
 use self::web_view::{Handle, WebView};
@@ -55,7 +55,9 @@ pub fn run_interface(context: Arc<Mutex<Context>>, miner: Arc<Mutex<Miner>>) {
                 MineDomain { name, data } => {
                     action_create_domain(Arc::clone(&context), Arc::clone(&miner), web_view, name, data);
                 }
-                TransferDomain { .. } => {}
+                TransferDomain { name, owner } => {
+                    action_transfer_domain(Arc::clone(&context), Arc::clone(&miner), web_view, name, owner);
+                }
                 CheckZone { name } => { action_check_zone(&context, web_view, name); }
                 MineZone { name, data } => {
                     action_create_zone(Arc::clone(&context), Arc::clone(&miner), web_view, name, data);
@@ -146,12 +148,16 @@ fn action_save_key(context: &Arc<Mutex<Context>>) {
     match result {
         None => {}
         Some(new_path) => {
+            let password = match tfd::password_box("Save keys file", "Enter password to encrypt the keys (leave it empty to save them unencrypted)") {
+                None => { return; }
+                Some(password) => { password }
+            };
             let mut context = context.lock().unwrap();
             let path = new_path.clone();
             if let Some(mut keystore) = context.get_keystore() {
                 let public = keystore.get_public().to_string();
                 let hash = keystore.get_hash().to_string();
-                keystore.save(&new_path, "");
+                keystore.save(&new_path, &password);
                 info!("Key file saved to {}", &path);
                 context.bus.post(Event::KeySaved { path, public, hash });
             }
@@ -164,10 +170,19 @@ fn action_load_key(context: &Arc<Mutex<Context>>, web_view: &mut WebView<()>) {
     match result {
         None => {}
         Some(file_name) => {
-            match Keystore::from_file(&file_name, "") {
+            let password = if Keystore::is_encrypted(&file_name) {
+                match tfd::password_box("Open keys file", "Enter password for the keys") {
+                    None => { return; }
+                    Some(password) => { password }
+                }
+            } else {
+                String::new()
+            };
+            let strength = context.lock().unwrap().chain.get_consensus().keystore_difficulty;
+            match Keystore::from_file(&file_name, &password, strength) {
                 None => {
                     error!("Error loading keystore '{}'!", &file_name);
-                    show_warning(web_view, "Error loading key!<br>Key cannot be loaded or its difficulty is not enough.");
+                    show_warning(web_view, "Error loading key!<br>Key cannot be loaded, the password is wrong or its difficulty is not enough.");
                     event_fail(web_view, &format!("Error loading key from \\'{}\\'!", &file_name));
                 }
                 Some(keystore) => {
@@ -296,6 +311,11 @@ fn action_loaded(context: &Arc<Mutex<Context>>, web_view: &mut WebView<()>) {
                     }
                     String::new() // Nothing
                 }
+                Event::DomainExpiring { name, expires } => {
+                    let time = Local.timestamp(expires, 0);
+                    event_handle_warn(&handle, &format!("Domain {} expires on {}, renew it!", &name, time.format("%d.%m.%y %X")));
+                    String::new()
+                }
                 _ => { String::new() }
             };
 
@@ -321,31 +341,19 @@ fn action_loaded(context: &Arc<Mutex<Context>>, web_view: &mut WebView<()>) {
 
 fn action_create_domain(context: Arc<Mutex<Context>>, miner: Arc<Mutex<Miner>>, web_view: &mut WebView<()>, name: String, data: String) {
     debug!("Creating domain with data: {}", &data);
-    let c = Arc::clone(&context);
-    let context = context.lock().unwrap();
-    if context.get_keystore().is_none() {
+    if context.lock().unwrap().get_keystore().is_none() {
         show_warning(web_view, "You don't have keys loaded!<br>Load or mine the keys and try again.");
         return;
     }
-    let keystore = context.get_keystore().unwrap();
-    let pub_key = keystore.get_public();
-    let mut data = match serde_json::from_str::<DomainData>(&data) {
+    let data = match serde_json::from_str::<DomainData>(&data) {
         Ok(data) => { data }
         Err(_) => {
             show_warning(web_view, "Something wrong with domain data. I cannot mine it.");
             return;
         }
     };
-    match context.chain.can_mine_domain(&name, &pub_key) {
+    match mine_domain(&context, &miner, &name, data) {
         MineResult::Fine => {
-            let zone = get_domain_zone(&name);
-            let difficulty = context.chain.get_zone_difficulty(&zone);
-            let last_block = context.chain.last_block().unwrap();
-            let encrypted = keystore.encrypt(name.as_bytes(), &last_block.hash.as_slice()[..12]);
-            data.domain = encrypted;
-            let data = serde_json::to_string(&data).unwrap();
-            std::mem::drop(context);
-            create_domain(c, miner, CLASS_DOMAIN, &name, &data, difficulty, &keystore);
             let _ = web_view.eval("domainMiningStarted();");
             event_info(web_view, &format!("Mining of domain \\'{}\\' has started", &name));
         }
@@ -361,24 +369,34 @@ fn action_create_domain(context: Arc<Mutex<Context>>, miner: Arc<Mutex<Miner>>,
     }
 }
 
+fn action_transfer_domain(context: Arc<Mutex<Context>>, miner: Arc<Mutex<Miner>>, web_view: &mut WebView<()>, name: String, owner: String) {
+    let owner = match from_hex(owner.trim()) {
+        Ok(owner) => Bytes::from_bytes(&owner),
+        Err(_) => {
+            show_warning(web_view, "Something wrong with the key of new owner!");
+            return;
+        }
+    };
+    match transfer_domain(&context, &miner, &name, &owner) {
+        MineResult::Fine => {
+            event_info(web_view, &format!("Mining of transfer for domain \\'{}\\' has started", &name));
+        }
+        MineResult::WrongKey => { show_warning(web_view, "You don't have keys loaded!<br>Load or mine the keys and try again."); }
+        MineResult::WrongData => { show_warning(web_view, "You can't transfer domain to this key!"); }
+        MineResult::NotOwned => { show_warning(web_view, "You cannot transfer domain that you don't own!"); }
+        _ => { show_warning(web_view, "There is no such domain!"); }
+    }
+}
+
 fn action_create_zone(context: Arc<Mutex<Context>>, miner: Arc<Mutex<Miner>>, web_view: &mut WebView<()>, name: String, data: String) {
     let name = name.to_lowercase();
-    if name.len() > ZONE_MAX_LENGTH || !check_domain(&name, false) || context.lock().unwrap().x_zones.has_zone(&name) {
-        warn!("This zone is unavailable for mining!");
-        show_warning(web_view, "This zone is unavailable for mining!");
-        return;
-    }
     let data = data.to_lowercase();
-    let mut data = match serde_json::from_str::<ZoneData>(&data) {
+    let data = match serde_json::from_str::<ZoneData>(&data) {
         Ok(zone) => {
-            if zone.difficulty < ZONE_MIN_DIFFICULTY {
-                warn!("Zone difficulty cannot be lower than {}!", ZONE_MIN_DIFFICULTY);
-                show_warning(web_view, &format!("Zone difficulty cannot be lower than {}!", ZONE_MIN_DIFFICULTY));
-                return;
-            }
-            if name != zone.name {
-                warn!("Something wrong with zone data!");
-                show_warning(web_view, "Something wrong with zone data!");
+            let min_difficulty = context.lock().unwrap().chain.get_consensus().zone_min_difficulty;
+            if zone.difficulty < min_difficulty {
+                warn!("Zone difficulty cannot be lower than {}!", min_difficulty);
+                show_warning(web_view, &format!("Zone difficulty cannot be lower than {}!", min_difficulty));
                 return;
             }
             zone
@@ -389,35 +407,26 @@ fn action_create_zone(context: Arc<Mutex<Context>>, miner: Arc<Mutex<Miner>>, we
             return;
         }
     };
-    let (keystore, transaction) = {
-        let context = context.lock().unwrap();
-        (context.get_keystore(), context.chain.get_domain_transaction(&name))
-    };
-    if let Some(keystore) = keystore {
-        data.owners = if data.owners.is_empty() {
-            vec!(keystore.get_public())
-        } else {
-            data.owners
-        };
-        let data = serde_json::to_string(&data).unwrap();
-        match transaction {
-            None => {
-                create_domain(Arc::clone(&context), miner.clone(), CLASS_ZONE, &name, &data, ZONE_DIFFICULTY, &keystore);
-                event_info(web_view, &format!("Mining of zone \\'{}\\' has started", &name));
-            }
-            Some(transaction) => {
-                if transaction.pub_key == keystore.get_public() {
-                    create_domain(Arc::clone(&context), miner.clone(), CLASS_ZONE, &name, &data, ZONE_DIFFICULTY, &keystore);
-                    event_info(web_view, &format!("Mining of zone \\'{}\\' has started", &name));
-                } else {
-                    warn!("Tried to mine not owned domain!");
-                    show_warning(web_view, "You cannot change domain that you don't own!");
-                }
-            }
+    match mine_zone(&context, &miner, &name, data) {
+        MineResult::Fine => {
+            event_info(web_view, &format!("Mining of zone \\'{}\\' has started", &name));
+        }
+        MineResult::WrongName => {
+            warn!("This zone is unavailable for mining!");
+            show_warning(web_view, "This zone is unavailable for mining!");
+        }
+        MineResult::WrongKey => {
+            warn!("Can not mine without keys!");
+            show_warning(web_view, "You don't have keys loaded!<br>Load or mine the keys and try again.");
+        }
+        MineResult::NotOwned => {
+            warn!("Tried to mine not owned domain!");
+            show_warning(web_view, "You cannot change domain that you don't own!");
+        }
+        _ => {
+            warn!("Something wrong with zone data!");
+            show_warning(web_view, "Something wrong with zone data!");
         }
-    } else {
-        warn!("Can not mine without keys!");
-        show_warning(web_view, "You don't have keys loaded!<br>Load or mine the keys and try again.");
     }
 }
 
@@ -495,18 +504,6 @@ fn format_event_now(kind: &str, message: &str) -> String {
     format!("addEvent('{}', '{}', '{}');", kind, time.format("%d.%m.%y %X"), message)
 }
 
-fn create_domain(context: Arc<Mutex<Context>>, miner: Arc<Mutex<Miner>>, class: &str, name: &str, data: &str, difficulty: u32, keystore: &Keystore) {
-    let name = name.to_owned();
-    info!("Generating domain or zone {}", &name);
-    if context.lock().unwrap().x_zones.has_zone(&name) {
-        error!("Unable to mine IANA/OpenNIC/etc zone {}!", &name);
-        return;
-    }
-    let transaction = Transaction::from_str(name, class.to_owned(), data.to_owned(), keystore.get_public().clone());
-    let block = Block::new(Some(transaction), keystore.get_public(), Bytes::default(), difficulty);
-    miner.lock().unwrap().add_block(block, keystore.clone());
-}
-
 #[derive(Deserialize)]
 #[serde(tag = "cmd", rename_all = "camelCase")]
 pub enum Cmd {